@@ -7,13 +7,8 @@ mod parser;
 use crate::assembler::instruction::Instruction;
 use crate::assembler::instruction::ParsedInstruction;
 
-pub fn compile(contents: &String) -> Vec<String> {
-    let parsed_instructions: Vec<ParsedInstruction> = contents
-        .lines()
-        .map(|line| line.trim())
-        .filter(|line| !(line.starts_with("//") || line.is_empty()))
-        .map(|line| parser::parse(&line))
-        .collect();
+pub fn compile(contents: &str) -> Vec<String> {
+    let parsed_instructions = parse(contents);
 
     let mut symbols: HashMap<String, u16> = HashMap::from([
         ("SP".to_owned(), 0),
@@ -41,18 +36,7 @@ pub fn compile(contents: &String) -> Vec<String> {
         ("KBD".to_owned(), 24576),
     ]);
 
-    let mut pc: u16 = 0;
-    for parsed_instruction in parsed_instructions.iter() {
-        match parsed_instruction {
-            ParsedInstruction::Label(string) => {
-                symbols.insert(string.clone(), pc);
-            }
-            _ => {
-                pc += 1;
-            }
-        }
-    }
-    pc = 0;
+    symbols.extend(find_labels(&parsed_instructions));
 
     let mut next_symbol_value: u16 = 16;
     let mut instructions: Vec<Instruction> = Vec::new();
@@ -60,18 +44,15 @@ pub fn compile(contents: &String) -> Vec<String> {
         match parsed_instruction {
             ParsedInstruction::AInstructionWithNumber(value) => {
                 instructions.push(Instruction::AInstruction(value));
-                pc += 1
             }
             ParsedInstruction::AInstructionWithSymbol(string) => {
                 if let Some(&value) = symbols.get(&string) {
                     instructions.push(Instruction::AInstruction(value));
-                    pc += 1
                 } else {
                     symbols.insert(string, next_symbol_value);
                     instructions
                         .push(Instruction::AInstruction(next_symbol_value));
                     next_symbol_value += 1;
-                    pc += 1
                 }
             }
             ParsedInstruction::CInstruction {
@@ -84,18 +65,48 @@ pub fn compile(contents: &String) -> Vec<String> {
                     computation,
                     jump,
                 });
-                pc += 1
             }
             ParsedInstruction::Label(_) => {}
         }
     }
 
-    instructions
-        .iter()
-        .map(|line| code::encode(&line))
+    instructions.iter().map(code::encode).collect()
+}
+
+// the labels and the ROM addresses they stand for, in program order
+pub fn labels(contents: &str) -> Vec<(String, u16)> {
+    find_labels(&parse(contents))
+}
+
+fn parse(contents: &str) -> Vec<ParsedInstruction> {
+    contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !(line.starts_with("//") || line.is_empty()))
+        .map(parser::parse)
         .collect()
 }
 
+fn find_labels(
+    parsed_instructions: &[ParsedInstruction],
+) -> Vec<(String, u16)> {
+    let mut labels = vec![];
+
+    let mut pc: u16 = 0;
+    for parsed_instruction in parsed_instructions.iter() {
+        match parsed_instruction {
+            ParsedInstruction::Label(string) => {
+                labels.push((string.clone(), pc));
+            }
+            _ => {
+                pc += 1;
+            }
+        }
+    }
+
+    labels
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -104,34 +115,33 @@ mod tests {
     fn test_ignores_comments() {
         assert_eq!(
             Vec::<String>::new(),
-            compile(&"// this is a comment\n// and another one".to_string())
+            compile("// this is a comment\n// and another one")
         );
         assert_eq!(
             vec!["0000000000000000"],
-            compile(&"@0  // end-of-line comment".to_string())
+            compile("@0  // end-of-line comment")
         );
     }
 
     #[test]
     fn test_ignores_blank_lines() {
-        assert_eq!(Vec::<String>::new(), compile(&"  \n\n".to_string()));
+        assert_eq!(Vec::<String>::new(), compile("  \n\n"));
     }
 
     #[test]
     fn test_a_instruction() {
-        assert_eq!(vec!["0000000000000000"], compile(&"@0".to_string()));
-        assert_eq!(vec!["0000000000000111"], compile(&"@7".to_string()));
-        assert_eq!(vec!["0111111111111111"], compile(&"@32767".to_string()));
+        assert_eq!(vec!["0000000000000000"], compile("@0"));
+        assert_eq!(vec!["0000000000000111"], compile("@7"));
+        assert_eq!(vec!["0111111111111111"], compile("@32767"));
     }
 
     #[test]
     fn test_c_instruction() {
-        assert_eq!(vec!["1111110000010000"], compile(&"D=M".to_string()));
-        assert_eq!(vec!["1110000010010000"], compile(&"D=D+A".to_string()));
-        assert_eq!(
-            vec!["1111010101111010"],
-            compile(&"AMD=D|M;JEQ".to_string())
-        );
+        assert_eq!(vec!["1111110000010000"], compile("D=M"));
+        assert_eq!(vec!["1110000010010000"], compile("D=D+A"));
+        assert_eq!(vec!["1111010101111010"], compile("AMD=D|M;JEQ"));
+        assert_eq!(compile("M=D+M"), compile("M=M+D"));
+        assert_eq!(compile("D=D&A"), compile("D=A&D"));
     }
 
     #[test]
@@ -154,7 +164,29 @@ mod tests {
                 "0000000000010000",
                 "1110101010001000"
             ],
-            compile(&program.to_string())
+            compile(program)
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        let program = "\
+        (START)
+        @LOOP
+        0;JMP
+        // a comment
+        (LOOP)
+        (ALSO_LOOP)
+        @START
+        0;JMP
+        ";
+        assert_eq!(
+            vec![
+                ("START".to_owned(), 0),
+                ("LOOP".to_owned(), 2),
+                ("ALSO_LOOP".to_owned(), 2)
+            ],
+            labels(program)
         );
     }
 }
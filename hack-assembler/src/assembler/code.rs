@@ -64,7 +64,7 @@ fn encode_computation(computation: &Computation) -> &str {
     }
 }
 
-fn encode_destinations(destinations: &Vec<Destination>) -> String {
+fn encode_destinations(destinations: &[Destination]) -> String {
     use Destination::*;
 
     let value = destinations.iter().fold(0, |total, destination| {
@@ -50,6 +50,7 @@ pub enum Computation {
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum JumpCondition {
     JumpIfGreaterThan,
     JumpIfEqual,
@@ -13,7 +13,7 @@ pub fn parse(input: &str) -> ParsedInstruction {
     } else if let Some(number_string) = line.strip_prefix("@") {
         parse_a_instruction(number_string)
     } else {
-        if let Some(instruction) = parse_c_instruction(&line) {
+        if let Some(instruction) = parse_c_instruction(line) {
             instruction
         } else {
             panic!("invalid C instruction: {}", line)
@@ -47,6 +47,8 @@ fn parse_c_instruction(line: &str) -> Option<ParsedInstruction> {
     })
 }
 
+// The commutative operations are also accepted with their operands
+// swapped, such as M+D, as the VM translator writes them that way
 fn parse_computation(string: &str) -> Computation {
     use Computation::*;
     use Destination::*;
@@ -70,16 +72,16 @@ fn parse_computation(string: &str) -> Computation {
         "D-1" => MinusOne(DRegister),
         "A-1" => MinusOne(ARegister),
         "M-1" => MinusOne(Memory),
-        "D+A" => DRegisterPlusARegister,
-        "D+M" => DRegisterPlusMemory,
+        "D+A" | "A+D" => DRegisterPlusARegister,
+        "D+M" | "M+D" => DRegisterPlusMemory,
         "D-A" => DRegisterMinusARegister,
         "D-M" => DRegisterMinusMemory,
         "A-D" => ARegisterMinusDRegister,
         "M-D" => MemoryMinusDRegister,
-        "D&A" => DRegisterAndARegister,
-        "D&M" => DRegisterAndMemory,
-        "D|A" => DRegisterOrARegister,
-        "D|M" => DRegisterOrMemory,
+        "D&A" | "A&D" => DRegisterAndARegister,
+        "D&M" | "M&D" => DRegisterAndMemory,
+        "D|A" | "A|D" => DRegisterOrARegister,
+        "D|M" | "M|D" => DRegisterOrMemory,
         _ => panic!("unknown computation: {}", string),
     }
 }
@@ -2,9 +2,9 @@ use std::env;
 use std::fs::File;
 use std::io::prelude::*;
 
-mod assembler;
+use hack_assembler::assembler;
 
-fn read_file(path: &String) -> std::io::Result<String> {
+fn read_file(path: &str) -> std::io::Result<String> {
     let mut file = File::open(path)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
@@ -12,7 +12,7 @@ fn read_file(path: &String) -> std::io::Result<String> {
 }
 
 fn main() {
-    if let Some(path) = env::args().skip(1).next() {
+    if let Some(path) = env::args().nth(1) {
         if let Ok(contents) = read_file(&path) {
             for line in assembler::compile(&contents) {
                 println!("{}", line);
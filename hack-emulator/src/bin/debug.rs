@@ -0,0 +1,91 @@
+use hack_emulator::computer::Computer;
+use hack_emulator::debugger::Debugger;
+use hack_emulator::keyboard::Keyboard;
+use hack_emulator::program::Program;
+use hack_emulator::source_map::SourceMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::process;
+
+const USAGE: &str = "USAGE: ./debug Prog.asm|Prog.hack [--keyboard FILE] \
+                     [--source-map FILE]";
+
+fn debug(
+    path: &str,
+    keyboard: Option<String>,
+    source_map: Option<String>,
+) -> io::Result<()> {
+    let program = Program::load(path)?;
+    let mut computer = Computer::new(program.rom);
+    if let Some(path) = keyboard {
+        computer.keyboard = Keyboard::parse(&fs::read_to_string(path)?)
+            .map_err(|message| {
+                io::Error::new(io::ErrorKind::InvalidData, message)
+            })?;
+    }
+    let mut debugger = Debugger::new(computer);
+    if let Some(path) = source_map {
+        debugger.source_map = Some(SourceMap::load(path)?);
+    }
+    let mut previous = String::new();
+
+    println!("{}", debugger.location());
+    loop {
+        print!("(hack) ");
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        // an empty line repeats the last command
+        let line = match line.trim() {
+            "" => previous.clone(),
+            line => line.to_owned(),
+        };
+
+        match line.as_str() {
+            "" => continue,
+            "quit" | "q" => return Ok(()),
+            _ => match debugger.execute(&line) {
+                Ok(output) => println!("{}", output),
+                Err(message) => println!("ERROR: {}", message),
+            },
+        }
+        previous = line;
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut path = None;
+    let mut keyboard = None;
+    let mut source_map = None;
+    let mut valid = true;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--keyboard" => {
+                keyboard = args.next();
+                valid &= keyboard.is_some();
+            }
+            "--source-map" => {
+                source_map = args.next();
+                valid &= source_map.is_some();
+            }
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => valid = false,
+        }
+    }
+    let (Some(path), true) = (path, valid) else {
+        println!("{USAGE}");
+        return;
+    };
+
+    debug(&path, keyboard, source_map).unwrap_or_else(|error| {
+        eprintln!("ERROR: {error}");
+        process::exit(1);
+    });
+}
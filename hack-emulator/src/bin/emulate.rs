@@ -0,0 +1,198 @@
+use hack_emulator::computer::Computer;
+use hack_emulator::keyboard::Keyboard;
+use hack_emulator::profiler::Profiler;
+use hack_emulator::program::Program;
+use hack_emulator::snapshot;
+use hack_emulator::source_map::SourceMap;
+use hack_emulator::trace::TraceWriter;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter};
+use std::process;
+
+const USAGE: &str = "USAGE: ./emulate Prog.asm|Prog.hack [--cycles N] \
+                     [--set ADDRESS=VALUE]... [--keyboard FILE] \
+                     [--load-snapshot FILE] [--save-snapshot FILE] \
+                     [--trace FILE] [--profile] [--folded FILE] \
+                     [--dump FROM-TO] [--source-map FILE]";
+
+struct Options {
+    path: String,
+    cycles: u64,
+    settings: Vec<(u16, u16)>,
+    keyboard: Option<String>,
+    load_snapshot: Option<String>,
+    save_snapshot: Option<String>,
+    trace: Option<String>,
+    profile: bool,
+    folded: Option<String>,
+    dump: Option<(u16, u16)>,
+    source_map: Option<String>,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut args = env::args().skip(1);
+    let mut options = Options {
+        path: String::new(),
+        cycles: 1_000_000,
+        settings: vec![],
+        keyboard: None,
+        load_snapshot: None,
+        save_snapshot: None,
+        trace: None,
+        profile: false,
+        folded: None,
+        dump: None,
+        source_map: None,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--cycles" => {
+                options.cycles = value()?
+                    .parse()
+                    .map_err(|_| "invalid cycle count".to_owned())?
+            }
+            "--set" => {
+                let setting = value()?;
+                let (address, number) = setting
+                    .split_once('=')
+                    .ok_or(format!("invalid setting {setting}"))?;
+                options
+                    .settings
+                    .push((parse_word(address)?, parse_word(number)?));
+            }
+            "--keyboard" => options.keyboard = Some(value()?),
+            "--load-snapshot" => options.load_snapshot = Some(value()?),
+            "--save-snapshot" => options.save_snapshot = Some(value()?),
+            "--trace" => options.trace = Some(value()?),
+            "--profile" => options.profile = true,
+            "--folded" => options.folded = Some(value()?),
+            "--dump" => {
+                let range = value()?;
+                let (from, to) = range
+                    .split_once('-')
+                    .ok_or(format!("invalid range {range}"))?;
+                options.dump = Some((parse_word(from)?, parse_word(to)?));
+            }
+            "--source-map" => options.source_map = Some(value()?),
+            _ if options.path.is_empty() && !arg.starts_with("--") => {
+                options.path = arg
+            }
+            _ => return Err(format!("unexpected argument {arg}")),
+        }
+    }
+
+    if options.path.is_empty() {
+        Err(USAGE.into())
+    } else {
+        Ok(options)
+    }
+}
+
+fn parse_word(string: &str) -> Result<u16, String> {
+    let string = string.trim_start_matches("RAM[").trim_end_matches(']');
+    match string.parse::<i16>() {
+        Ok(value) => Ok(value as u16),
+        Err(_) => string
+            .parse::<u16>()
+            .map_err(|_| format!("invalid number {string}")),
+    }
+}
+
+fn emulate(options: Options) -> io::Result<()> {
+    let program = Program::load(&options.path)?;
+    let mut profiler = (options.profile || options.folded.is_some())
+        .then(|| Profiler::new(&program));
+    let mut computer = Computer::new(program.rom);
+    if let Some(path) = options.keyboard {
+        computer.keyboard = Keyboard::parse(&fs::read_to_string(path)?)
+            .map_err(|message| {
+                io::Error::new(io::ErrorKind::InvalidData, message)
+            })?;
+    }
+    if let Some(path) = options.load_snapshot {
+        snapshot::restore(&mut computer, BufReader::new(File::open(path)?))?;
+    }
+    for (address, value) in options.settings {
+        computer.poke(address, value);
+    }
+
+    let mut trace = match options.trace {
+        Some(path) => {
+            Some(TraceWriter::new(BufWriter::new(File::create(path)?))?)
+        }
+        None => None,
+    };
+    let cycles = if trace.is_none() && profiler.is_none() {
+        computer.run(options.cycles)
+    } else {
+        let mut cycles = 0;
+        while cycles < options.cycles && !computer.halted() {
+            if let Some(profiler) = &mut profiler {
+                profiler.record(computer.pc);
+            }
+            let step = computer.step();
+            if let Some(trace) = &mut trace {
+                trace.record(&step)?;
+            }
+            cycles += 1;
+        }
+        cycles
+    };
+    if let Some(trace) = trace {
+        trace.finish()?;
+    }
+
+    if computer.halted() {
+        println!("halted after {cycles} cycles");
+    } else {
+        println!("stopped after {cycles} cycles");
+    }
+    println!(
+        "PC={} A={} D={}",
+        computer.pc, computer.a as i16, computer.d as i16
+    );
+    if let Some(path) = options.source_map {
+        if let Some(location) = SourceMap::load(path)?.lookup(computer.pc) {
+            println!("at {location}");
+        }
+    }
+    if let Some(path) = options.save_snapshot {
+        snapshot::save(&computer, BufWriter::new(File::create(path)?))?;
+    }
+    if let Some((from, to)) = options.dump {
+        for address in from..=to {
+            println!("RAM[{}]={}", address, computer.peek(address) as i16);
+        }
+    }
+    if let Some(profiler) = profiler {
+        if options.profile {
+            println!("{}", profiler.report());
+        }
+        if let Some(path) = options.folded {
+            fs::write(path, profiler.folded() + "\n")?;
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    match parse_options() {
+        Ok(options) => {
+            let path = options.path.clone();
+            if let Err(error) = emulate(options) {
+                eprintln!("ERROR: {path}: {error}");
+                process::exit(1);
+            }
+        }
+        Err(message) => {
+            eprintln!("{message}");
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use hack_emulator::equivalence::*;
+use hack_emulator::program::Program;
+use std::env;
+use std::process;
+
+const USAGE: &str = "USAGE: ./equiv left.asm|left.hack right.asm|right.hack \
+                     --input ADDRESS=FROM..TO... --output ADDRESS... \
+                     [--steps N]";
+
+struct Options {
+    paths: Vec<String>,
+    inputs: Vec<Input>,
+    outputs: Vec<u16>,
+    steps: u64,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut args = env::args().skip(1);
+    let mut options = Options {
+        paths: vec![],
+        inputs: vec![],
+        outputs: vec![],
+        steps: 100_000,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--input" => {
+                let input = value()?;
+                let (address, range) = input
+                    .split_once('=')
+                    .ok_or(format!("invalid input {input}"))?;
+                let (from, to) =
+                    range.split_once("..").unwrap_or((range, range));
+                let (from, to) = (parse_value(from)?, parse_value(to)?);
+                if from > to {
+                    return Err(format!("empty range in input {input}"));
+                }
+                options.inputs.push(Input {
+                    address: parse_address(address)?,
+                    from,
+                    to,
+                });
+            }
+            "--output" => options.outputs.push(parse_address(&value()?)?),
+            "--steps" => {
+                options.steps = value()?
+                    .parse()
+                    .map_err(|_| "invalid step count".to_owned())?
+            }
+            _ if options.paths.len() < 2 && !arg.starts_with("--") => {
+                options.paths.push(arg)
+            }
+            _ => return Err(format!("unexpected argument {arg}")),
+        }
+    }
+
+    if options.paths.len() < 2 || options.outputs.is_empty() {
+        Err(USAGE.into())
+    } else {
+        Ok(options)
+    }
+}
+
+// a RAM address as a number, `RAM[N]` or one of R0 to R15
+fn parse_address(string: &str) -> Result<u16, String> {
+    let number = string
+        .strip_prefix("RAM[")
+        .and_then(|rest| rest.strip_suffix(']'))
+        .or_else(|| string.strip_prefix('R'))
+        .unwrap_or(string);
+    number
+        .parse()
+        .map_err(|_| format!("invalid address {string}"))
+}
+
+fn parse_value(string: &str) -> Result<i16, String> {
+    string
+        .parse()
+        .map_err(|_| format!("invalid value {string}"))
+}
+
+fn describe(outcome: &Outcome, outputs: &[u16]) -> String {
+    match outcome {
+        Outcome::Halted(values) => outputs
+            .iter()
+            .zip(values)
+            .map(|(address, &value)| {
+                format!("RAM[{}]={}", address, value as i16)
+            })
+            .collect::<Vec<String>>()
+            .join(" "),
+        Outcome::Running => "did not halt in time".into(),
+    }
+}
+
+fn main() {
+    let options = match parse_options() {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{message}");
+            process::exit(2);
+        }
+    };
+    let mut roms = vec![];
+    for path in &options.paths {
+        match Program::load(path) {
+            Ok(program) => roms.push(program.rom),
+            Err(error) => {
+                eprintln!("ERROR: {path}: {error}");
+                process::exit(2);
+            }
+        }
+    }
+
+    let verdict = check(
+        &roms[0],
+        &roms[1],
+        &options.inputs,
+        &options.outputs,
+        options.steps,
+    );
+    // only a proof exits with 0: a counterexample exits with 1, and runs
+    // that didn't finish with 3
+    match verdict {
+        Verdict::Equivalent { cases } => {
+            println!("equivalent on all {cases} inputs");
+        }
+        Verdict::Inconclusive { cases, unfinished } => {
+            println!(
+                "equivalent on {} of {cases} inputs, inconclusive on \
+                 {unfinished} where a program didn't halt within {} steps",
+                cases - unfinished,
+                options.steps
+            );
+            process::exit(3);
+        }
+        Verdict::Different(counterexample) => {
+            let inputs: Vec<String> = counterexample
+                .inputs
+                .iter()
+                .map(|(address, value)| {
+                    format!("RAM[{address}]={}", *value as i16)
+                })
+                .collect();
+            println!("counterexample: {}", inputs.join(" "));
+            println!("< {}", describe(&counterexample.left, &options.outputs));
+            println!("> {}", describe(&counterexample.right, &options.outputs));
+            process::exit(1);
+        }
+    }
+}
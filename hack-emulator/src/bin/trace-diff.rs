@@ -0,0 +1,90 @@
+use hack_emulator::computer::Step;
+use hack_emulator::trace::*;
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::process;
+
+const USAGE: &str = "USAGE: ./trace-diff [--writes] [--context N] \
+                     left.trace right.trace";
+
+fn open(path: &str) -> std::io::Result<TraceReader<BufReader<File>>> {
+    TraceReader::new(BufReader::new(File::open(path)?))
+}
+
+fn describe(name: &str, item: &Option<(u64, Step)>) -> String {
+    match item {
+        Some((cycle, step)) => format!("{name} {}", format_step(*cycle, step)),
+        None => format!("{name} <end of trace>"),
+    }
+}
+
+fn diff(
+    paths: &[String],
+    comparison: Comparison,
+    context: usize,
+) -> std::io::Result<bool> {
+    let divergence = first_divergence(
+        open(&paths[0])?,
+        open(&paths[1])?,
+        comparison,
+        context,
+    )?;
+
+    match divergence {
+        None => {
+            println!("traces are identical");
+            Ok(false)
+        }
+        Some(divergence) => {
+            match comparison {
+                Comparison::Steps => {
+                    println!("traces diverge at cycle {}", divergence.index)
+                }
+                Comparison::Writes => {
+                    println!("traces diverge at write {}", divergence.index)
+                }
+            }
+            for (cycle, step) in &divergence.context {
+                println!("  {}", format_step(*cycle, step));
+            }
+            println!("{}", describe("<", &divergence.left));
+            println!("{}", describe(">", &divergence.right));
+            Ok(true)
+        }
+    }
+}
+
+fn main() {
+    let mut comparison = Comparison::Steps;
+    let mut context = 5;
+    let mut paths = vec![];
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--writes" => comparison = Comparison::Writes,
+            "--context" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) => context = n,
+                None => {
+                    eprintln!("{USAGE}");
+                    process::exit(2);
+                }
+            },
+            _ => paths.push(arg),
+        }
+    }
+
+    if paths.len() != 2 {
+        eprintln!("{USAGE}");
+        process::exit(2);
+    }
+
+    match diff(&paths, comparison, context) {
+        Ok(diverged) => process::exit(if diverged { 1 } else { 0 }),
+        Err(error) => {
+            eprintln!("ERROR: {error}");
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use hack_emulator::trace::{format_step, TraceReader};
+use std::env;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::process;
+
+fn dump(path: &str) -> std::io::Result<()> {
+    let reader = TraceReader::new(BufReader::new(File::open(path)?))?;
+    let mut out = BufWriter::new(std::io::stdout().lock());
+
+    for (cycle, step) in reader.enumerate() {
+        writeln!(out, "{}", format_step(cycle as u64, &step?))?;
+    }
+    out.flush()
+}
+
+fn main() {
+    if let Some(path) = env::args().nth(1) {
+        if let Err(error) = dump(&path) {
+            eprintln!("ERROR: {path}: {error}");
+            process::exit(1);
+        }
+    } else {
+        println!("USAGE: ./trace-dump program.trace");
+    }
+}
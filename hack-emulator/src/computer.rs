@@ -0,0 +1,314 @@
+use crate::keyboard::Keyboard;
+use std::rc::Rc;
+
+pub const RAM_SIZE: usize = 32768;
+pub const SCREEN: u16 = 16384;
+pub const KBD: u16 = 24576;
+
+// cloning is cheap apart from the RAM, as the ROM is shared
+#[derive(Debug, Clone)]
+pub struct Computer {
+    rom: Rc<[u16]>,
+    pub ram: Vec<u16>,
+    pub a: u16,
+    pub d: u16,
+    pub pc: u16,
+    pub cycles: u64,
+    pub keyboard: Keyboard,
+}
+
+// everything a single cycle changed, as needed for tracing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    pub pc: u16,
+    pub instruction: u16,
+    pub a: Option<u16>,
+    pub d: Option<u16>,
+    pub memory: Option<(u16, u16)>,
+}
+
+impl Computer {
+    pub fn new(rom: Vec<u16>) -> Self {
+        Self {
+            rom: rom.into(),
+            ram: vec![0; RAM_SIZE],
+            a: 0,
+            d: 0,
+            pc: 0,
+            cycles: 0,
+            keyboard: Keyboard::default(),
+        }
+    }
+
+    pub fn rom(&self) -> &[u16] {
+        &self.rom
+    }
+
+    pub fn peek(&self, address: u16) -> u16 {
+        self.ram[address as usize % RAM_SIZE]
+    }
+
+    pub fn poke(&mut self, address: u16, value: u16) {
+        self.ram[address as usize % RAM_SIZE] = value;
+    }
+
+    // true once the PC has left the ROM or sits in one of the infinite
+    // loops programs use to stop, `(END) @END 0;JMP` or `@END (END) 0;JMP`
+    pub fn halted(&self) -> bool {
+        let pc = self.pc as usize;
+
+        match self.rom.get(pc) {
+            None => true,
+            Some(&instruction) if is_jump_to_self(instruction, self.pc) => self
+                .rom
+                .get(pc + 1)
+                .is_some_and(|&next| is_unconditional_jump(next)),
+            Some(&instruction) if is_unconditional_jump(instruction) => {
+                self.a == self.pc
+                    || (pc > 0
+                        && self.a == self.pc - 1
+                        && is_jump_to_self(self.rom[pc - 1], self.a))
+            }
+            _ => false,
+        }
+    }
+
+    pub fn step(&mut self) -> Step {
+        if let Some(key) = self.keyboard.advance(self.cycles) {
+            self.poke(KBD, key);
+        }
+
+        let pc = self.pc;
+        let instruction = self.rom.get(pc as usize).copied().unwrap_or(0);
+        let mut step = Step {
+            pc,
+            instruction,
+            a: None,
+            d: None,
+            memory: None,
+        };
+
+        if instruction & 0x8000 == 0 {
+            self.a = instruction;
+            step.a = Some(instruction);
+            self.pc = pc.wrapping_add(1);
+        } else {
+            let address = self.a;
+            let out = self.compute(instruction);
+            let destinations = (instruction >> 3) & 0b111;
+
+            if destinations & 0b001 != 0 {
+                self.poke(address, out);
+                step.memory = Some((address % RAM_SIZE as u16, out));
+            }
+            if destinations & 0b010 != 0 {
+                self.d = out;
+                step.d = Some(out);
+            }
+            if destinations & 0b100 != 0 {
+                self.a = out;
+                step.a = Some(out);
+            }
+
+            if jumps(instruction, out) {
+                self.pc = address;
+            } else {
+                self.pc = pc.wrapping_add(1);
+            }
+        }
+
+        self.cycles += 1;
+        step
+    }
+
+    // runs until the program halts or `max_cycles` more cycles have passed
+    pub fn run(&mut self, max_cycles: u64) -> u64 {
+        let mut cycles = 0;
+        while cycles < max_cycles && !self.halted() {
+            self.step();
+            cycles += 1;
+        }
+        cycles
+    }
+
+    fn compute(&self, instruction: u16) -> u16 {
+        let y = if instruction & 0x1000 != 0 {
+            self.peek(self.a)
+        } else {
+            self.a
+        };
+        let control = (instruction >> 6) & 0b111111;
+
+        let mut x = if control & 0b100000 != 0 { 0 } else { self.d };
+        if control & 0b010000 != 0 {
+            x = !x;
+        }
+        let mut y = if control & 0b001000 != 0 { 0 } else { y };
+        if control & 0b000100 != 0 {
+            y = !y;
+        }
+        let out = if control & 0b000010 != 0 {
+            x.wrapping_add(y)
+        } else {
+            x & y
+        };
+        if control & 0b000001 != 0 {
+            !out
+        } else {
+            out
+        }
+    }
+}
+
+fn jumps(instruction: u16, out: u16) -> bool {
+    let out = out as i16;
+    let jump = instruction & 0b111;
+
+    (jump & 0b100 != 0 && out < 0)
+        || (jump & 0b010 != 0 && out == 0)
+        || (jump & 0b001 != 0 && out > 0)
+}
+
+fn is_jump_to_self(instruction: u16, address: u16) -> bool {
+    instruction & 0x8000 == 0 && instruction == address
+}
+
+fn is_unconditional_jump(instruction: u16) -> bool {
+    instruction & 0x8000 != 0 && instruction & 0b111 == 0b111
+}
+
+pub fn disassemble(instruction: u16) -> String {
+    if instruction & 0x8000 == 0 {
+        return format!("@{}", instruction);
+    }
+
+    let computation = match (instruction >> 6) & 0b1111111 {
+        0b0101010 => "0",
+        0b0111111 => "1",
+        0b0111010 => "-1",
+        0b0001100 => "D",
+        0b0110000 => "A",
+        0b1110000 => "M",
+        0b0001101 => "!D",
+        0b0110001 => "!A",
+        0b1110001 => "!M",
+        0b0001111 => "-D",
+        0b0110011 => "-A",
+        0b1110011 => "-M",
+        0b0011111 => "D+1",
+        0b0110111 => "A+1",
+        0b1110111 => "M+1",
+        0b0001110 => "D-1",
+        0b0110010 => "A-1",
+        0b1110010 => "M-1",
+        0b0000010 => "D+A",
+        0b1000010 => "D+M",
+        0b0010011 => "D-A",
+        0b1010011 => "D-M",
+        0b0000111 => "A-D",
+        0b1000111 => "M-D",
+        0b0000000 => "D&A",
+        0b1000000 => "D&M",
+        0b0010101 => "D|A",
+        0b1010101 => "D|M",
+        _ => "?",
+    };
+    let destinations = match (instruction >> 3) & 0b111 {
+        0b001 => "M=",
+        0b010 => "D=",
+        0b011 => "MD=",
+        0b100 => "A=",
+        0b101 => "AM=",
+        0b110 => "AD=",
+        0b111 => "AMD=",
+        _ => "",
+    };
+    let jump = match instruction & 0b111 {
+        0b001 => ";JGT",
+        0b010 => ";JEQ",
+        0b011 => ";JGE",
+        0b100 => ";JLT",
+        0b101 => ";JNE",
+        0b110 => ";JLE",
+        0b111 => ";JMP",
+        _ => "",
+    };
+
+    format!("{}{}{}", destinations, computation, jump)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::Program;
+
+    fn computer(source: &str) -> Computer {
+        Computer::new(Program::assemble(source).rom)
+    }
+
+    #[test]
+    fn test_a_instruction() {
+        let mut computer = computer("@1234");
+        let step = computer.step();
+
+        assert_eq!(1234, computer.a);
+        assert_eq!(Some(1234), step.a);
+        assert_eq!(1, computer.pc);
+    }
+
+    #[test]
+    fn test_computation_and_destinations() {
+        let mut computer = computer("@7\nD=A\n@100\nAM=D-1\nMD=-1");
+        computer.run(5);
+
+        assert_eq!(6, computer.peek(100));
+        assert_eq!(6, computer.a);
+        assert_eq!(0xffff, computer.peek(6));
+        assert_eq!(0xffff, computer.d);
+    }
+
+    #[test]
+    fn test_jumps() {
+        let mut computer = computer("@5\nD=-A\n@6\nD;JLT\n@R0\nM=1\n@R1\nM=1");
+        computer.run(100);
+
+        assert_eq!(0, computer.peek(0));
+        assert_eq!(1, computer.peek(1));
+    }
+
+    #[test]
+    fn test_halts_in_end_loop() {
+        let mut computer = computer("@R0\nM=1\n(END)\n@END\n0;JMP");
+
+        assert_eq!(2, computer.run(1000));
+        assert!(computer.halted());
+    }
+
+    #[test]
+    fn test_halts_in_jump_to_self() {
+        let mut computer = computer("@R0\nM=1\n@END\n(END)\n0;JMP");
+
+        assert_eq!(3, computer.run(1000));
+        assert!(computer.halted());
+    }
+
+    #[test]
+    fn test_keyboard_script() {
+        let mut computer =
+            computer("(LOOP)\n@KBD\nD=M\n@R0\nM=D\n@LOOP\n0;JMP");
+        computer.keyboard = Keyboard::parse("1 left\n10 none").unwrap();
+
+        computer.run(6);
+        assert_eq!(130, computer.peek(0));
+        computer.run(12);
+        assert_eq!(0, computer.peek(0));
+    }
+
+    #[test]
+    fn test_disassemble() {
+        assert_eq!("@17", disassemble(17));
+        assert_eq!("D=M", disassemble(0b1111110000010000));
+        assert_eq!("AMD=D|M;JEQ", disassemble(0b1111010101111010));
+        assert_eq!("0;JMP", disassemble(0b1110101010000111));
+    }
+}
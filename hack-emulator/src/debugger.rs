@@ -0,0 +1,371 @@
+use crate::computer::{disassemble, Computer};
+use crate::history::History;
+use crate::snapshot;
+use crate::source_map::SourceMap;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+pub const HELP: &str = "\
+step [N]             run N instructions forward (s)
+back [N]             run N instructions backward (b)
+continue [N]         run forward to the next breakpoint, for at most N
+                     instructions, 10000000 by default (c)
+reverse-continue     run backward to the previous breakpoint (rc)
+last-write ADDRESS   run backward to the previous write of RAM[ADDRESS] (lw)
+rewind CYCLE         go back to an earlier cycle
+break PC             set a breakpoint
+delete PC            remove a breakpoint
+print [FROM[-TO]]    show the registers or a range of RAM (p)
+save FILE            save a snapshot of the machine
+load FILE            resume from a snapshot, forgetting the history
+quit                 leave the debugger (q)";
+
+// how long `continue` runs for when not told, as programs such as games
+// never halt
+const CONTINUE_CYCLES: u64 = 10_000_000;
+
+pub struct Debugger {
+    pub computer: Computer,
+    history: History,
+    breakpoints: BTreeSet<u16>,
+    // shows the .vm line of each PC in translated programs
+    pub source_map: Option<SourceMap>,
+}
+
+impl Debugger {
+    pub fn new(computer: Computer) -> Self {
+        Self {
+            history: History::new(&computer),
+            computer,
+            breakpoints: BTreeSet::new(),
+            source_map: None,
+        }
+    }
+
+    pub fn execute(&mut self, line: &str) -> Result<String, String> {
+        match line.split_whitespace().collect::<Vec<&str>>()[..] {
+            ["step" | "s"] => self.step(1),
+            ["step" | "s", count] => self.step(parse_number(count)?),
+            ["back" | "b"] => self.back(1),
+            ["back" | "b", count] => self.back(parse_number(count)?),
+            ["continue" | "c"] => self.resume(CONTINUE_CYCLES),
+            ["continue" | "c", count] => self.resume(parse_number(count)?),
+            ["reverse-continue" | "rc"] => self.reverse_resume(),
+            ["last-write" | "lw", address] => {
+                self.last_write(parse_address(address)?)
+            }
+            ["rewind", cycle] => {
+                self.history
+                    .rewind(&mut self.computer, parse_number(cycle)?);
+                Ok(self.location())
+            }
+            ["break", pc] => {
+                let pc = parse_address(pc)?;
+                self.breakpoints.insert(pc);
+                Ok(format!("breakpoint set at {}", pc))
+            }
+            ["delete", pc] => {
+                let pc = parse_address(pc)?;
+                if self.breakpoints.remove(&pc) {
+                    Ok(format!("deleted breakpoint at {}", pc))
+                } else {
+                    Err(format!("no breakpoint at {}", pc))
+                }
+            }
+            ["print" | "p"] => Ok(self.location()),
+            ["print" | "p", range] => self.print(range),
+            ["save", path] => {
+                File::create(path)
+                    .and_then(|file| {
+                        snapshot::save(&self.computer, BufWriter::new(file))
+                    })
+                    .map_err(|error| format!("{}: {}", path, error))?;
+                Ok(format!("saved cycle {} to {}", self.computer.cycles, path))
+            }
+            ["load", path] => {
+                File::open(path)
+                    .and_then(|file| {
+                        snapshot::restore(
+                            &mut self.computer,
+                            BufReader::new(file),
+                        )
+                    })
+                    .map_err(|error| format!("{}: {}", path, error))?;
+                self.history = History::new(&self.computer);
+                Ok(self.location())
+            }
+            ["help" | "h"] => Ok(HELP.into()),
+            _ => Err(format!("unknown command: {}", line.trim())),
+        }
+    }
+
+    pub fn location(&self) -> String {
+        let instruction =
+            match self.computer.rom().get(self.computer.pc as usize) {
+                Some(&instruction) => disassemble(instruction),
+                None => "<end of ROM>".into(),
+            };
+
+        let location = format!(
+            "cycle {} PC={} {}  A={} D={}",
+            self.computer.cycles,
+            self.computer.pc,
+            instruction,
+            self.computer.a as i16,
+            self.computer.d as i16
+        );
+        match self
+            .source_map
+            .as_ref()
+            .and_then(|map| map.lookup(self.computer.pc))
+        {
+            Some(source) => format!("{}  {}", location, source),
+            None => location,
+        }
+    }
+
+    fn step(&mut self, count: u64) -> Result<String, String> {
+        for _ in 0..count {
+            if self.computer.halted() {
+                return Ok(format!("halted\n{}", self.location()));
+            }
+            self.history.step(&mut self.computer);
+        }
+        Ok(self.location())
+    }
+
+    fn back(&mut self, count: u64) -> Result<String, String> {
+        if self.computer.cycles < self.history.start() + count {
+            return Err("not that much history".into());
+        }
+
+        let cycle = self.computer.cycles - count;
+        self.history.rewind(&mut self.computer, cycle);
+        Ok(self.location())
+    }
+
+    fn resume(&mut self, count: u64) -> Result<String, String> {
+        for _ in 0..count {
+            if self.computer.halted() {
+                return Ok(format!("halted\n{}", self.location()));
+            }
+            self.history.step(&mut self.computer);
+            if self.breakpoints.contains(&self.computer.pc) {
+                return Ok(format!(
+                    "breakpoint at {}\n{}",
+                    self.computer.pc,
+                    self.location()
+                ));
+            }
+        }
+        Ok(format!(
+            "stopped after {} cycles\n{}",
+            count,
+            self.location()
+        ))
+    }
+
+    fn reverse_resume(&mut self) -> Result<String, String> {
+        let breakpoints = &self.breakpoints;
+        if self.history.rewind_to_last(&mut self.computer, |undo| {
+            breakpoints.contains(&undo.pc)
+        }) {
+            Ok(format!(
+                "breakpoint at {}\n{}",
+                self.computer.pc,
+                self.location()
+            ))
+        } else {
+            Ok(format!("reached the start\n{}", self.location()))
+        }
+    }
+
+    fn last_write(&mut self, address: u16) -> Result<String, String> {
+        let found = self.history.rewind_to_last(&mut self.computer, |undo| {
+            undo.memory.is_some_and(|(written, _)| written == address)
+        });
+
+        if found {
+            Ok(format!(
+                "RAM[{}]={} is written by the next instruction\n{}",
+                address,
+                self.computer.peek(address) as i16,
+                self.location()
+            ))
+        } else {
+            Ok(format!(
+                "RAM[{}] was never written\n{}",
+                address,
+                self.location()
+            ))
+        }
+    }
+
+    fn print(&self, range: &str) -> Result<String, String> {
+        let (from, to) = match range.split_once('-') {
+            Some((from, to)) => (parse_address(from)?, parse_address(to)?),
+            None => (parse_address(range)?, parse_address(range)?),
+        };
+
+        Ok((from..=to)
+            .map(|address| {
+                format!(
+                    "RAM[{}]={}",
+                    address,
+                    self.computer.peek(address) as i16
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n"))
+    }
+}
+
+fn parse_number(string: &str) -> Result<u64, String> {
+    string
+        .parse()
+        .map_err(|_| format!("invalid number: {}", string))
+}
+
+fn parse_address(string: &str) -> Result<u16, String> {
+    string
+        .parse()
+        .map_err(|_| format!("invalid address: {}", string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::Program;
+
+    // R1 = R0 * 3 by repeated addition, then stop
+    const TRIPLE: &str = "\
+        @3
+        D=A
+        @R2
+        M=D
+        (LOOP)
+        @R2
+        D=M
+        @END
+        D;JEQ
+        @R0
+        D=M
+        @R1
+        M=D+M
+        @R2
+        M=M-1
+        @LOOP
+        0;JMP
+        (END)
+        @END
+        0;JMP";
+
+    fn debugger() -> Debugger {
+        let mut computer = Computer::new(Program::assemble(TRIPLE).rom);
+        computer.poke(0, 5);
+        Debugger::new(computer)
+    }
+
+    #[test]
+    fn test_step_and_back() {
+        let mut debugger = debugger();
+
+        assert_eq!(
+            "cycle 2 PC=2 @2  A=3 D=3",
+            debugger.execute("step 2").unwrap()
+        );
+        assert_eq!(
+            "cycle 1 PC=1 D=A  A=3 D=0",
+            debugger.execute("back").unwrap()
+        );
+        assert!(debugger.execute("back 5").is_err());
+    }
+
+    #[test]
+    fn test_continue_and_reverse_continue() {
+        let mut debugger = debugger();
+        debugger.execute("break 13").unwrap();
+
+        debugger.execute("c").unwrap();
+        debugger.execute("c").unwrap();
+        assert_eq!(13, debugger.computer.pc);
+        assert_eq!(10, debugger.computer.peek(1));
+
+        debugger.execute("rc").unwrap();
+        assert_eq!(13, debugger.computer.pc);
+        assert_eq!(5, debugger.computer.peek(1));
+
+        debugger.execute("delete 13").unwrap();
+        assert!(debugger.execute("c").unwrap().starts_with("halted"));
+        assert_eq!(15, debugger.computer.peek(1));
+    }
+
+    #[test]
+    fn test_continue_stops_after_a_while() {
+        let computer = Computer::new(
+            Program::assemble("(LOOP)\n@R0\nM=M+1\n@LOOP\n0;JMP").rom,
+        );
+        let mut debugger = Debugger::new(computer);
+
+        assert!(debugger
+            .execute("c 10")
+            .unwrap()
+            .starts_with("stopped after 10 cycles\ncycle 10 "));
+        debugger.execute("c").unwrap();
+        assert_eq!(10 + CONTINUE_CYCLES, debugger.computer.cycles);
+    }
+
+    #[test]
+    fn test_last_write() {
+        let mut debugger = debugger();
+        debugger.execute("c").unwrap();
+
+        let output = debugger.execute("lw 1").unwrap();
+
+        assert!(output.starts_with("RAM[1]=10 is written by the next"));
+        assert_eq!(11, debugger.computer.pc);
+        debugger.execute("s").unwrap();
+        assert_eq!(15, debugger.computer.peek(1));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir()
+            .join(format!("debugger-{}.snapshot", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut debugger = debugger();
+        debugger.execute("step 20").unwrap();
+        debugger.execute(&format!("save {}", path)).unwrap();
+        debugger.execute("c").unwrap();
+
+        let location = debugger.execute(&format!("load {}", path)).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert!(location.starts_with("cycle 20 "));
+        assert!(debugger.execute("back").is_err());
+        assert!(debugger.execute("load /nonexistent").is_err());
+    }
+
+    #[test]
+    fn test_print() {
+        let mut debugger = debugger();
+
+        assert_eq!("RAM[0]=5\nRAM[1]=0", debugger.execute("p 0-1").unwrap());
+        assert!(debugger.execute("frobnicate").is_err());
+    }
+
+    #[test]
+    fn test_location_shows_the_source_line() {
+        let mut debugger = debugger();
+        debugger.source_map = Some(
+            SourceMap::parse("0\t4\tMain.vm\t42\tpush constant 3\n").unwrap(),
+        );
+
+        assert!(debugger
+            .execute("step 3")
+            .unwrap()
+            .ends_with("  Main.vm:42 push constant 3"));
+        assert!(!debugger.execute("step").unwrap().contains("Main.vm"));
+    }
+}
@@ -0,0 +1,255 @@
+use crate::computer::Computer;
+
+// an initial RAM cell that takes every value in `from..=to`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Input {
+    pub address: u16,
+    pub from: i16,
+    pub to: i16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    // the values of the output cells once the program stopped
+    Halted(Vec<u16>),
+    // still going after the step bound
+    Running,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Counterexample {
+    pub inputs: Vec<(u16, u16)>,
+    pub left: Outcome,
+    pub right: Outcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Equivalent { cases: u64 },
+    // No input told them apart, but on `unfinished` of them either program
+    // didn't halt in time, which proves nothing either way, as a slower
+    // program may still get there
+    Inconclusive { cases: u64, unfinished: u64 },
+    Different(Counterexample),
+}
+
+// Checks that two programs leave the same values in the output cells for
+// every combination of input values, running each for at most `steps`
+// cycles. The ranges are enumerated exhaustively, so they need to be small,
+// and none can be empty; all other RAM starts out zero.
+pub fn check(
+    left: &[u16],
+    right: &[u16],
+    inputs: &[Input],
+    outputs: &[u16],
+    steps: u64,
+) -> Verdict {
+    let left = Computer::new(left.to_vec());
+    let right = Computer::new(right.to_vec());
+    let mut values: Vec<i16> = inputs.iter().map(|input| input.from).collect();
+    let mut cases = 0;
+    let mut unfinished = 0;
+
+    assert!(
+        inputs.iter().all(|input| input.from <= input.to),
+        "empty input range"
+    );
+    loop {
+        let assignment: Vec<(u16, u16)> = inputs
+            .iter()
+            .zip(&values)
+            .map(|(input, &value)| (input.address, value as u16))
+            .collect();
+        let left = run(&left, &assignment, outputs, steps);
+        let right = run(&right, &assignment, outputs, steps);
+        cases += 1;
+
+        match (&left, &right) {
+            (Outcome::Running, _) | (_, Outcome::Running) => unfinished += 1,
+            _ if left != right => {
+                return Verdict::Different(Counterexample {
+                    inputs: assignment,
+                    left,
+                    right,
+                })
+            }
+            _ => {}
+        }
+
+        // next combination, counting like an odometer
+        let mut index = 0;
+        loop {
+            if index == inputs.len() {
+                return match unfinished {
+                    0 => Verdict::Equivalent { cases },
+                    _ => Verdict::Inconclusive { cases, unfinished },
+                };
+            }
+            if values[index] < inputs[index].to {
+                values[index] += 1;
+                break;
+            }
+            values[index] = inputs[index].from;
+            index += 1;
+        }
+    }
+}
+
+fn run(
+    computer: &Computer,
+    inputs: &[(u16, u16)],
+    outputs: &[u16],
+    steps: u64,
+) -> Outcome {
+    let mut computer = computer.clone();
+    for &(address, value) in inputs {
+        computer.poke(address, value);
+    }
+
+    computer.run(steps);
+    if computer.halted() {
+        Outcome::Halted(
+            outputs
+                .iter()
+                .map(|&address| computer.peek(address))
+                .collect(),
+        )
+    } else {
+        Outcome::Running
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::Program;
+
+    // R2 = R0 * R1 by adding R0 to itself R1 times
+    const MULT: &str = "\
+        @R2
+        M=0
+        (LOOP)
+        @R1
+        D=M
+        @END
+        D;JLE
+        @R0
+        D=M
+        @R2
+        M=D+M
+        @R1
+        M=M-1
+        @LOOP
+        0;JMP
+        (END)
+        @END
+        0;JMP";
+
+    // the same by shifting and adding, one step per bit of R1
+    const SHIFT_MULT: &str = "\
+        @R2
+        M=0
+        @R0
+        D=M
+        @addend
+        M=D
+        @bit
+        M=1
+        (LOOP)
+        @bit
+        D=M
+        @END
+        D;JEQ
+        @R1
+        D=D&M
+        @SKIP
+        D;JEQ
+        @addend
+        D=M
+        @R2
+        M=D+M
+        (SKIP)
+        @addend
+        D=M
+        M=D+M
+        @bit
+        D=M
+        M=D+M
+        @LOOP
+        0;JMP
+        (END)
+        @END
+        0;JMP";
+
+    fn rom(source: &str) -> Vec<u16> {
+        Program::assemble(source).rom
+    }
+
+    fn inputs(to: i16) -> Vec<Input> {
+        vec![
+            Input {
+                address: 0,
+                from: 0,
+                to,
+            },
+            Input {
+                address: 1,
+                from: 0,
+                to,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_equivalent_programs() {
+        let verdict =
+            check(&rom(MULT), &rom(SHIFT_MULT), &inputs(20), &[2], 10_000);
+
+        assert_eq!(Verdict::Equivalent { cases: 441 }, verdict);
+    }
+
+    #[test]
+    fn test_counterexample() {
+        // forgets to clear R2 first
+        let broken = MULT.replacen("M=0", "M=M", 1);
+        let mut inputs = inputs(3);
+        inputs.push(Input {
+            address: 2,
+            from: 0,
+            to: 1,
+        });
+
+        let verdict = check(&rom(MULT), &rom(&broken), &inputs, &[2], 1000);
+
+        assert_eq!(
+            Verdict::Different(Counterexample {
+                inputs: vec![(0, 0), (1, 0), (2, 1)],
+                left: Outcome::Halted(vec![0]),
+                right: Outcome::Halted(vec![1]),
+            }),
+            verdict
+        );
+    }
+
+    #[test]
+    fn test_step_bound() {
+        let forever = rom("(LOOP)\n@R3\nM=M+1\n@LOOP\n0;JMP");
+
+        assert_eq!(
+            Verdict::Inconclusive {
+                cases: 16,
+                unfinished: 16
+            },
+            check(&forever, &forever, &inputs(3), &[2], 100)
+        );
+        // MULT halts in time on some inputs, but that doesn't make them
+        // counterexamples
+        assert_eq!(
+            Verdict::Inconclusive {
+                cases: 16,
+                unfinished: 16
+            },
+            check(&rom(MULT), &forever, &inputs(3), &[2], 100)
+        );
+    }
+}
@@ -0,0 +1,305 @@
+use crate::computer::{Computer, Step, KBD};
+
+pub const DEFAULT_INTERVAL: u64 = 65536;
+const MAX_CHECKPOINTS: usize = 256;
+
+// what a single cycle overwrote, so it can be put back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Undo {
+    pub pc: u16,
+    pub a: u16,
+    pub d: u16,
+    pub memory: Option<(u16, u16)>,
+    // script position and keyboard register before a key event
+    pub keyboard: Option<(usize, u16)>,
+}
+
+// Execution history that lets a computer run backwards. Full copies of the
+// machine are kept every `interval` cycles and an undo log covers the cycles
+// since the newest one, so going back within the current segment is just
+// popping the log. Going back further restores an older checkpoint and
+// replays forward, which works because execution is deterministic. When the
+// checkpoints pile up, every other one is dropped and the interval doubled,
+// so memory stays bounded however long the program runs.
+pub struct History {
+    interval: u64,
+    checkpoints: Vec<Computer>,
+    undo: Vec<Undo>,
+}
+
+impl History {
+    pub fn new(computer: &Computer) -> Self {
+        Self::with_interval(computer, DEFAULT_INTERVAL)
+    }
+
+    pub fn with_interval(computer: &Computer, interval: u64) -> Self {
+        Self {
+            interval: interval.max(1),
+            checkpoints: vec![computer.clone()],
+            undo: vec![],
+        }
+    }
+
+    // the earliest cycle that can be rewound to
+    pub fn start(&self) -> u64 {
+        self.checkpoints[0].cycles
+    }
+
+    pub fn step(&mut self, computer: &mut Computer) -> Step {
+        let step = self.record(computer);
+
+        if computer.cycles - self.segment_start() >= self.interval {
+            self.checkpoint(computer);
+        }
+        step
+    }
+
+    // moves back to `cycle`, which must not be in the future
+    pub fn rewind(&mut self, computer: &mut Computer, cycle: u64) {
+        let cycle = cycle.max(self.start()).min(computer.cycles);
+
+        if cycle < self.segment_start() {
+            let index = self
+                .checkpoints
+                .partition_point(|checkpoint| checkpoint.cycles <= cycle)
+                - 1;
+            self.checkpoints.truncate(index + 1);
+            *computer = self.checkpoints[index].clone();
+            self.undo.clear();
+
+            while computer.cycles < cycle {
+                self.step(computer);
+            }
+        } else {
+            while computer.cycles > cycle {
+                let undo = self.undo.pop().unwrap();
+                if let Some((address, value)) = undo.memory {
+                    computer.poke(address, value);
+                }
+                if let Some((position, key)) = undo.keyboard {
+                    computer.keyboard.position = position;
+                    computer.poke(KBD, key);
+                }
+                computer.pc = undo.pc;
+                computer.a = undo.a;
+                computer.d = undo.d;
+                computer.cycles -= 1;
+            }
+        }
+    }
+
+    // Moves back to just before the most recent cycle whose undo record
+    // matches, searching older segments by replaying them. Returns false and
+    // stops at the start of the history when no cycle matches.
+    pub fn rewind_to_last<F>(
+        &mut self,
+        computer: &mut Computer,
+        matches: F,
+    ) -> bool
+    where
+        F: Fn(&Undo) -> bool,
+    {
+        loop {
+            let start = self.segment_start();
+
+            if let Some(index) = self.undo.iter().rposition(&matches) {
+                self.rewind(computer, start + index as u64);
+                return true;
+            }
+            if self.checkpoints.len() == 1 {
+                self.rewind(computer, start);
+                return false;
+            }
+
+            // replay the previous segment to get its undo log back
+            self.checkpoints.pop();
+            *computer = self.checkpoints.last().unwrap().clone();
+            self.undo.clear();
+            while computer.cycles < start {
+                self.record(computer);
+            }
+        }
+    }
+
+    fn segment_start(&self) -> u64 {
+        self.checkpoints.last().unwrap().cycles
+    }
+
+    fn record(&mut self, computer: &mut Computer) -> Step {
+        let mut undo = Undo {
+            pc: computer.pc,
+            a: computer.a,
+            d: computer.d,
+            memory: None,
+            keyboard: None,
+        };
+        let position = computer.keyboard.position;
+        let key = computer.peek(KBD);
+        // a C-instruction can only write the cell A points at
+        let previous = computer.peek(computer.a);
+
+        let step = computer.step();
+        if let Some((address, _)) = step.memory {
+            undo.memory = Some((address, previous));
+        }
+        if computer.keyboard.position != position {
+            undo.keyboard = Some((position, key));
+        }
+        self.undo.push(undo);
+        step
+    }
+
+    fn checkpoint(&mut self, computer: &Computer) {
+        self.checkpoints.push(computer.clone());
+        self.undo.clear();
+
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            let last = self.checkpoints.pop().unwrap();
+            let mut index = 0;
+            self.checkpoints.retain(|_| {
+                index += 1;
+                index % 2 == 1
+            });
+            self.checkpoints.push(last);
+            self.interval *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::Keyboard;
+    use crate::program::Program;
+
+    // counts RAM[16] up forever, touching RAM[17] every 8th iteration
+    const COUNTER: &str = "\
+        (LOOP)
+        @16
+        M=M+1
+        D=M
+        @7
+        D=D&A
+        @LOOP
+        D;JNE
+        @17
+        M=M+1
+        @LOOP
+        0;JMP";
+
+    fn run(computer: &mut Computer, history: &mut History, cycles: u64) {
+        for _ in 0..cycles {
+            history.step(computer);
+        }
+    }
+
+    fn same_state(left: &Computer, right: &Computer) -> bool {
+        left.pc == right.pc
+            && left.a == right.a
+            && left.d == right.d
+            && left.cycles == right.cycles
+            && left.ram == right.ram
+    }
+
+    #[test]
+    fn test_rewind_within_segment() {
+        let mut computer = Computer::new(Program::assemble(COUNTER).rom);
+        let mut history = History::new(&computer);
+        run(&mut computer, &mut history, 100);
+        let expected = computer.clone();
+        run(&mut computer, &mut history, 50);
+
+        history.rewind(&mut computer, 100);
+
+        assert!(same_state(&expected, &computer));
+    }
+
+    #[test]
+    fn test_rewind_across_checkpoints() {
+        let mut computer = Computer::new(Program::assemble(COUNTER).rom);
+        let mut history = History::with_interval(&computer, 64);
+        run(&mut computer, &mut history, 1000);
+        let expected = computer.clone();
+        run(&mut computer, &mut history, 5000);
+
+        history.rewind(&mut computer, 1000);
+        assert!(same_state(&expected, &computer));
+
+        // and forward again from there
+        run(&mut computer, &mut history, 10);
+        history.rewind(&mut computer, 3);
+        assert_eq!(3, computer.cycles);
+        assert_eq!(1, computer.peek(16));
+    }
+
+    #[test]
+    fn test_rewind_restores_keyboard() {
+        let mut computer = Computer::new(Program::assemble(COUNTER).rom);
+        computer.keyboard = Keyboard::parse("5 left\n500 none").unwrap();
+        let mut history = History::with_interval(&computer, 100);
+        run(&mut computer, &mut history, 50);
+        let expected = computer.clone();
+        run(&mut computer, &mut history, 1000);
+
+        history.rewind(&mut computer, 50);
+        assert!(same_state(&expected, &computer));
+        assert_eq!(130, computer.peek(KBD));
+
+        history.rewind(&mut computer, 3);
+        assert_eq!(0, computer.peek(KBD));
+        assert_eq!(0, computer.keyboard.position);
+    }
+
+    #[test]
+    fn test_rewind_to_last_write() {
+        let mut computer = Computer::new(Program::assemble(COUNTER).rom);
+        let mut history = History::with_interval(&computer, 100);
+        run(&mut computer, &mut history, 2000);
+
+        let found = history.rewind_to_last(&mut computer, |undo| {
+            undo.memory.is_some_and(|(address, _)| address == 17)
+        });
+
+        // stopped right before the write, which happens on the next step
+        assert!(found);
+        assert_eq!(8, computer.pc);
+        let value = computer.peek(17);
+        history.step(&mut computer);
+        assert_eq!(value + 1, computer.peek(17));
+    }
+
+    #[test]
+    fn test_rewind_to_last_without_match_stops_at_start() {
+        let mut computer = Computer::new(Program::assemble(COUNTER).rom);
+        let mut history = History::with_interval(&computer, 100);
+        run(&mut computer, &mut history, 1000);
+
+        let found = history.rewind_to_last(&mut computer, |undo| {
+            undo.memory.is_some_and(|(address, _)| address == 18)
+        });
+
+        assert!(!found);
+        assert_eq!(0, computer.cycles);
+    }
+
+    #[test]
+    fn test_long_runs_keep_checkpoints_bounded() {
+        let mut computer = Computer::new(Program::assemble(COUNTER).rom);
+        let mut history = History::with_interval(&computer, 1000);
+        run(&mut computer, &mut history, 3_000_000);
+        let expected = computer.clone();
+        run(&mut computer, &mut history, 10);
+
+        assert!(history.checkpoints.len() <= MAX_CHECKPOINTS);
+        assert!(history.undo.len() as u64 <= history.interval);
+
+        let found =
+            history.rewind_to_last(&mut computer, |undo| undo.memory.is_some());
+        assert!(found);
+        history.rewind(&mut computer, 3_000_000);
+        assert!(same_state(&expected, &computer));
+
+        history.rewind(&mut computer, 12_345);
+        assert_eq!(12_345, computer.cycles);
+    }
+}
@@ -0,0 +1,157 @@
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub cycle: u64,
+    pub key: u16,
+}
+
+// A scripted keyboard: each event sets the keyboard register once the
+// computer reaches its cycle, which keeps runs reproducible.
+#[derive(Debug, Clone, Default)]
+pub struct Keyboard {
+    events: Rc<[KeyEvent]>,
+    pub position: usize,
+}
+
+impl Keyboard {
+    pub fn new(mut events: Vec<KeyEvent>) -> Self {
+        events.sort_by_key(|event| event.cycle);
+
+        Self {
+            events: events.into(),
+            position: 0,
+        }
+    }
+
+    // One event per line as `CYCLE KEY`, where the key is a key code, a
+    // single character, a key name such as `left` or `f1`, or `none` for
+    // releasing all keys.
+    pub fn parse(script: &str) -> Result<Self, String> {
+        let mut events = vec![];
+
+        for (number, line) in script.lines().enumerate() {
+            let line = match line.split_once("//") {
+                Some((event, _comment)) => event,
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let event = line.split_once(' ').and_then(|(cycle, key)| {
+                Some(KeyEvent {
+                    cycle: cycle.parse().ok()?,
+                    key: parse_key(key.trim())?,
+                })
+            });
+            match event {
+                Some(event) => events.push(event),
+                None => {
+                    return Err(format!(
+                        "line {}: invalid key event: {}",
+                        number + 1,
+                        line
+                    ))
+                }
+            }
+        }
+
+        Ok(Self::new(events))
+    }
+
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
+    }
+
+    // the key pressed by the events due at `cycle`, if any are
+    pub fn advance(&mut self, cycle: u64) -> Option<u16> {
+        let mut key = None;
+        while let Some(event) = self.events.get(self.position) {
+            if event.cycle > cycle {
+                break;
+            }
+            key = Some(event.key);
+            self.position += 1;
+        }
+        key
+    }
+}
+
+fn parse_key(string: &str) -> Option<u16> {
+    let key = match string {
+        "none" => 0,
+        "space" => 32,
+        "newline" | "enter" => 128,
+        "backspace" => 129,
+        "left" => 130,
+        "up" => 131,
+        "right" => 132,
+        "down" => 133,
+        "home" => 134,
+        "end" => 135,
+        "pageup" => 136,
+        "pagedown" => 137,
+        "insert" => 138,
+        "delete" => 139,
+        "esc" => 140,
+        _ => {
+            if let Some(Ok(number @ 1..=12)) =
+                string.strip_prefix('f').map(str::parse::<u16>)
+            {
+                return Some(140 + number);
+            }
+            let mut chars = string.chars();
+            return match (chars.next(), chars.next()) {
+                (Some(ch), None) if ch.is_ascii_graphic() => Some(ch as u16),
+                _ => string.parse().ok(),
+            };
+        }
+    };
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let keyboard = Keyboard::parse(
+            "// press and release\n300 none\n100 left\n\n200 q // quit\n250 f2\n260 65",
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![100, 200, 250, 260, 300],
+            keyboard
+                .events()
+                .iter()
+                .map(|e| e.cycle)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![130, 'q' as u16, 142, 65, 0],
+            keyboard.events().iter().map(|e| e.key).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_events() {
+        assert!(Keyboard::parse("100").is_err());
+        assert!(Keyboard::parse("soon left").is_err());
+        assert!(Keyboard::parse("100 hyper").is_err());
+    }
+
+    #[test]
+    fn test_advance() {
+        let mut keyboard = Keyboard::parse("10 a\n10 b\n20 none").unwrap();
+
+        assert_eq!(None, keyboard.advance(9));
+        assert_eq!(Some('b' as u16), keyboard.advance(10));
+        assert_eq!(None, keyboard.advance(19));
+        assert_eq!(Some(0), keyboard.advance(25));
+        assert_eq!(3, keyboard.position);
+    }
+}
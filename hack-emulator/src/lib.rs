@@ -0,0 +1,10 @@
+pub mod computer;
+pub mod debugger;
+pub mod equivalence;
+pub mod history;
+pub mod keyboard;
+pub mod profiler;
+pub mod program;
+pub mod snapshot;
+pub mod source_map;
+pub mod trace;
@@ -0,0 +1,410 @@
+use crate::program::Program;
+use std::collections::HashMap;
+
+const START: &str = "<start>";
+const ROOT: usize = 0;
+
+// A node of the call tree, one per distinct call path
+struct Frame {
+    function: Option<usize>,
+    parent: usize,
+    children: HashMap<usize, usize>,
+}
+
+// Attributes executed instructions to the label region they are in, that is
+// the nearest label at or before the PC. For translated VM code, the
+// function labels and the `$return.N` labels around each call also give the
+// call tree: reaching a function label enters that function and reaching a
+// return label goes back to the caller.
+pub struct Profiler {
+    regions: Vec<String>,
+    region_at: Vec<usize>,
+    functions: Vec<String>,
+    function_at: Vec<Option<usize>>,
+    return_at: Vec<bool>,
+    flat: Vec<u64>,
+    frames: Vec<Frame>,
+    current: usize,
+    samples: HashMap<(usize, usize), u64>,
+    total: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub own: u64,
+    pub cumulative: u64,
+}
+
+impl Profiler {
+    pub fn new(program: &Program) -> Self {
+        let size = program.rom.len();
+        let mut regions = vec![START.to_owned()];
+        let mut region_at = vec![0; size];
+        let mut functions: Vec<String> = vec![];
+        let mut function_at = vec![None; size];
+        let mut return_at = vec![false; size];
+        let mut starts: Vec<(usize, usize)> = vec![];
+
+        let mut labels = program.labels.clone();
+        labels.sort_by_key(|(_, address)| *address);
+        for (name, address) in labels {
+            let address = address as usize;
+            if address >= size {
+                continue;
+            }
+            if is_function_label(&name) {
+                function_at[address] = Some(functions.len());
+                functions.push(name.clone());
+            } else if name.contains("$return.") {
+                return_at[address] = true;
+            }
+            // the last of several labels at one address names the region
+            if let Some(last) =
+                starts.last_mut().filter(|(at, _)| *at == address)
+            {
+                last.1 = regions.len();
+            } else {
+                starts.push((address, regions.len()));
+            }
+            regions.push(name);
+        }
+        for (index, &(address, region)) in starts.iter().enumerate() {
+            let end = starts.get(index + 1).map_or(size, |(next, _)| *next);
+            region_at[address..end].fill(region);
+        }
+
+        Self {
+            flat: vec![0; regions.len()],
+            regions,
+            region_at,
+            functions,
+            function_at,
+            return_at,
+            frames: vec![Frame {
+                function: None,
+                parent: ROOT,
+                children: HashMap::new(),
+            }],
+            current: ROOT,
+            samples: HashMap::new(),
+            total: 0,
+        }
+    }
+
+    // counts the instruction at `pc`, which is about to be executed
+    pub fn record(&mut self, pc: u16) {
+        let address = pc as usize;
+        self.total += 1;
+        if address >= self.region_at.len() {
+            return;
+        }
+
+        if self.return_at[address] {
+            self.current = self.frames[self.current].parent;
+        }
+        if let Some(function) = self.function_at[address] {
+            self.current = self.enter(function);
+        }
+
+        let region = self.region_at[address];
+        self.flat[region] += 1;
+        *self.samples.entry((self.current, region)).or_default() += 1;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    // cycles per label region, busiest first
+    pub fn flat(&self) -> Vec<(String, u64)> {
+        let mut flat: Vec<(String, u64)> = self
+            .regions
+            .iter()
+            .cloned()
+            .zip(self.flat.iter().copied())
+            .filter(|(_, cycles)| *cycles > 0)
+            .collect();
+        flat.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        flat
+    }
+
+    // cycles spent in each function itself and including its callees,
+    // counting recursive calls only once, busiest first
+    pub fn functions(&self) -> Vec<FunctionProfile> {
+        let mut inclusive = vec![0; self.frames.len()];
+        for (&(frame, _), &cycles) in &self.samples {
+            inclusive[frame] += cycles;
+        }
+        let mut own = vec![0; self.functions.len()];
+        for (frame, cycles) in inclusive.iter().enumerate() {
+            if let Some(function) = self.frames[frame].function {
+                own[function] += cycles;
+            }
+        }
+        // children are always created after their parents
+        for frame in (1..self.frames.len()).rev() {
+            inclusive[self.frames[frame].parent] += inclusive[frame];
+        }
+
+        let mut cumulative = vec![0; self.functions.len()];
+        for (frame, cycles) in inclusive.iter().enumerate() {
+            if let Some(function) = self.frames[frame].function {
+                if !self.is_recursive(frame, function) {
+                    cumulative[function] += cycles;
+                }
+            }
+        }
+
+        let mut profiles: Vec<FunctionProfile> = (0..self.functions.len())
+            .filter(|&function| cumulative[function] > 0)
+            .map(|function| FunctionProfile {
+                name: self.functions[function].clone(),
+                own: own[function],
+                cumulative: cumulative[function],
+            })
+            .collect();
+        profiles.sort_by(|a, b| {
+            b.cumulative
+                .cmp(&a.cumulative)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        profiles
+    }
+
+    pub fn report(&self) -> String {
+        let total = self.total.max(1) as f64;
+        let mut lines = vec![
+            format!("{} cycles", self.total),
+            String::new(),
+            format!("{:>12} {:>7}  label", "cycles", "%"),
+        ];
+        for (name, cycles) in self.flat() {
+            lines.push(format!(
+                "{:>12} {:>6.2}%  {}",
+                cycles,
+                cycles as f64 * 100.0 / total,
+                name
+            ));
+        }
+
+        let functions = self.functions();
+        if !functions.is_empty() {
+            lines.push(String::new());
+            lines.push(format!(
+                "{:>12} {:>7} {:>12} {:>7}  function",
+                "self", "%", "cumulative", "%"
+            ));
+            for function in functions {
+                lines.push(format!(
+                    "{:>12} {:>6.2}% {:>12} {:>6.2}%  {}",
+                    function.own,
+                    function.own as f64 * 100.0 / total,
+                    function.cumulative,
+                    function.cumulative as f64 * 100.0 / total,
+                    function.name
+                ));
+            }
+        }
+
+        lines.join("\n")
+    }
+
+    // One line per call path and label region, as `outer;inner;region N`,
+    // the input format of flamegraph tools
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = self
+            .samples
+            .iter()
+            .map(|(&(frame, region), cycles)| {
+                let mut stack = self.call_path(frame);
+                let region = &self.regions[region];
+                if stack.last() != Some(region) {
+                    stack.push(region.clone());
+                }
+                format!("{} {}", stack.join(";"), cycles)
+            })
+            .collect();
+        lines.sort();
+        lines.join("\n")
+    }
+
+    fn enter(&mut self, function: usize) -> usize {
+        if let Some(&frame) = self.frames[self.current].children.get(&function)
+        {
+            return frame;
+        }
+
+        let frame = self.frames.len();
+        self.frames.push(Frame {
+            function: Some(function),
+            parent: self.current,
+            children: HashMap::new(),
+        });
+        self.frames[self.current].children.insert(function, frame);
+        frame
+    }
+
+    fn is_recursive(&self, frame: usize, function: usize) -> bool {
+        let mut ancestor = self.frames[frame].parent;
+        while ancestor != ROOT {
+            if self.frames[ancestor].function == Some(function) {
+                return true;
+            }
+            ancestor = self.frames[ancestor].parent;
+        }
+        false
+    }
+
+    fn call_path(&self, mut frame: usize) -> Vec<String> {
+        let mut path = vec![];
+        while frame != ROOT {
+            if let Some(function) = self.frames[frame].function {
+                path.push(self.functions[function].clone());
+            }
+            frame = self.frames[frame].parent;
+        }
+        path.reverse();
+        path
+    }
+}
+
+// Translated VM functions are labelled `Class.name`, where the name is a
+// Jack identifier and so can't start with a digit. Other labels the
+// translator generates contain a `$`.
+fn is_function_label(name: &str) -> bool {
+    match name.rsplit_once('.') {
+        Some((class, function)) => {
+            !name.contains('$')
+                && !class.is_empty()
+                && function
+                    .chars()
+                    .next()
+                    .is_some_and(|ch| ch.is_ascii_alphabetic() || ch == '_')
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::computer::Computer;
+
+    fn profile(source: &str) -> Profiler {
+        let program = Program::assemble(source);
+        let mut profiler = Profiler::new(&program);
+        let mut computer = Computer::new(program.rom);
+
+        while !computer.halted() {
+            profiler.record(computer.pc);
+            computer.step();
+        }
+        profiler
+    }
+
+    // Main.main calls Main.twice, which calls Main.once, with the return
+    // address in R15 like the VM translator's calling convention
+    const CALLS: &str = "\
+        @Main.main
+        0;JMP
+        (Main.once)
+        @R15
+        A=M
+        0;JMP
+        (Main.twice)
+        @R14
+        M=D
+        @Main.twice$return.1
+        D=A
+        @R15
+        M=D
+        @Main.once
+        0;JMP
+        (Main.twice$return.1)
+        @R14
+        A=M
+        0;JMP
+        (Main.main)
+        @Main.main$return.0
+        D=A
+        @Main.twice
+        0;JMP
+        (Main.main$return.0)
+        (Main.main$END)
+        @Main.main$END
+        0;JMP";
+
+    #[test]
+    fn test_flat_profile() {
+        let profiler =
+            profile("@3\nD=A\n(LOOP)\nD=D-1\n@LOOP\nD;JGT\n(END)\n@END\n0;JMP");
+
+        assert_eq!(11, profiler.total());
+        assert_eq!(
+            vec![("LOOP".to_owned(), 9), ("<start>".to_owned(), 2)],
+            profiler.flat()
+        );
+        assert!(profiler.functions().is_empty());
+        assert_eq!("<start> 2\nLOOP 9", profiler.folded());
+    }
+
+    #[test]
+    fn test_call_tree_profile() {
+        let profiler = profile(CALLS);
+
+        assert_eq!(
+            vec![
+                FunctionProfile {
+                    name: "Main.main".into(),
+                    own: 4,
+                    cumulative: 18,
+                },
+                FunctionProfile {
+                    name: "Main.twice".into(),
+                    own: 11,
+                    cumulative: 14,
+                },
+                FunctionProfile {
+                    name: "Main.once".into(),
+                    own: 3,
+                    cumulative: 3,
+                },
+            ],
+            profiler.functions()
+        );
+        assert_eq!(
+            "<start> 2\n\
+             Main.main 4\n\
+             Main.main;Main.twice 8\n\
+             Main.main;Main.twice;Main.once 3\n\
+             Main.main;Main.twice;Main.twice$return.1 3",
+            profiler.folded()
+        );
+    }
+
+    #[test]
+    fn test_recursion_is_counted_once() {
+        let mut profiler = Profiler::new(&Program::assemble(
+            "(Main.f)\n@Main.f\n0;JMP\n(Main.f$return.0)\n0",
+        ));
+        for pc in [0, 1, 0, 1, 0, 1, 2, 2] {
+            profiler.record(pc);
+        }
+
+        let functions = profiler.functions();
+        assert_eq!(1, functions.len());
+        assert_eq!(8, functions[0].cumulative);
+        assert_eq!(8, functions[0].own);
+    }
+
+    #[test]
+    fn test_is_function_label() {
+        assert!(is_function_label("Main.main"));
+        assert!(is_function_label("Sys.init"));
+        assert!(!is_function_label("Main.main$LOOP"));
+        assert!(!is_function_label("Main.main$return.3"));
+        assert!(!is_function_label("EQUAL.3"));
+        assert!(!is_function_label("LOOP"));
+    }
+}
@@ -0,0 +1,98 @@
+use hack_assembler::assembler;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub rom: Vec<u16>,
+    // label names and addresses, only known when loaded from assembly
+    pub labels: Vec<(String, u16)>,
+}
+
+impl Program {
+    // loads either assembly source (`.asm`) or assembled machine code
+    pub fn load<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let contents = fs::read_to_string(&path)?;
+
+        match path.as_ref().extension() {
+            Some(extension) if extension == "asm" => {
+                Ok(Self::assemble(&contents))
+            }
+            _ => Self::parse_binary(&contents),
+        }
+    }
+
+    pub fn assemble(source: &str) -> Self {
+        let rom = assembler::compile(source)
+            .iter()
+            .map(|word| u16::from_str_radix(word, 2).unwrap())
+            .collect();
+
+        Self {
+            rom,
+            labels: assembler::labels(source),
+        }
+    }
+
+    pub fn parse_binary(contents: &str) -> io::Result<Self> {
+        let mut rom = vec![];
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            match u16::from_str_radix(line, 2) {
+                Ok(word) if line.len() == 16 => rom.push(word),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "line {}: invalid instruction {}",
+                            number + 1,
+                            line
+                        ),
+                    ))
+                }
+            }
+        }
+
+        Ok(Self {
+            rom,
+            labels: vec![],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble_keeps_labels() {
+        let program = Program::assemble("@END\n0;JMP\n(END)\n@END\n0;JMP");
+
+        assert_eq!(4, program.rom.len());
+        assert_eq!(vec![("END".to_owned(), 2)], program.labels);
+    }
+
+    #[test]
+    fn test_parse_binary() {
+        let program =
+            Program::parse_binary("0000000000000111\n\n1110101010000111\n")
+                .unwrap();
+
+        assert_eq!(vec![7, 0b1110101010000111], program.rom);
+    }
+
+    #[test]
+    fn test_parse_binary_rejects_garbage() {
+        assert!(Program::parse_binary("00000001\n").is_err());
+        assert!(Program::parse_binary("@7\n").is_err());
+    }
+}
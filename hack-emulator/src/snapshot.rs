@@ -0,0 +1,242 @@
+use crate::computer::{Computer, RAM_SIZE};
+use crate::keyboard::{KeyEvent, Keyboard};
+use std::io;
+use std::io::prelude::*;
+
+// A snapshot is the magic bytes and a format version, the identity of the
+// program (a hash of the ROM and its length), the registers and cycle count,
+// the whole keyboard script with its position, and the RAM as runs of equal
+// words. The script is included so a snapshot is enough to reproduce the
+// rest of a session. All numbers are little-endian.
+const MAGIC: &[u8; 4] = b"HSNP";
+const VERSION: u16 = 1;
+
+// FNV-1a over the ROM words
+pub fn rom_hash(rom: &[u16]) -> u64 {
+    rom.iter()
+        .flat_map(|word| word.to_le_bytes())
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+pub fn save<W: Write>(computer: &Computer, mut writer: W) -> io::Result<()> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(VERSION.to_le_bytes());
+    bytes.extend(rom_hash(computer.rom()).to_le_bytes());
+    bytes.extend((computer.rom().len() as u32).to_le_bytes());
+    bytes.extend(computer.pc.to_le_bytes());
+    bytes.extend(computer.a.to_le_bytes());
+    bytes.extend(computer.d.to_le_bytes());
+    bytes.extend(computer.cycles.to_le_bytes());
+
+    let events = computer.keyboard.events();
+    bytes.extend((events.len() as u32).to_le_bytes());
+    for event in events {
+        bytes.extend(event.cycle.to_le_bytes());
+        bytes.extend(event.key.to_le_bytes());
+    }
+    bytes.extend((computer.keyboard.position as u32).to_le_bytes());
+
+    let mut words = computer.ram.iter().peekable();
+    while let Some(&value) = words.next() {
+        let mut count: u16 = 1;
+        while count < u16::MAX && words.next_if_eq(&&value).is_some() {
+            count += 1;
+        }
+        bytes.extend(count.to_le_bytes());
+        bytes.extend(value.to_le_bytes());
+    }
+
+    writer.write_all(&bytes)?;
+    writer.flush()
+}
+
+// Restores a snapshot into a computer running the same program. Nothing is
+// changed unless the whole snapshot is valid.
+pub fn restore<R: Read>(computer: &mut Computer, reader: R) -> io::Result<()> {
+    let mut reader = SnapshotReader { reader };
+
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a Hack snapshot"));
+    }
+    let version = reader.read_u16()?;
+    if version != VERSION {
+        return Err(invalid_data(format!(
+            "unsupported snapshot version {}",
+            version
+        )));
+    }
+    let hash = reader.read_u64()?;
+    let length = reader.read_u32()?;
+    if hash != rom_hash(computer.rom())
+        || length as usize != computer.rom().len()
+    {
+        return Err(invalid_data("snapshot was taken of a different program"));
+    }
+
+    let pc = reader.read_u16()?;
+    let a = reader.read_u16()?;
+    let d = reader.read_u16()?;
+    let cycles = reader.read_u64()?;
+
+    let mut events = vec![];
+    for _ in 0..reader.read_u32()? {
+        events.push(KeyEvent {
+            cycle: reader.read_u64()?,
+            key: reader.read_u16()?,
+        });
+    }
+    let mut keyboard = Keyboard::new(events);
+    keyboard.position = reader.read_u32()? as usize;
+    if keyboard.position > keyboard.events().len() {
+        return Err(invalid_data("keyboard position is past the script"));
+    }
+
+    let mut ram = Vec::with_capacity(RAM_SIZE);
+    while ram.len() < RAM_SIZE {
+        let count = reader.read_u16()? as usize;
+        let value = reader.read_u16()?;
+        if count == 0 || ram.len() + count > RAM_SIZE {
+            return Err(invalid_data("corrupt RAM contents"));
+        }
+        ram.resize(ram.len() + count, value);
+    }
+
+    computer.pc = pc;
+    computer.a = a;
+    computer.d = d;
+    computer.cycles = cycles;
+    computer.keyboard = keyboard;
+    computer.ram = ram;
+    Ok(())
+}
+
+struct SnapshotReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> SnapshotReader<R> {
+    fn read_exact(&mut self, bytes: &mut [u8]) -> io::Result<()> {
+        self.reader.read_exact(bytes).map_err(|error| {
+            if error.kind() == io::ErrorKind::UnexpectedEof {
+                invalid_data("truncated snapshot")
+            } else {
+                error
+            }
+        })
+    }
+
+    fn read_u16(&mut self) -> io::Result<u16> {
+        let mut bytes = [0; 2];
+        self.read_exact(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        self.read_exact(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        self.read_exact(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+fn invalid_data<S>(message: S) -> io::Error
+where
+    S: Into<String>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::Program;
+
+    // copies the keyboard into a growing stretch of the screen
+    const ECHO: &str = "\
+        @SCREEN
+        D=A
+        @R0
+        M=D
+        (LOOP)
+        @KBD
+        D=M
+        @R0
+        M=M+1
+        A=M
+        M=D
+        @LOOP
+        0;JMP";
+
+    fn computer() -> Computer {
+        let mut computer = Computer::new(Program::assemble(ECHO).rom);
+        computer.keyboard =
+            Keyboard::parse("100 left\n400 none\n700 q").unwrap();
+        computer
+    }
+
+    fn snapshot(computer: &Computer) -> Vec<u8> {
+        let mut bytes = vec![];
+        save(computer, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_save_and_restore_resumes_the_session() {
+        let mut original = computer();
+        original.run(500);
+        let bytes = snapshot(&original);
+
+        let mut resumed = computer();
+        restore(&mut resumed, &bytes[..]).unwrap();
+        assert_eq!(500, resumed.cycles);
+        assert_eq!(original.ram, resumed.ram);
+        assert_eq!(2, resumed.keyboard.position);
+
+        original.run(1000);
+        resumed.run(1000);
+        assert_eq!(original.ram, resumed.ram);
+        assert_eq!(original.pc, resumed.pc);
+        assert_eq!(original.d, resumed.d);
+    }
+
+    #[test]
+    fn test_ram_is_run_length_encoded() {
+        let bytes = snapshot(&computer());
+
+        assert!(bytes.len() < 100);
+    }
+
+    #[test]
+    fn test_rejects_snapshot_of_another_program() {
+        let bytes = snapshot(&computer());
+        let mut other = Computer::new(Program::assemble("@0\nD=A").rom);
+
+        let error = restore(&mut other, &bytes[..]).unwrap_err();
+        assert_eq!(
+            "snapshot was taken of a different program",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn test_rejects_unknown_versions_and_corruption() {
+        let mut bytes = snapshot(&computer());
+        let mut computer = computer();
+
+        assert!(restore(&mut computer, &bytes[..bytes.len() - 2]).is_err());
+
+        bytes[4] = 99;
+        let error = restore(&mut computer, &bytes[..]).unwrap_err();
+        assert_eq!("unsupported snapshot version 99", error.to_string());
+        assert_eq!(0, computer.cycles);
+    }
+}
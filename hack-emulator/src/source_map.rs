@@ -0,0 +1,117 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// the ROM addresses from `start` up to `end` and the line they came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub start: u16,
+    pub end: u16,
+    pub file: String,
+    pub line: usize,
+    pub text: String,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{} {}", self.file, self.line, self.text)
+    }
+}
+
+// Where each instruction of a translated program came from, as written by
+// the VM translator's `--source-map`
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    locations: Vec<Location>,
+}
+
+impl SourceMap {
+    pub fn load<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::parse(&fs::read_to_string(path)?).map_err(|message| {
+            io::Error::new(io::ErrorKind::InvalidData, message)
+        })
+    }
+
+    // One range a line as `START END FILE LINE TEXT`, separated by tabs,
+    // with END the address after the range
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut locations = vec![];
+
+        for (number, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match parse_location(line) {
+                Some(location) => locations.push(location),
+                None => {
+                    return Err(format!(
+                        "line {}: invalid source map entry: {}",
+                        number + 1,
+                        line
+                    ))
+                }
+            }
+        }
+
+        locations.sort_by_key(|location| location.start);
+        Ok(Self { locations })
+    }
+
+    pub fn lookup(&self, pc: u16) -> Option<&Location> {
+        let index = self
+            .locations
+            .partition_point(|location| location.start <= pc);
+        self.locations[..index]
+            .last()
+            .filter(|location| pc < location.end)
+    }
+}
+
+fn parse_location(line: &str) -> Option<Location> {
+    match line.splitn(5, '\t').collect::<Vec<&str>>()[..] {
+        [start, end, file, number, text] => Some(Location {
+            start: start.parse().ok()?,
+            end: end.parse().ok()?,
+            file: file.into(),
+            line: number.parse().ok()?,
+            text: text.into(),
+        })
+        .filter(|location| location.start < location.end),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let map = SourceMap::parse(
+            "4\t7\tMain.vm\t2\tpush constant 1\n\
+             9\t20\tMain.vm\t42\tpush local 1\n",
+        )
+        .unwrap();
+
+        assert_eq!(None, map.lookup(3));
+        assert_eq!(2, map.lookup(6).unwrap().line);
+        assert_eq!(None, map.lookup(7));
+        assert_eq!(
+            "Main.vm:42 push local 1",
+            map.lookup(9).unwrap().to_string()
+        );
+        assert_eq!(42, map.lookup(19).unwrap().line);
+        assert_eq!(None, map.lookup(20));
+    }
+
+    #[test]
+    fn test_parse_rejects_garbage() {
+        assert!(SourceMap::parse("1\t2\tMain.vm\n").is_err());
+        assert!(SourceMap::parse("5\t2\tMain.vm\t1\tadd\n").is_err());
+    }
+}
@@ -0,0 +1,391 @@
+use crate::computer::{disassemble, Step};
+use std::collections::VecDeque;
+use std::io;
+use std::io::prelude::*;
+
+// A trace is the magic bytes and a version, followed by one record per
+// cycle. Each record starts with a flags byte saying which fields follow:
+// the PC (only stored when it isn't the previous PC plus one), the
+// instruction, and the new values of A, D and the written RAM cell. All
+// words are little-endian.
+const MAGIC: &[u8; 4] = b"HKTR";
+const VERSION: u8 = 1;
+
+const HAS_PC: u8 = 0b0001;
+const WRITES_A: u8 = 0b0010;
+const WRITES_D: u8 = 0b0100;
+const WRITES_MEMORY: u8 = 0b1000;
+
+pub struct TraceWriter<W: Write> {
+    writer: W,
+    next_pc: Option<u16>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+
+        Ok(Self {
+            writer,
+            next_pc: None,
+        })
+    }
+
+    pub fn record(&mut self, step: &Step) -> io::Result<()> {
+        let mut flags = 0;
+        if self.next_pc != Some(step.pc) {
+            flags |= HAS_PC;
+        }
+        if step.a.is_some() {
+            flags |= WRITES_A;
+        }
+        if step.d.is_some() {
+            flags |= WRITES_D;
+        }
+        if step.memory.is_some() {
+            flags |= WRITES_MEMORY;
+        }
+
+        let mut record = vec![flags];
+        if flags & HAS_PC != 0 {
+            record.extend(step.pc.to_le_bytes());
+        }
+        record.extend(step.instruction.to_le_bytes());
+        if let Some(a) = step.a {
+            record.extend(a.to_le_bytes());
+        }
+        if let Some(d) = step.d {
+            record.extend(d.to_le_bytes());
+        }
+        if let Some((address, value)) = step.memory {
+            record.extend(address.to_le_bytes());
+            record.extend(value.to_le_bytes());
+        }
+        self.next_pc = Some(step.pc.wrapping_add(1));
+
+        self.writer.write_all(&record)
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+pub struct TraceReader<R: Read> {
+    reader: R,
+    next_pc: u16,
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 5];
+        reader.read_exact(&mut header)?;
+
+        if &header[0..4] != MAGIC {
+            return Err(invalid_data("not a Hack trace"));
+        }
+        if header[4] != VERSION {
+            return Err(invalid_data(format!(
+                "unsupported trace version {}",
+                header[4]
+            )));
+        }
+
+        Ok(Self { reader, next_pc: 0 })
+    }
+
+    fn read_word(&mut self) -> io::Result<u16> {
+        let mut bytes = [0; 2];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn read_step(&mut self, flags: u8) -> io::Result<Step> {
+        let pc = if flags & HAS_PC != 0 {
+            self.read_word()?
+        } else {
+            self.next_pc
+        };
+        let instruction = self.read_word()?;
+        let a = if flags & WRITES_A != 0 {
+            Some(self.read_word()?)
+        } else {
+            None
+        };
+        let d = if flags & WRITES_D != 0 {
+            Some(self.read_word()?)
+        } else {
+            None
+        };
+        let memory = if flags & WRITES_MEMORY != 0 {
+            Some((self.read_word()?, self.read_word()?))
+        } else {
+            None
+        };
+        self.next_pc = pc.wrapping_add(1);
+
+        Ok(Step {
+            pc,
+            instruction,
+            a,
+            d,
+            memory,
+        })
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<Step>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut flags = [0];
+        match self.reader.read(&mut flags) {
+            Ok(0) => None,
+            Ok(_) => Some(self.read_step(flags[0]).map_err(|error| {
+                if error.kind() == io::ErrorKind::UnexpectedEof {
+                    invalid_data("truncated trace record")
+                } else {
+                    error
+                }
+            })),
+            Err(error) => Some(Err(error)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    // every field of every cycle must match
+    Steps,
+    // only the sequence of RAM writes must match, so traces of differently
+    // generated code for the same program can be compared
+    Writes,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Divergence {
+    // number of matching steps (or writes) before the divergence
+    pub index: u64,
+    // the diverging cycle of each trace, or None when that trace ended
+    pub left: Option<(u64, Step)>,
+    pub right: Option<(u64, Step)>,
+    // the last matching cycles of the left trace
+    pub context: Vec<(u64, Step)>,
+}
+
+pub fn first_divergence<L, R>(
+    left: L,
+    right: R,
+    comparison: Comparison,
+    context: usize,
+) -> io::Result<Option<Divergence>>
+where
+    L: Iterator<Item = io::Result<Step>>,
+    R: Iterator<Item = io::Result<Step>>,
+{
+    let keep = |item: &io::Result<(u64, Step)>| match item {
+        Ok((_, step)) => {
+            comparison == Comparison::Steps || step.memory.is_some()
+        }
+        Err(_) => true,
+    };
+    let mut left = left
+        .zip(0..)
+        .map(|(step, cycle)| step.map(|step| (cycle, step)))
+        .filter(keep);
+    let mut right = right
+        .zip(0..)
+        .map(|(step, cycle)| step.map(|step| (cycle, step)))
+        .filter(keep);
+    let mut previous = VecDeque::with_capacity(context);
+    let mut index = 0;
+
+    loop {
+        let left_item = left.next().transpose()?;
+        let right_item = right.next().transpose()?;
+
+        let same = match (&left_item, &right_item) {
+            (None, None) => return Ok(None),
+            (Some((_, left_step)), Some((_, right_step))) => match comparison {
+                Comparison::Steps => left_step == right_step,
+                Comparison::Writes => left_step.memory == right_step.memory,
+            },
+            _ => false,
+        };
+
+        if !same {
+            return Ok(Some(Divergence {
+                index,
+                left: left_item,
+                right: right_item,
+                context: previous.into(),
+            }));
+        }
+
+        if context > 0 {
+            if previous.len() == context {
+                previous.pop_front();
+            }
+            previous.extend(left_item);
+        }
+        index += 1;
+    }
+}
+
+pub fn format_step(cycle: u64, step: &Step) -> String {
+    let mut line = format!(
+        "{:>10} {:>5}  {:<14}",
+        cycle,
+        step.pc,
+        disassemble(step.instruction)
+    );
+    if let Some(a) = step.a {
+        line.push_str(&format!(" A={}", a as i16));
+    }
+    if let Some(d) = step.d {
+        line.push_str(&format!(" D={}", d as i16));
+    }
+    if let Some((address, value)) = step.memory {
+        line.push_str(&format!(" RAM[{}]={}", address, value as i16));
+    }
+    line.trim_end().to_owned()
+}
+
+fn invalid_data<S>(message: S) -> io::Error
+where
+    S: Into<String>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::computer::Computer;
+    use crate::program::Program;
+
+    fn record(source: &str, cycles: usize) -> (Vec<Step>, Vec<u8>) {
+        let mut computer = Computer::new(Program::assemble(source).rom);
+        let mut writer = TraceWriter::new(vec![]).unwrap();
+        let mut steps = vec![];
+
+        for _ in 0..cycles {
+            let step = computer.step();
+            writer.record(&step).unwrap();
+            steps.push(step);
+        }
+
+        (steps, writer.finish().unwrap())
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (steps, bytes) =
+            record("(LOOP)\n@100\nM=M+1\nD=M\n@LOOP\n0;JMP", 12);
+        let read: Vec<Step> = TraceReader::new(&bytes[..])
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+
+        assert_eq!(steps, read);
+    }
+
+    #[test]
+    fn test_sequential_pcs_are_not_stored() {
+        let (_, bytes) = record("D=0\nD=0\nD=0", 3);
+
+        // header, then flags + instruction + D, with the PC only once
+        assert_eq!(5 + 2 + 3 * 5, bytes.len());
+    }
+
+    #[test]
+    fn test_rejects_truncated_trace() {
+        let (_, bytes) = record("@100\nM=1", 2);
+        let mut reader = TraceReader::new(&bytes[..bytes.len() - 1]).unwrap();
+
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_err());
+    }
+
+    fn read(bytes: &[u8]) -> TraceReader<&[u8]> {
+        TraceReader::new(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_identical_traces_do_not_diverge() {
+        let (_, bytes) = record("@100\nM=1\nD=M", 3);
+
+        assert_eq!(
+            None,
+            first_divergence(read(&bytes), read(&bytes), Comparison::Steps, 2)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_finds_first_diverging_cycle() {
+        let (steps, left) = record("@100\nM=1\nD=M\n@101\nM=D", 5);
+        let (_, right) = record("@100\nM=1\nD=M\n@102\nM=D", 5);
+        let divergence =
+            first_divergence(read(&left), read(&right), Comparison::Steps, 2)
+                .unwrap()
+                .unwrap();
+
+        assert_eq!(3, divergence.index);
+        assert_eq!(Some((3, steps[3])), divergence.left);
+        assert_eq!(Some(102), divergence.right.unwrap().1.a);
+        assert_eq!(vec![(1, steps[1]), (2, steps[2])], divergence.context);
+    }
+
+    #[test]
+    fn test_shorter_trace_diverges_at_its_end() {
+        let (_, left) = record("@100\nM=1", 2);
+        let (_, right) = record("@100\nM=1\nM=0", 3);
+        let divergence =
+            first_divergence(read(&left), read(&right), Comparison::Steps, 0)
+                .unwrap()
+                .unwrap();
+
+        assert_eq!(2, divergence.index);
+        assert_eq!(None, divergence.left);
+        assert!(divergence.right.is_some());
+    }
+
+    #[test]
+    fn test_writes_comparison_ignores_how_values_are_computed() {
+        let (_, left) = record("@7\nD=A\n@100\nM=D", 4);
+        let (_, right) =
+            record("@100\nM=1\nM=M+1\nD=1\n@5\nD=D+A\n@100\nM=D", 8);
+        let divergence =
+            first_divergence(read(&left), read(&right), Comparison::Writes, 0)
+                .unwrap()
+                .unwrap();
+
+        assert_eq!(0, divergence.index);
+        assert_eq!(Some((100, 7)), divergence.left.unwrap().1.memory);
+        assert_eq!(Some((100, 1)), divergence.right.unwrap().1.memory);
+
+        let (_, right) = record("D=1\n@6\nD=D+A\n@100\nM=D", 5);
+        assert_eq!(
+            None,
+            first_divergence(read(&left), read(&right), Comparison::Writes, 0)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_format_step() {
+        let (steps, _) = record("@100\nM=-1", 2);
+
+        assert_eq!(
+            "         0     0  @100           A=100",
+            format_step(0, &steps[0])
+        );
+        assert_eq!(
+            "         1     1  M=-1           RAM[100]=-1",
+            format_step(1, &steps[1])
+        );
+    }
+}
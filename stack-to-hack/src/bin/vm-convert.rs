@@ -0,0 +1,100 @@
+use stack_to_hack::bytecode::{self, TextFile};
+use stack_to_hack::sources::{is_bytecode, output_path, source_paths};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+const USAGE: &str = "USAGE: ./vm-convert file.vm|file.vmb|directory \
+                     [-o out.vmb|directory]";
+
+struct Options {
+    path: String,
+    output: Option<String>,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut args = env::args().skip(1);
+    let mut options = Options {
+        path: String::new(),
+        output: None,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => {
+                options.output =
+                    Some(args.next().ok_or("-o needs a value".to_owned())?)
+            }
+            _ if options.path.is_empty() && !arg.starts_with('-') => {
+                options.path = arg
+            }
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+
+    if options.path.is_empty() {
+        Err(USAGE.into())
+    } else {
+        Ok(options)
+    }
+}
+
+// Encodes .vm files into one bytecode file, or writes the .vm files in a
+// bytecode file back out, by default next to it
+fn run(options: Options) -> Result<(), Vec<String>> {
+    let path = Path::new(&options.path);
+    let error = |path: &Path, error: std::io::Error| {
+        vec![format!("{}: {}", path.display(), error)]
+    };
+
+    if is_bytecode(path) {
+        let bytes = fs::read(path).map_err(|e| error(path, e))?;
+        let files = bytecode::decode(&bytes).map_err(|e| error(path, e))?;
+        let directory = match &options.output {
+            Some(directory) => PathBuf::from(directory),
+            None => path.parent().unwrap_or(Path::new(".")).to_path_buf(),
+        };
+        fs::create_dir_all(&directory).map_err(|e| error(&directory, e))?;
+        for file in files {
+            let path = directory.join(&file.name);
+            fs::write(&path, file.contents).map_err(|e| error(&path, e))?;
+        }
+    } else {
+        let mut files = vec![];
+        for path in source_paths(path).map_err(|error| vec![error])? {
+            files.push(TextFile {
+                name: path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                contents: fs::read_to_string(&path)
+                    .map_err(|e| error(&path, e))?,
+            });
+        }
+        let bytes = bytecode::encode(&files)?;
+        let output = match &options.output {
+            Some(file) => PathBuf::from(file),
+            None => output_path(path, "vmb"),
+        };
+        fs::write(&output, bytes).map_err(|e| error(&output, e))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let options = match parse_options() {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(2);
+        }
+    };
+
+    if let Err(errors) = run(options) {
+        for error in errors {
+            eprintln!("ERROR: {}", error);
+        }
+        process::exit(1);
+    }
+}
@@ -0,0 +1,144 @@
+use stack_to_hack::emulator::{VirtualMachine, RAM_SIZE};
+use stack_to_hack::sources::{parse_sources, source_paths};
+use stack_to_hack::translator::{check_extended, defines_function};
+use std::env;
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "USAGE: ./vm-emulate file.vm|file.vmb|directory \
+                     [--steps N] [--set NAME=VALUE]... [--no-bootstrap] \
+                     [--dump FROM-TO] [--extended]";
+
+struct Options {
+    path: String,
+    steps: u64,
+    settings: Vec<(String, u16)>,
+    bootstrap: bool,
+    dump: Option<(u16, u16)>,
+    extended: bool,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut args = env::args().skip(1);
+    let mut options = Options {
+        path: String::new(),
+        steps: 1_000_000,
+        settings: vec![],
+        bootstrap: true,
+        dump: None,
+        extended: false,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--steps" => {
+                options.steps = value()?
+                    .parse()
+                    .map_err(|_| "invalid step count".to_owned())?
+            }
+            "--set" => {
+                let setting = value()?;
+                let (name, number) = setting
+                    .split_once('=')
+                    .ok_or(format!("invalid setting {}", setting))?;
+                options.settings.push((name.into(), parse_word(number)?));
+            }
+            "--no-bootstrap" => options.bootstrap = false,
+            "--extended" => options.extended = true,
+            "--dump" => {
+                let range = value()?;
+                let (from, to) = range
+                    .split_once('-')
+                    .ok_or(format!("invalid range {}", range))?;
+                let (from, to) = (parse_word(from)?, parse_word(to)?);
+                if to as usize >= RAM_SIZE {
+                    return Err(format!("address {} is outside of RAM", to));
+                }
+                options.dump = Some((from, to));
+            }
+            _ if options.path.is_empty() && !arg.starts_with('-') => {
+                options.path = arg
+            }
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+
+    if options.path.is_empty() {
+        Err(USAGE.into())
+    } else {
+        Ok(options)
+    }
+}
+
+fn parse_word(string: &str) -> Result<u16, String> {
+    match string.parse::<i16>() {
+        Ok(value) => Ok(value as u16),
+        Err(_) => string
+            .parse::<u16>()
+            .map_err(|_| format!("invalid number {}", string)),
+    }
+}
+
+// Runs the program, which by default starts with SP at 256 and a call to
+// Sys.init if it has a Sys.init or a Main.main for the built-in one to
+// call, and prints where it stopped
+fn run(options: Options) -> Result<(), Vec<String>> {
+    let paths =
+        source_paths(Path::new(&options.path)).map_err(|error| vec![error])?;
+    let sources = parse_sources(&paths)?;
+    let errors = check_extended(&sources);
+    if !options.extended && !errors.is_empty() {
+        return Err(errors.iter().map(|error| error.to_string()).collect());
+    }
+    let mut machine = VirtualMachine::new(&sources)?;
+    let entry = ["Sys.init", "Main.main"]
+        .iter()
+        .any(|name| defines_function(&sources, name));
+    if options.bootstrap && entry {
+        machine
+            .bootstrap()
+            .map_err(|error| vec![error.to_string()])?;
+    }
+    for (name, value) in &options.settings {
+        machine.set(name, *value).map_err(|error| vec![error])?;
+    }
+
+    let steps = machine
+        .run(options.steps)
+        .map_err(|error| vec![error.to_string()])?;
+    if machine.halted() {
+        println!("halted after {} steps", steps);
+    } else {
+        println!("stopped after {} steps", steps);
+    }
+    if let Some(location) = machine.location() {
+        println!("at {}", location);
+    }
+    if let Some(code) = machine.error_code() {
+        println!("Sys.error called with {}", code);
+    }
+    if let Some((from, to)) = options.dump {
+        for address in from..=to {
+            println!("RAM[{}]={}", address, machine.peek(address) as i16);
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let options = match parse_options() {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(2);
+        }
+    };
+
+    if let Err(errors) = run(options) {
+        for error in errors {
+            eprintln!("ERROR: {}", error);
+        }
+        process::exit(1);
+    }
+}
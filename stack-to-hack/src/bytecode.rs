@@ -0,0 +1,554 @@
+use crate::translator::{parse_file, Instruction, Segment, SourceLine};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Component, Path};
+
+// A bytecode file is the magic bytes and a format version, a table of the
+// strings the program uses, and then each .vm file: its name, a flags byte
+// for how its lines end, and an opcode per line followed by the
+// instruction's operands. Names are indexes into the string table, as are
+// the lines that aren't written the way `Instruction::to_vm` writes them,
+// such as comments, so the text can be put back together exactly. Segment
+// indexes and arities are little-endian u16s, and counts, lengths and
+// string indexes are LEB128 so the common small ones take a byte.
+const MAGIC: &[u8; 4] = b"HVMB";
+const VERSION: u8 = 1;
+
+const CRLF: u8 = 0b01;
+const FINAL_NEWLINE: u8 = 0b10;
+
+const NO_INSTRUCTION: u8 = 0;
+const PUSH: u8 = 1;
+const POP: u8 = 2;
+const LABEL: u8 = 3;
+const GOTO: u8 = 4;
+const IF_GOTO: u8 = 5;
+const FUNCTION: u8 = 6;
+const CALL: u8 = 7;
+// the instructions without operands follow, in this order
+const OPERATORS: u8 = 8;
+const OPERATOR_INSTRUCTIONS: [Instruction; 16] = [
+    Instruction::Add,
+    Instruction::Subtract,
+    Instruction::Negate,
+    Instruction::Equal,
+    Instruction::GreaterThan,
+    Instruction::LessThan,
+    Instruction::And,
+    Instruction::Or,
+    Instruction::Not,
+    Instruction::Multiply,
+    Instruction::Divide,
+    Instruction::Modulo,
+    Instruction::ShiftLeft,
+    Instruction::ShiftRight,
+    Instruction::Xor,
+    Instruction::Return,
+];
+// set on the opcode of a line whose text follows the operands
+const HAS_TEXT: u8 = 0x80;
+
+const SEGMENTS: [Segment; 8] = [
+    Segment::Argument,
+    Segment::Local,
+    Segment::Static,
+    Segment::Constant,
+    Segment::This,
+    Segment::That,
+    Segment::Pointer,
+    Segment::Temp,
+];
+
+// a .vm file's name, without the directory, and its contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextFile {
+    pub name: String,
+    pub contents: String,
+}
+
+// Parses the files and encodes them, returning the parse errors in all of
+// them if any line is invalid
+pub fn encode(files: &[TextFile]) -> Result<Vec<u8>, Vec<String>> {
+    let mut strings = Strings::default();
+    let mut body = vec![];
+    let mut errors = vec![];
+
+    for file in files {
+        let lines = match parse_file(&file.name, &file.contents) {
+            Ok(lines) => lines,
+            Err(parse_errors) => {
+                errors
+                    .extend(parse_errors.iter().map(|error| error.to_string()));
+                continue;
+            }
+        };
+        let (texts, flags) = split_lines(&file.contents);
+        write_varint(&mut body, strings.index(&file.name));
+        body.push(flags);
+        write_varint(&mut body, texts.len());
+        for (text, line) in texts.iter().zip(&lines) {
+            encode_line(&mut body, &mut strings, text, &line.instruction);
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+    write_varint(&mut bytes, strings.strings.len());
+    for string in &strings.strings {
+        write_varint(&mut bytes, string.len());
+        bytes.extend(string.as_bytes());
+    }
+    bytes.extend(body);
+    Ok(bytes)
+}
+
+// the files exactly as they were encoded
+pub fn decode(bytes: &[u8]) -> io::Result<Vec<TextFile>> {
+    Ok(read_files(bytes)?
+        .into_iter()
+        .map(|file| {
+            let ending = if file.crlf { "\r\n" } else { "\n" };
+            let mut contents = file
+                .lines
+                .iter()
+                .map(|(text, _)| text.as_str())
+                .collect::<Vec<&str>>()
+                .join(ending);
+            if file.final_newline {
+                contents += ending;
+            }
+            TextFile {
+                name: file.name,
+                contents,
+            }
+        })
+        .collect())
+}
+
+// Each file's name and its lines as `parse_file` would give them, without
+// parsing any text
+pub fn decode_lines(
+    bytes: &[u8],
+) -> io::Result<Vec<(String, Vec<SourceLine>)>> {
+    Ok(read_files(bytes)?
+        .into_iter()
+        .map(|file| {
+            let lines = file
+                .lines
+                .into_iter()
+                .enumerate()
+                .map(|(index, (text, instruction))| SourceLine {
+                    number: index + 1,
+                    text: text.trim().into(),
+                    instruction,
+                })
+                .collect();
+            (file.name, lines)
+        })
+        .collect())
+}
+
+// The lines as they're written, and the flags for how they end. A file's
+// lines end in CRLF if every one of them that ends does; otherwise the
+// CRs are kept as part of the text.
+fn split_lines(contents: &str) -> (Vec<&str>, u8) {
+    let mut flags = 0;
+    let mut lines: Vec<&str> = contents.split('\n').collect();
+    if contents.ends_with('\n') {
+        flags |= FINAL_NEWLINE;
+    }
+    if contents.is_empty() || contents.ends_with('\n') {
+        lines.pop();
+    }
+
+    let ended = if flags & FINAL_NEWLINE != 0 {
+        lines.len()
+    } else {
+        lines.len().saturating_sub(1)
+    };
+    if ended > 0 && lines[..ended].iter().all(|line| line.ends_with('\r')) {
+        flags |= CRLF;
+        for line in &mut lines[..ended] {
+            *line = &line[..line.len() - 1];
+        }
+    }
+    (lines, flags)
+}
+
+fn encode_line(
+    bytes: &mut Vec<u8>,
+    strings: &mut Strings,
+    text: &str,
+    instruction: &Option<Instruction>,
+) {
+    use Instruction::*;
+
+    let mut opcode = match instruction {
+        None => NO_INSTRUCTION,
+        Some(Push(..)) => PUSH,
+        Some(Pop(..)) => POP,
+        Some(Label(_)) => LABEL,
+        Some(Goto(_)) => GOTO,
+        Some(IfGoto(_)) => IF_GOTO,
+        Some(Function(..)) => FUNCTION,
+        Some(Call(..)) => CALL,
+        Some(instruction) => {
+            OPERATORS
+                + OPERATOR_INSTRUCTIONS
+                    .iter()
+                    .position(|operator| operator == instruction)
+                    .expect("only .vm instructions can be encoded")
+                    as u8
+        }
+    };
+    if instruction.as_ref().and_then(Instruction::to_vm).as_deref()
+        != Some(text)
+    {
+        opcode |= HAS_TEXT;
+    }
+    bytes.push(opcode);
+
+    match instruction {
+        Some(Push(segment, index) | Pop(segment, index)) => {
+            let segment = SEGMENTS
+                .iter()
+                .position(|other| other == segment)
+                .expect("only .vm segments can be encoded");
+            bytes.push(segment as u8);
+            bytes.extend(index.to_le_bytes());
+        }
+        Some(Label(label) | Goto(label) | IfGoto(label)) => {
+            write_varint(bytes, strings.index(label))
+        }
+        Some(Function(name, count) | Call(name, count)) => {
+            write_varint(bytes, strings.index(name));
+            bytes.extend(count.to_le_bytes());
+        }
+        _ => {}
+    }
+    if opcode & HAS_TEXT != 0 {
+        write_varint(bytes, strings.index(text));
+    }
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+// the string table, with each string stored once
+#[derive(Default)]
+struct Strings {
+    strings: Vec<String>,
+    indexes: HashMap<String, usize>,
+}
+
+impl Strings {
+    fn index(&mut self, string: &str) -> usize {
+        if let Some(&index) = self.indexes.get(string) {
+            return index;
+        }
+        self.strings.push(string.into());
+        self.indexes.insert(string.into(), self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+}
+
+// a file as it's stored, with each line's exact text
+struct File {
+    name: String,
+    crlf: bool,
+    final_newline: bool,
+    lines: Vec<(String, Option<Instruction>)>,
+}
+
+fn read_files(bytes: &[u8]) -> io::Result<Vec<File>> {
+    let mut reader = BytecodeReader {
+        bytes,
+        position: 0,
+        strings: vec![],
+    };
+
+    if !bytes.starts_with(MAGIC) {
+        return Err(invalid_data("not VM bytecode"));
+    }
+    reader.position = MAGIC.len();
+    let version = reader.read_u8()?;
+    if version != VERSION {
+        return Err(invalid_data(format!(
+            "unsupported bytecode version {}",
+            version
+        )));
+    }
+    for _ in 0..reader.read_varint()? {
+        let length = reader.read_varint()?;
+        let string = String::from_utf8(reader.read_bytes(length)?.to_vec())
+            .map_err(|_| invalid_data("string table isn't valid UTF-8"))?;
+        reader.strings.push(string);
+    }
+
+    let mut files = vec![];
+    while reader.position < bytes.len() {
+        let name = reader.read_string()?;
+        // the files are written out by name, so it mustn't lead anywhere
+        // else
+        if !is_file_name(&name) {
+            return Err(invalid_data(format!("invalid file name {}", name)));
+        }
+        let flags = reader.read_u8()?;
+        let mut lines = vec![];
+        for _ in 0..reader.read_varint()? {
+            lines.push(reader.read_line()?);
+        }
+        files.push(File {
+            name,
+            crlf: flags & CRLF != 0,
+            final_newline: flags & FINAL_NEWLINE != 0,
+            lines,
+        });
+    }
+    Ok(files)
+}
+
+// a single component of a path, with no directories, root or prefix
+fn is_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    !name.contains(['/', '\\'])
+        && matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none()
+}
+
+struct BytecodeReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    strings: Vec<String>,
+}
+
+impl<'a> BytecodeReader<'a> {
+    fn read_bytes(&mut self, count: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position.saturating_add(count))
+            .ok_or_else(|| invalid_data("truncated bytecode"))?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> io::Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> io::Result<u16> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_varint(&mut self) -> io::Result<usize> {
+        let mut value = 0;
+        for shift in (0..usize::BITS).step_by(7) {
+            let byte = self.read_u8()?;
+            value |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid_data("number is too long"))
+    }
+
+    fn read_string(&mut self) -> io::Result<String> {
+        let index = self.read_varint()?;
+        self.strings.get(index).cloned().ok_or_else(|| {
+            invalid_data(format!("no string {} in the table", index))
+        })
+    }
+
+    fn read_line(&mut self) -> io::Result<(String, Option<Instruction>)> {
+        use Instruction::*;
+
+        let opcode = self.read_u8()?;
+        let instruction = match opcode & !HAS_TEXT {
+            NO_INSTRUCTION => None,
+            PUSH => Some(Push(self.read_segment()?, self.read_u16()?)),
+            POP => Some(Pop(self.read_segment()?, self.read_u16()?)),
+            LABEL => Some(Label(self.read_string()?)),
+            GOTO => Some(Goto(self.read_string()?)),
+            IF_GOTO => Some(IfGoto(self.read_string()?)),
+            FUNCTION => Some(Function(self.read_string()?, self.read_u16()?)),
+            CALL => Some(Call(self.read_string()?, self.read_u16()?)),
+            code => Some(
+                OPERATOR_INSTRUCTIONS
+                    .get(code.wrapping_sub(OPERATORS) as usize)
+                    .ok_or_else(|| {
+                        invalid_data(format!("unknown opcode {}", opcode))
+                    })?
+                    .clone(),
+            ),
+        };
+
+        let text = if opcode & HAS_TEXT != 0 {
+            self.read_string()?
+        } else {
+            instruction
+                .as_ref()
+                .and_then(Instruction::to_vm)
+                .ok_or_else(|| invalid_data("a line without text"))?
+        };
+        Ok((text, instruction))
+    }
+
+    fn read_segment(&mut self) -> io::Result<Segment> {
+        let segment = self.read_u8()?;
+        SEGMENTS
+            .get(segment as usize)
+            .cloned()
+            .ok_or_else(|| invalid_data(format!("unknown segment {}", segment)))
+    }
+}
+
+fn invalid_data<S>(message: S) -> io::Error
+where
+    S: Into<String>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::{parse_sources, source_paths};
+    use std::fs;
+    use std::path::Path;
+
+    fn file(name: &str, contents: &str) -> TextFile {
+        TextFile {
+            name: name.into(),
+            contents: contents.into(),
+        }
+    }
+
+    // the course's .vm files, some of which end their lines in CRLF
+    fn course_directories() -> Vec<String> {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+        let mut directories = vec![root.join("tools/OS")];
+        let subdirectories = |path: &Path| {
+            fs::read_dir(path)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.is_dir())
+                .collect::<Vec<_>>()
+        };
+        for project in ["07", "08"] {
+            for group in subdirectories(&root.join("projects").join(project)) {
+                directories.extend(subdirectories(&group));
+            }
+        }
+        directories.extend(subdirectories(&root.join("projects/11")));
+        directories
+            .into_iter()
+            .filter(|directory| source_paths(directory).is_ok())
+            .map(|directory| directory.display().to_string())
+            .collect()
+    }
+
+    fn read_directory(directory: &str) -> Vec<TextFile> {
+        source_paths(Path::new(directory))
+            .unwrap()
+            .iter()
+            .map(|path| TextFile {
+                name: path.file_name().unwrap().to_string_lossy().into(),
+                contents: fs::read_to_string(path).unwrap(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_the_text_comes_back_exactly() {
+        let files = vec![
+            file("Main.vm", "push constant 1\r\n// one\r\n\r\nadd\r\n"),
+            file("Odd.vm", "  push   local 2  // x\n\tlabel L\r\ngoto L"),
+            file("Empty.vm", ""),
+            file("Newline.vm", "\n"),
+        ];
+        assert_eq!(files, decode(&encode(&files).unwrap()).unwrap());
+
+        for directory in course_directories() {
+            let files = read_directory(&directory);
+            let bytes = encode(&files).unwrap();
+            assert_eq!(files, decode(&bytes).unwrap(), "{}", directory);
+        }
+    }
+
+    #[test]
+    fn test_lines_are_as_parsed() {
+        for directory in course_directories() {
+            let paths = source_paths(Path::new(&directory)).unwrap();
+            let sources = parse_sources(&paths).unwrap();
+            let bytes = encode(&read_directory(&directory)).unwrap();
+
+            let decoded = decode_lines(&bytes).unwrap();
+            assert_eq!(sources.len(), decoded.len());
+            for (source, (name, lines)) in sources.iter().zip(decoded) {
+                assert!(source.file.ends_with(&name));
+                assert_eq!(source.lines, lines, "{}", source.file);
+            }
+        }
+    }
+
+    #[test]
+    fn test_names_are_stored_once() {
+        let directory = concat!(env!("CARGO_MANIFEST_DIR"), "/../tools/OS");
+        let files = read_directory(directory);
+        let bytes = encode(&files).unwrap();
+        let text: usize = files.iter().map(|file| file.contents.len()).sum();
+
+        assert!(bytes.len() * 3 < text, "{} of {}", bytes.len(), text);
+        let calls = bytes
+            .windows(b"Math.multiply".len())
+            .filter(|window| window == b"Math.multiply")
+            .count();
+        assert_eq!(1, calls);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let files = [
+            file("A.vm", "push constant 1\nfoo"),
+            file("B.vm", "pop nowhere 2"),
+        ];
+
+        assert_eq!(
+            vec![
+                "A.vm:2: unknown instruction: foo",
+                "B.vm:1: unknown segment: nowhere",
+            ],
+            encode(&files).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_rejects_other_files_and_corruption() {
+        let mut bytes =
+            encode(&[file("Main.vm", "push constant 1\nadd\n")]).unwrap();
+
+        let error = |bytes: &[u8]| decode(bytes).unwrap_err().to_string();
+        assert_eq!("not VM bytecode", error(b"push constant 1"));
+        assert_eq!("not VM bytecode", error(b"x"));
+        assert_eq!("truncated bytecode", error(&bytes[..bytes.len() - 1]));
+        let last = bytes.len() - 1;
+        bytes[last] = 0x7f;
+        assert_eq!("unknown opcode 127", error(&bytes));
+        bytes[4] = 2;
+        assert_eq!("unsupported bytecode version 2", error(&bytes));
+
+        for name in ["../Evil.vm", "/tmp/Evil.vm", "a\\Evil.vm", "..", ""] {
+            let bytes = encode(&[file(name, "push constant 1")]).unwrap();
+            assert_eq!(format!("invalid file name {}", name), error(&bytes));
+        }
+    }
+}
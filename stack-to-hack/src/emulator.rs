@@ -0,0 +1,891 @@
+mod font;
+mod os;
+
+use crate::translator::{
+    parse_segment, validate_with_builtins, Instruction, Segment, Source,
+};
+use os::{Builtin, Os, BUILTINS};
+use std::collections::HashMap;
+use std::fmt;
+
+pub const RAM_SIZE: usize = 32768;
+pub const STACK: u16 = 256;
+pub const SCREEN: u16 = 16384;
+pub const KEYBOARD: u16 = 24576;
+
+// statics are allocated from here in the order they're first used, as the
+// assembler does for the translated program
+const STATICS: u16 = 16;
+const ENTRY: &str = "Sys.init";
+// how long a built-in function waits for a function in a .vm file to return
+const NESTED_STEPS: u64 = 10_000_000;
+
+const SP: u16 = 0;
+const LCL: u16 = 1;
+const ARG: u16 = 2;
+const THIS: u16 = 3;
+const THAT: u16 = 4;
+const TEMP: u16 = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.file.as_str() {
+            "" => write!(f, "{}", self.message),
+            file => write!(f, "{}:{}: {}", file, self.line, self.message),
+        }
+    }
+}
+
+// Why a built-in function didn't return a value: the program halted, the
+// function is waiting for a key and has to be called again on the next
+// step, it went on to a function in a .vm file, or something went wrong
+enum Stop {
+    Halt,
+    Wait,
+    Jump(usize),
+    Error(RuntimeError),
+}
+
+impl From<RuntimeError> for Stop {
+    fn from(error: RuntimeError) -> Self {
+        Stop::Error(error)
+    }
+}
+
+// An instruction and where it came from. `target` is the index a jump or a
+// call goes to, or the address of a static, and `builtin` the function a
+// call runs instead when it isn't in the program.
+struct Operation {
+    instruction: Instruction,
+    file: usize,
+    line: usize,
+    text: String,
+    target: usize,
+    builtin: Option<&'static Builtin>,
+}
+
+// Runs .vm programs directly, one instruction a step with labels not
+// counting, as in the course's VM emulator, and with the segments in
+// RAM where the translated program would have them: the pointers in RAM[0]
+// to RAM[4], temp from RAM[5], statics from RAM[16], the stack from 256 and
+// the screen and keyboard at the top. Return addresses on the stack are
+// instruction indexes.
+//
+// The OS is built in: calls to functions of the course's OS classes that
+// the program doesn't define itself run in Rust, and go through the
+// program's own versions whenever they use another OS function, so a
+// `Memory.vm` of one's own serves all the built-in allocations. The one
+// exception is reading keys: the built-in readLine and readInt always use
+// the built-in readChar and readLine, which wait for keys between steps
+// where a .vm version would have to run to its return.
+pub struct VirtualMachine {
+    ram: Vec<u16>,
+    code: Vec<Operation>,
+    files: Vec<String>,
+    functions: HashMap<String, usize>,
+    os: Os,
+    halted: bool,
+    waiting: bool,
+    pub pc: usize,
+    pub steps: u64,
+}
+
+impl VirtualMachine {
+    // Loads a program that starts at Sys.init if it has one, as the course's
+    // VM emulator does, or at its first instruction. Nothing is set up: the
+    // pointers are zero until set, or until `bootstrap` is called.
+    pub fn new(sources: &[Source]) -> Result<Self, Vec<String>> {
+        let builtins: Vec<&str> =
+            BUILTINS.iter().map(|builtin| builtin.name).collect();
+        let errors = validate_with_builtins(sources, &builtins);
+        if !errors.is_empty() {
+            return Err(errors.iter().map(|error| error.to_string()).collect());
+        }
+
+        let mut code = vec![];
+        let mut functions = HashMap::new();
+        let mut labels = HashMap::new();
+        for (file, source) in sources.iter().enumerate() {
+            let mut function = String::new();
+            for line in &source.lines {
+                let Some(instruction) = &line.instruction else {
+                    continue;
+                };
+                match instruction {
+                    Instruction::Function(name, _) => {
+                        function = name.clone();
+                        functions.insert(name.clone(), code.len());
+                    }
+                    Instruction::Label(label) => {
+                        labels.insert(
+                            (file, function.clone(), label.clone()),
+                            code.len(),
+                        );
+                        // a jump goes to the instruction after the label
+                        continue;
+                    }
+                    _ => {}
+                }
+                code.push((file, function.clone(), line));
+            }
+        }
+
+        let mut statics = HashMap::new();
+        let code = code
+            .into_iter()
+            .map(|(file, function, line)| {
+                let instruction = line.instruction.clone().unwrap();
+                let mut builtin = None;
+                let target = match &instruction {
+                    Instruction::Goto(label) | Instruction::IfGoto(label) => {
+                        labels[&(file, function, label.clone())]
+                    }
+                    Instruction::Call(name, _) => match functions.get(name) {
+                        Some(&function) => function,
+                        None => {
+                            builtin = os::builtin(name);
+                            0
+                        }
+                    },
+                    Instruction::Push(Segment::Static, index)
+                    | Instruction::Pop(Segment::Static, index) => {
+                        let next = STATICS as usize + statics.len();
+                        *statics.entry((file, *index)).or_insert(next)
+                    }
+                    _ => 0,
+                };
+                Operation {
+                    instruction,
+                    file,
+                    line: line.number,
+                    text: line.text.split("//").next().unwrap().trim().into(),
+                    target,
+                    builtin,
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            ram: vec![0; RAM_SIZE],
+            code,
+            files: sources.iter().map(|source| source.file.clone()).collect(),
+            pc: functions.get(ENTRY).copied().unwrap_or(0),
+            functions,
+            os: Os::default(),
+            halted: false,
+            waiting: false,
+            steps: 0,
+        })
+    }
+
+    // Sets SP to the stack and calls Sys.init like the translator's
+    // bootstrap, stopping the program if it ever returns. The built-in
+    // Sys.init sets up the OS and calls Main.main.
+    pub fn bootstrap(&mut self) -> Result<(), RuntimeError> {
+        let end = self.code.len();
+        self.poke(SP, STACK);
+        match self.functions.get(ENTRY) {
+            Some(&entry) => self.pc = self.call(end, entry, 0)?,
+            None => {
+                self.pc = self.call(end, end, 0)?;
+                match os::builtin(ENTRY).unwrap().run(self, &[]) {
+                    Err(Stop::Jump(pc)) => self.pc = pc,
+                    Err(Stop::Error(error)) => return Err(error),
+                    _ => self.halted = true,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn peek(&self, address: u16) -> u16 {
+        self.ram[address as usize]
+    }
+
+    pub fn poke(&mut self, address: u16, value: u16) {
+        self.ram[address as usize] = value;
+    }
+
+    // the values on the stack, from its base up to SP or the end of RAM
+    pub fn stack(&self) -> &[u16] {
+        let sp = (self.peek(SP) as usize).clamp(STACK as usize, RAM_SIZE);
+        &self.ram[STACK as usize..sp]
+    }
+
+    pub fn screen(&self) -> &[u16] {
+        &self.ram[SCREEN as usize..KEYBOARD as usize]
+    }
+
+    // the value at an index of a segment named as in .vm files, such as
+    // `local` or `temp`, if the segment and the index are in RAM
+    pub fn segment(&self, segment: &str, index: u16) -> Option<u16> {
+        let address = self.address(&parse_segment(segment).ok()?, index)?;
+        self.ram.get(address as usize).copied()
+    }
+
+    // A pointer or a value named as in the course's test scripts, such as
+    // `sp`, `local`, `argument[2]` or `RAM[300]`
+    pub fn get(&self, name: &str) -> Option<u16> {
+        Some(self.peek(self.named_address(name)?))
+    }
+
+    pub fn set(&mut self, name: &str, value: u16) -> Result<(), String> {
+        let address = self
+            .named_address(name)
+            .ok_or(format!("unknown variable {}", name))?;
+        self.poke(address, value);
+        Ok(())
+    }
+
+    // the file, line and text of the next instruction
+    pub fn location(&self) -> Option<String> {
+        let operation = self.code.get(self.pc)?;
+        Some(format!(
+            "{}:{} {}",
+            self.files[operation.file], operation.line, operation.text
+        ))
+    }
+
+    // stopped by Sys.halt, past the end of the program, or at a `goto` to
+    // itself
+    pub fn halted(&self) -> bool {
+        if self.halted {
+            return true;
+        }
+        match self.code.get(self.pc) {
+            Some(operation) => {
+                matches!(operation.instruction, Instruction::Goto(_))
+                    && operation.target == self.pc
+            }
+            None => true,
+        }
+    }
+
+    // the code Sys.error was called with, if it was
+    pub fn error_code(&self) -> Option<i16> {
+        self.os.error_code
+    }
+
+    // Runs until the program halts or the steps run out, returning how
+    // many were run, counting those of the functions built-in functions
+    // call
+    pub fn run(&mut self, steps: u64) -> Result<u64, RuntimeError> {
+        let start = self.steps;
+        while self.steps - start < steps && !self.halted() {
+            self.step()?;
+        }
+        Ok(self.steps - start)
+    }
+
+    pub fn step(&mut self) -> Result<(), RuntimeError> {
+        if self.halted() {
+            return Ok(());
+        }
+        match self.execute() {
+            Err(Stop::Halt) => {
+                self.halted = true;
+                Ok(())
+            }
+            Err(Stop::Error(error)) => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    fn execute(&mut self) -> Result<(), Stop> {
+        use Instruction::*;
+
+        let operation = &self.code[self.pc];
+        let target = operation.target;
+        let builtin = operation.builtin;
+        let mut next = self.pc + 1;
+        self.waiting = false;
+        match operation.instruction.clone() {
+            Push(Segment::Constant, value) => self.push(value)?,
+            Push(segment, index) => {
+                let address = self.segment_address(&segment, index, target)?;
+                self.push(self.peek(address))?
+            }
+            Pop(segment, index) => {
+                let address = self.segment_address(&segment, index, target)?;
+                let value = self.pop()?;
+                self.poke(address, value)
+            }
+            Add => self.binary(|x, y| x.wrapping_add(y))?,
+            Subtract => self.binary(|x, y| x.wrapping_sub(y))?,
+            And => self.binary(|x, y| x & y)?,
+            Or => self.binary(|x, y| x | y)?,
+            Equal => self.binary(|x, y| truth(x == y))?,
+            GreaterThan => self.binary(|x, y| truth(x as i16 > y as i16))?,
+            LessThan => self.binary(|x, y| truth((x as i16) < y as i16))?,
+            Negate => self.unary(|x| x.wrapping_neg())?,
+            Not => self.unary(|x| !x)?,
+            instruction @ (Multiply | Divide | Modulo | ShiftLeft
+            | ShiftRight | Xor) => {
+                self.binary(|x, y| instruction.extended_result(x, y).unwrap())?
+            }
+            Goto(_) => next = target,
+            IfGoto(_) => {
+                if self.pop()? != 0 {
+                    next = target
+                }
+            }
+            Function(_, locals) => {
+                for _ in 0..locals {
+                    self.push(0)?;
+                }
+            }
+            Call(_, arguments) => match builtin {
+                None => next = self.call(next, target, arguments)?,
+                Some(builtin) => {
+                    let sp = self.peek(SP);
+                    let start = sp.wrapping_sub(arguments);
+                    let arguments: Vec<u16> = (start..sp)
+                        .map(|address| self.load(address))
+                        .collect::<Result<_, _>>()?;
+                    match builtin.run(self, &arguments) {
+                        Ok(value) => {
+                            self.poke(SP, start);
+                            self.push(value)?;
+                        }
+                        Err(Stop::Jump(pc)) => next = pc,
+                        Err(Stop::Wait) => {
+                            self.waiting = true;
+                            next = self.pc;
+                        }
+                        Err(stop) => return Err(stop),
+                    }
+                }
+            },
+            Return => next = self.ret()?,
+            instruction => {
+                let message = format!("can't run {:?}", instruction);
+                return Err(Stop::Error(self.error(message)));
+            }
+        }
+        self.pc = next;
+        self.steps += 1;
+        Ok(())
+    }
+
+    // Calls an OS function for a built-in one, running the program's own
+    // version to its return if it has one
+    fn invoke(&mut self, name: &str, arguments: &[u16]) -> Result<u16, Stop> {
+        let Some(&function) = self.functions.get(name) else {
+            return os::builtin(name).unwrap().run(self, arguments);
+        };
+
+        let pc = self.pc;
+        for &argument in arguments {
+            self.push(argument)?;
+        }
+        // an address no instruction is at
+        let back = self.code.len() + 1;
+        self.pc = self.call(back, function, arguments.len() as u16)?;
+        let start = self.steps;
+        while self.pc != back {
+            if self.pc >= self.code.len() || self.halted() {
+                return Err(Stop::Halt);
+            }
+            if self.steps - start > NESTED_STEPS {
+                let message = format!("{} never returned", name);
+                return Err(Stop::Error(self.error(message)));
+            }
+            self.execute()?;
+            if self.waiting {
+                let message = format!("{} waits for a key", name);
+                return Err(Stop::Error(self.error(message)));
+            }
+        }
+        self.pc = pc;
+        Ok(self.pop()?)
+    }
+
+    // pushes the frame and returns where the function starts
+    fn call(
+        &mut self,
+        return_address: usize,
+        function: usize,
+        arguments: u16,
+    ) -> Result<usize, RuntimeError> {
+        self.push(return_address as u16)?;
+        for pointer in [LCL, ARG, THIS, THAT] {
+            self.push(self.peek(pointer))?;
+        }
+        let sp = self.peek(SP);
+        self.poke(ARG, sp.wrapping_sub(arguments).wrapping_sub(5));
+        self.poke(LCL, sp);
+        Ok(function)
+    }
+
+    // puts the returned value in place of the arguments, restores the
+    // caller's pointers and returns where to continue
+    fn ret(&mut self) -> Result<usize, RuntimeError> {
+        let frame = self.peek(LCL);
+        let return_address = self.load(frame.wrapping_sub(5))?;
+        let value = self.pop()?;
+        let argument = self.peek(ARG);
+        self.store(argument, value)?;
+        self.poke(SP, argument + 1);
+        for (offset, pointer) in [(1, THAT), (2, THIS), (3, ARG), (4, LCL)] {
+            let value = self.load(frame.wrapping_sub(offset))?;
+            self.poke(pointer, value);
+        }
+        Ok(return_address as usize)
+    }
+
+    fn binary(
+        &mut self,
+        f: impl Fn(u16, u16) -> u16,
+    ) -> Result<(), RuntimeError> {
+        let y = self.pop()?;
+        let x = self.pop()?;
+        self.push(f(x, y))
+    }
+
+    fn unary(&mut self, f: impl Fn(u16) -> u16) -> Result<(), RuntimeError> {
+        let x = self.pop()?;
+        self.push(f(x))
+    }
+
+    fn push(&mut self, value: u16) -> Result<(), RuntimeError> {
+        let sp = self.peek(SP);
+        self.store(sp, value)?;
+        self.poke(SP, sp + 1);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<u16, RuntimeError> {
+        let sp = self.peek(SP).wrapping_sub(1);
+        let value = self.load(sp)?;
+        self.poke(SP, sp);
+        Ok(value)
+    }
+
+    fn load(&self, address: u16) -> Result<u16, RuntimeError> {
+        self.ram
+            .get(address as usize)
+            .copied()
+            .ok_or_else(|| self.out_of_range(address))
+    }
+
+    fn store(&mut self, address: u16, value: u16) -> Result<(), RuntimeError> {
+        let Some(word) = self.ram.get_mut(address as usize) else {
+            return Err(self.out_of_range(address));
+        };
+        *word = value;
+        Ok(())
+    }
+
+    fn segment_address(
+        &self,
+        segment: &Segment,
+        index: u16,
+        target: usize,
+    ) -> Result<u16, RuntimeError> {
+        let address = match segment {
+            Segment::Static => target as u16,
+            _ => self.address(segment, index).unwrap_or(u16::MAX),
+        };
+        match (address as usize) < RAM_SIZE {
+            true => Ok(address),
+            false => Err(self.out_of_range(address)),
+        }
+    }
+
+    // where an index of a segment is, except for statics, which depend on
+    // the file
+    fn address(&self, segment: &Segment, index: u16) -> Option<u16> {
+        let base = |pointer: u16| self.peek(pointer).wrapping_add(index);
+        match segment {
+            Segment::Local => Some(base(LCL)),
+            Segment::Argument => Some(base(ARG)),
+            Segment::This => Some(base(THIS)),
+            Segment::That => Some(base(THAT)),
+            Segment::Pointer => Some(THIS + index),
+            Segment::Temp => Some(TEMP + index),
+            Segment::Static | Segment::Constant | Segment::Stack => None,
+        }
+    }
+
+    fn named_address(&self, name: &str) -> Option<u16> {
+        let address = match name.split_once('[') {
+            None => match name {
+                "sp" => SP,
+                "local" => LCL,
+                "argument" => ARG,
+                "this" => THIS,
+                "that" => THAT,
+                _ => return None,
+            },
+            Some((segment, index)) => {
+                let index = index.strip_suffix(']')?.parse().ok()?;
+                match segment {
+                    "RAM" => index,
+                    _ => self.address(&parse_segment(segment).ok()?, index)?,
+                }
+            }
+        };
+        ((address as usize) < RAM_SIZE).then_some(address)
+    }
+
+    fn out_of_range(&self, address: u16) -> RuntimeError {
+        self.error(format!("address {} is outside of RAM", address))
+    }
+
+    fn error(&self, message: String) -> RuntimeError {
+        match self.code.get(self.pc) {
+            Some(operation) => RuntimeError {
+                file: self.files[operation.file].clone(),
+                line: operation.line,
+                message,
+            },
+            None => RuntimeError {
+                file: String::new(),
+                line: 0,
+                message,
+            },
+        }
+    }
+}
+
+fn truth(value: bool) -> u16 {
+    if value {
+        0xFFFF
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::{parse_sources, source_paths};
+    use crate::translator::parse_file;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn source(contents: &str) -> Source {
+        Source {
+            file: "Main.vm".into(),
+            name: "Main".into(),
+            lines: parse_file("Main", contents).unwrap(),
+        }
+    }
+
+    fn machine(contents: &str) -> VirtualMachine {
+        VirtualMachine::new(&[source(contents)]).unwrap()
+    }
+
+    // runs the program to its end, with the course's OS in .vm files or
+    // with the built-in one
+    fn run_with_os(
+        mut sources: Vec<Source>,
+        course_os: bool,
+    ) -> VirtualMachine {
+        if course_os {
+            let directory =
+                Path::new(env!("CARGO_MANIFEST_DIR")).join("../tools/OS");
+            let paths = source_paths(&directory).unwrap();
+            sources.extend(parse_sources(&paths).unwrap());
+        }
+        let mut machine = VirtualMachine::new(&sources).unwrap();
+        machine.bootstrap().unwrap();
+        // the course's Sys.halt loops instead of stopping the machine
+        while !machine.halted()
+            && !machine.location().unwrap().contains("Sys.vm:2")
+        {
+            machine.run(10_000).unwrap();
+        }
+        machine
+    }
+
+    // Runs one of the course's VM emulator scripts and returns the rows it
+    // outputs and the rows in its compare file
+    fn run_test_script(script: &Path) -> (Vec<Vec<i16>>, Vec<Vec<i16>>) {
+        let directory = script.parent().unwrap();
+        let sources = parse_sources(&source_paths(directory).unwrap()).unwrap();
+        let mut machine = VirtualMachine::new(&sources).unwrap();
+
+        let contents = fs::read_to_string(script).unwrap();
+        let commands: String = contents
+            .lines()
+            .map(|line| line.split("//").next().unwrap())
+            .collect::<Vec<&str>>()
+            .join(" ");
+        let mut outputs = vec![];
+        let mut list = vec![];
+        let mut repeat = 1;
+        for command in commands.split([',', ';', '{', '}']) {
+            match command.split_whitespace().collect::<Vec<&str>>()[..] {
+                ["output-list", ref cells @ ..] => {
+                    list = cells
+                        .iter()
+                        .map(|cell| cell.split('%').next().unwrap().to_owned())
+                        .collect()
+                }
+                ["set", name, value] => machine
+                    .set(name, value.parse::<i16>().unwrap() as u16)
+                    .unwrap(),
+                ["repeat", count] => repeat = count.parse().unwrap(),
+                ["vmstep"] => {
+                    for _ in 0..repeat {
+                        machine.step().unwrap();
+                    }
+                    repeat = 1;
+                }
+                ["output"] => outputs.push(
+                    list.iter()
+                        .map(|name| machine.get(name).unwrap() as i16)
+                        .collect(),
+                ),
+                ["load" | "output-file" | "compare-to", ..] | [] => {}
+                _ => panic!("unexpected command {}", command),
+            }
+        }
+
+        let compare = fs::read_to_string(script.with_file_name(format!(
+            "{}.cmp",
+            directory.file_name().unwrap().to_string_lossy()
+        )))
+        .unwrap();
+        let expected = compare
+            .lines()
+            .filter(|line| !line.contains("RAM"))
+            .map(|line| {
+                line.split('|')
+                    .map(|cell| cell.trim())
+                    .filter(|cell| !cell.is_empty())
+                    .map(|cell| cell.parse().unwrap())
+                    .collect()
+            })
+            .collect();
+        (outputs, expected)
+    }
+
+    #[test]
+    fn test_course_test_scripts() {
+        let mut scripts: Vec<PathBuf> = vec![];
+        for project in ["07", "08"] {
+            let project = format!(
+                "{}/../projects/{}",
+                env!("CARGO_MANIFEST_DIR"),
+                project
+            );
+            for group in fs::read_dir(project).unwrap() {
+                for program in fs::read_dir(group.unwrap().path()).unwrap() {
+                    let program = program.unwrap().path();
+                    let name = program.file_name().unwrap().to_string_lossy();
+                    let script = program.join(format!("{}VME.tst", name));
+                    if script.exists() {
+                        scripts.push(script);
+                    }
+                }
+            }
+        }
+        assert_eq!(11, scripts.len());
+
+        for script in &scripts {
+            let (outputs, expected) = run_test_script(script);
+            assert_eq!(expected, outputs, "{}", script.display());
+        }
+    }
+
+    #[test]
+    fn test_segments_by_name() {
+        let mut machine = machine(
+            "function Main.main 2\npush constant 7\npop local 1\n\
+             push constant 3000\npop pointer 0\npush constant 5\n\
+             pop this 2\npush constant 9\npush constant 1\nneg",
+        );
+        machine.bootstrap().unwrap();
+
+        assert_eq!(10, machine.run(100).unwrap());
+        assert!(machine.halted());
+        assert_eq!(Some(7), machine.segment("local", 1));
+        assert_eq!(Some(3000), machine.get("pointer[0]"));
+        assert_eq!(Some(5), machine.segment("this", 2));
+        assert_eq!(Some(5), machine.get("RAM[3002]"));
+        assert_eq!(None, machine.segment("constant", 0));
+        // the frames of Sys.init and Main.main, the two locals and the
+        // values pushed last
+        assert_eq!(&[9, 0xFFFF], &machine.stack()[12..]);
+    }
+
+    #[test]
+    fn test_calls_and_statics() {
+        let mut machine = machine(
+            "function Sys.init 0\npush constant 4\ncall Main.twice 1\n\
+             pop static 0\nlabel END\ngoto END\n\
+             function Main.twice 0\npush argument 0\npush argument 0\nadd\n\
+             return",
+        );
+        machine.bootstrap().unwrap();
+        machine.run(1000).unwrap();
+
+        assert!(machine.halted());
+        assert_eq!(Some("Main.vm:6 goto END".into()), machine.location());
+        assert_eq!(8, machine.peek(STATICS));
+        assert_eq!(261, machine.get("sp").unwrap());
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let mut machine = machine("push constant 1\npop that 0");
+        machine.set("sp", 256).unwrap();
+        machine.set("that", 40000).unwrap();
+
+        machine.step().unwrap();
+        assert_eq!(
+            "Main.vm:2: address 40000 is outside of RAM",
+            machine.step().unwrap_err().to_string()
+        );
+        assert!(machine.set("stack", 1).is_err());
+    }
+
+    #[test]
+    fn test_inspecting_outside_of_ram() {
+        let mut machine = machine("push constant 1");
+        machine.set("that", 40000).unwrap();
+        machine.set("sp", 40000).unwrap();
+
+        assert_eq!(None, machine.segment("that", 0));
+        assert_eq!(None, machine.get("that[0]"));
+        assert_eq!(RAM_SIZE - STACK as usize, machine.stack().len());
+    }
+
+    #[test]
+    fn test_argument_counts_wrap_around() {
+        let mut machine = machine(
+            "function Main.main 0\ncall Main.f 65535\n\
+             function Main.f 0\npush constant 1\nreturn",
+        );
+        machine.set("sp", 256).unwrap();
+        machine.run(2).unwrap();
+        assert_eq!(Some(257), machine.get("argument"));
+    }
+
+    #[test]
+    fn test_builtin_os_draws_like_the_course_os() {
+        let seven = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../projects/11/Seven/Main.vm");
+        let drawing = "function Main.main 0\n\
+            push constant 10\npush constant 200\npush constant 300\n\
+            push constant 20\ncall Screen.drawLine 4\npop temp 0\n\
+            push constant 50\npush constant 5\npush constant 5\n\
+            push constant 90\ncall Screen.drawLine 4\npop temp 0\n\
+            push constant 100\npush constant 100\npush constant 140\n\
+            push constant 130\ncall Screen.drawRectangle 4\npop temp 0\n\
+            push constant 400\npush constant 128\npush constant 30\n\
+            call Screen.drawCircle 3\npop temp 0\n\
+            push constant 0\ncall Screen.setColor 1\npop temp 0\n\
+            push constant 400\npush constant 128\npush constant 10\n\
+            call Screen.drawCircle 3\npop temp 0\n\
+            push constant 12\npush constant 62\n\
+            call Output.moveCursor 2\npop temp 0\n\
+            push constant 1234\nneg\ncall Output.printInt 1\npop temp 0\n\
+            push constant 129\ncall Output.printChar 1\npop temp 0\n\
+            push constant 100\npush constant 7\ncall Math.divide 2\n\
+            push constant 3\ncall Math.multiply 2\n\
+            call Output.printInt 1\npop temp 0\n\
+            push constant 0\nreturn";
+
+        for sources in [parse_sources(&[seven]).unwrap(), vec![source(drawing)]]
+        {
+            let builtin = run_with_os(sources.clone(), false);
+            let course = run_with_os(sources, true);
+            assert!(builtin.screen().iter().any(|&word| word != 0));
+            assert!(course.screen() == builtin.screen());
+        }
+    }
+
+    #[test]
+    fn test_programs_replace_builtin_functions() {
+        // Array.new is built in, and allocates with this Memory.alloc
+        let mut machine = machine(
+            "function Main.main 0\npush constant 3\ncall Array.new 1\n\
+             pop static 0\npush constant 0\nreturn\n\
+             function Memory.alloc 0\npush constant 5000\n\
+             push argument 0\nadd\nreturn",
+        );
+        machine.bootstrap().unwrap();
+        machine.run(1000).unwrap();
+
+        assert!(machine.halted());
+        assert_eq!(5003, machine.peek(STATICS));
+    }
+
+    #[test]
+    fn test_corrupt_heap() {
+        // a free block at the very end of RAM, too big to fit
+        let mut machine = machine(
+            "function Main.main 0\npush constant 32767\n\
+             call Memory.deAlloc 1\npop temp 0\npush constant 32766\n\
+             push constant 1\nneg\ncall Memory.poke 2\npop temp 0\n\
+             push constant 5\ncall Memory.alloc 1\nreturn",
+        );
+        machine.bootstrap().unwrap();
+
+        assert_eq!(
+            "Main.vm:11: heap block at 32766 runs past the end of RAM",
+            machine.run(1000).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_double_free() {
+        let mut machine = machine(
+            "function Main.main 1\npush constant 3\ncall Memory.alloc 1\n\
+             pop local 0\npush local 0\ncall Memory.deAlloc 1\n\
+             pop temp 0\npush local 0\ncall Memory.deAlloc 1\n\
+             pop temp 0\npush constant 20000\ncall Memory.alloc 1\n\
+             return",
+        );
+        machine.bootstrap().unwrap();
+
+        assert_eq!(
+            "Main.vm:12: free list loops back to the heap block at 16380",
+            machine.run(1000).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_os_errors() {
+        let mut machine = machine(
+            "function Main.main 0\npush constant 1\npush constant 0\n\
+             call Math.divide 2\nreturn",
+        );
+        machine.bootstrap().unwrap();
+        machine.run(1000).unwrap();
+
+        assert!(machine.halted());
+        assert_eq!(Some(3), machine.error_code());
+        assert_ne!(0, machine.peek(SCREEN + 32));
+    }
+
+    #[test]
+    fn test_keyboard_waits_for_keys() {
+        let mut machine = machine(
+            "function Main.main 0\npush constant 0\ncall String.new 1\n\
+             call Keyboard.readInt 1\npop static 0\npush constant 0\n\
+             return",
+        );
+        machine.bootstrap().unwrap();
+        for key in [b'4', b'5', 129, b'2', 128] {
+            machine.run(100).unwrap();
+            machine.poke(KEYBOARD, key as u16);
+            machine.run(100).unwrap();
+            machine.poke(KEYBOARD, 0);
+        }
+        machine.run(100).unwrap();
+
+        assert!(machine.halted());
+        assert_eq!(42, machine.peek(STATICS));
+    }
+}
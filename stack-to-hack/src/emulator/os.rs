@@ -0,0 +1,774 @@
+use crate::emulator::font::FONT;
+use crate::emulator::{
+    RuntimeError, Stop, VirtualMachine, KEYBOARD, RAM_SIZE, SCREEN,
+};
+use std::collections::HashSet;
+
+const HEAP: u16 = 2048;
+const HEAP_END: u16 = SCREEN;
+const ROWS: u16 = 23;
+const COLUMNS: u16 = 64;
+// the text starts a line of pixels down, like the course's OS
+const TEXT: u16 = SCREEN + 32;
+const NEW_LINE: u16 = 128;
+const BACKSPACE: u16 = 129;
+const DOUBLE_QUOTE: u16 = 34;
+const LINE_LENGTH: u16 = 80;
+
+type Function = fn(&mut VirtualMachine, &[u16]) -> Result<u16, Stop>;
+
+pub struct Builtin {
+    pub name: &'static str,
+    pub arguments: u16,
+    function: Function,
+}
+
+impl Builtin {
+    pub(super) fn run(
+        &self,
+        machine: &mut VirtualMachine,
+        arguments: &[u16],
+    ) -> Result<u16, Stop> {
+        if arguments.len() != self.arguments as usize {
+            let message = format!(
+                "{} takes {} arguments, not {}",
+                self.name,
+                self.arguments,
+                arguments.len()
+            );
+            return Err(Stop::Error(machine.error(message)));
+        }
+        (self.function)(machine, arguments)
+    }
+}
+
+// The state the OS classes keep in statics, kept here instead of in RAM.
+// The heap's free blocks are in RAM, each with its size and the address of
+// the next one.
+#[derive(Default)]
+pub struct Os {
+    pub error_code: Option<i16>,
+    // the first free block, once the heap is set up
+    free: Option<u16>,
+    row: u16,
+    column: u16,
+    white: bool,
+    // the string printInt prints through
+    number: Option<u16>,
+    // the key readChar has seen pressed, once it's shown the cursor
+    key: Option<u16>,
+    // the line readLine is reading
+    line: Option<u16>,
+}
+
+pub const BUILTINS: &[Builtin] = &[
+    native("Math.init", 0, |_, _| Ok(0)),
+    native("Math.abs", 1, |_, a| Ok(int(a[0]).wrapping_abs() as u16)),
+    native("Math.multiply", 2, |_, a| Ok(a[0].wrapping_mul(a[1]))),
+    native("Math.divide", 2, |machine, a| {
+        math_divide(machine, a[0], a[1])
+    }),
+    native("Math.min", 2, |_, a| Ok(int(a[0]).min(int(a[1])) as u16)),
+    native("Math.max", 2, |_, a| Ok(int(a[0]).max(int(a[1])) as u16)),
+    native("Math.sqrt", 1, |machine, a| math_sqrt(machine, a[0])),
+    native("String.new", 1, |machine, a| string_new(machine, a[0])),
+    native("String.dispose", 1, |machine, a| {
+        machine.invoke("Memory.deAlloc", a)
+    }),
+    native("String.length", 1, |machine, a| {
+        Ok(machine.load(a[0].wrapping_add(1))?)
+    }),
+    native("String.charAt", 2, |machine, a| {
+        string_char_at(machine, a[0], a[1])
+    }),
+    native("String.setCharAt", 3, |machine, a| {
+        string_set_char_at(machine, a[0], a[1], a[2])
+    }),
+    native("String.appendChar", 2, |machine, a| {
+        string_append_char(machine, a[0], a[1])
+    }),
+    native("String.eraseLastChar", 1, |machine, a| {
+        string_erase_last_char(machine, a[0])
+    }),
+    native("String.intValue", 1, |machine, a| {
+        string_int_value(machine, a[0])
+    }),
+    native("String.setInt", 2, |machine, a| {
+        string_set_int(machine, a[0], a[1])
+    }),
+    native("String.backSpace", 0, |_, _| Ok(BACKSPACE)),
+    native("String.doubleQuote", 0, |_, _| Ok(DOUBLE_QUOTE)),
+    native("String.newLine", 0, |_, _| Ok(NEW_LINE)),
+    native("Array.new", 1, |machine, a| {
+        if int(a[0]) <= 0 {
+            return sys_error(machine, 2);
+        }
+        machine.invoke("Memory.alloc", a)
+    }),
+    native("Array.dispose", 1, |machine, a| {
+        machine.invoke("Memory.deAlloc", a)
+    }),
+    native("Output.init", 0, |machine, _| {
+        machine.os.row = 0;
+        machine.os.column = 0;
+        Ok(0)
+    }),
+    native("Output.moveCursor", 2, |machine, a| {
+        output_move_cursor(machine, a[0], a[1])
+    }),
+    native("Output.printChar", 1, |machine, a| {
+        output_print_char(machine, a[0])
+    }),
+    native("Output.printString", 1, |machine, a| {
+        output_print_string(machine, a[0])
+    }),
+    native("Output.printInt", 1, |machine, a| {
+        output_print_int(machine, a[0])
+    }),
+    native("Output.println", 0, |machine, _| output_println(machine)),
+    native("Output.backSpace", 0, |machine, _| {
+        output_backspace(machine)
+    }),
+    native("Screen.init", 0, |machine, _| {
+        machine.os.white = false;
+        Ok(0)
+    }),
+    native("Screen.clearScreen", 0, |machine, _| {
+        for address in SCREEN..KEYBOARD {
+            machine.poke(address, 0);
+        }
+        Ok(0)
+    }),
+    native("Screen.setColor", 1, |machine, a| {
+        machine.os.white = a[0] == 0;
+        Ok(0)
+    }),
+    native("Screen.drawPixel", 2, |machine, a| {
+        screen_draw_pixel(machine, a[0], a[1])
+    }),
+    native("Screen.drawLine", 4, |machine, a| {
+        screen_draw_line(machine, a[0], a[1], a[2], a[3])
+    }),
+    native("Screen.drawRectangle", 4, |machine, a| {
+        screen_draw_rectangle(machine, a[0], a[1], a[2], a[3])
+    }),
+    native("Screen.drawCircle", 3, |machine, a| {
+        screen_draw_circle(machine, a[0], a[1], a[2])
+    }),
+    native("Keyboard.init", 0, |_, _| Ok(0)),
+    native("Keyboard.keyPressed", 0, |machine, _| {
+        Ok(machine.peek(KEYBOARD))
+    }),
+    native("Keyboard.readChar", 0, |machine, _| read_char(machine)),
+    native("Keyboard.readLine", 1, |machine, a| {
+        read_line(machine, a[0])
+    }),
+    native("Keyboard.readInt", 1, |machine, a| {
+        let line = read_line(machine, a[0])?;
+        let value = machine.invoke("String.intValue", &[line])?;
+        machine.invoke("String.dispose", &[line])?;
+        Ok(value)
+    }),
+    native("Memory.init", 0, |machine, _| {
+        machine.poke(HEAP, HEAP_END - HEAP);
+        machine.poke(HEAP + 1, 0);
+        machine.os.free = Some(HEAP);
+        Ok(0)
+    }),
+    native("Memory.peek", 1, |machine, a| Ok(machine.load(a[0])?)),
+    native("Memory.poke", 2, |machine, a| {
+        machine.store(a[0], a[1])?;
+        Ok(0)
+    }),
+    native("Memory.alloc", 1, |machine, a| memory_alloc(machine, a[0])),
+    native("Memory.deAlloc", 1, |machine, a| {
+        memory_dealloc(machine, a[0])
+    }),
+    native("Sys.init", 0, |machine, _| sys_init(machine)),
+    native("Sys.halt", 0, |_, _| Err(Stop::Halt)),
+    native("Sys.error", 1, |machine, a| {
+        machine.os.error_code = Some(int(a[0]));
+        for c in "ERR".bytes() {
+            machine.invoke("Output.printChar", &[c as u16])?;
+        }
+        machine.invoke("Output.printInt", a)?;
+        machine.invoke("Sys.halt", &[])
+    }),
+    // waits no time at all: the machine's only clock is its steps
+    native("Sys.wait", 1, |machine, a| {
+        if int(a[0]) < 0 {
+            return sys_error(machine, 1);
+        }
+        Ok(0)
+    }),
+];
+
+// the built-in function with the name, if there's one
+pub fn builtin(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|builtin| builtin.name == name)
+}
+
+// Each function indexes its arguments freely, as `Builtin::run` checks
+// there are as many as it takes
+const fn native(
+    name: &'static str,
+    arguments: u16,
+    function: Function,
+) -> Builtin {
+    Builtin {
+        name,
+        arguments,
+        function,
+    }
+}
+
+fn int(value: u16) -> i16 {
+    value as i16
+}
+
+fn sys_error(machine: &mut VirtualMachine, code: u16) -> Result<u16, Stop> {
+    machine.invoke("Sys.error", &[code])
+}
+
+fn math_divide(
+    machine: &mut VirtualMachine,
+    x: u16,
+    y: u16,
+) -> Result<u16, Stop> {
+    if y == 0 {
+        return sys_error(machine, 3);
+    }
+    Ok(int(x).wrapping_div(int(y)) as u16)
+}
+
+fn math_sqrt(machine: &mut VirtualMachine, x: u16) -> Result<u16, Stop> {
+    if int(x) < 0 {
+        return sys_error(machine, 4);
+    }
+    let mut root = 0;
+    while (root + 1) * (root + 1) <= x as u32 {
+        root += 1;
+    }
+    Ok(root as u16)
+}
+
+// A string is its capacity, its length and then its characters
+fn string_new(
+    machine: &mut VirtualMachine,
+    capacity: u16,
+) -> Result<u16, Stop> {
+    if int(capacity) < 0 {
+        return sys_error(machine, 14);
+    }
+    let string = machine.invoke("Memory.alloc", &[capacity + 2])?;
+    machine.store(string, capacity)?;
+    machine.store(string.wrapping_add(1), 0)?;
+    Ok(string)
+}
+
+fn string_char_at(
+    machine: &mut VirtualMachine,
+    string: u16,
+    index: u16,
+) -> Result<u16, Stop> {
+    if index >= machine.invoke("String.length", &[string])? {
+        return sys_error(machine, 15);
+    }
+    Ok(machine.load(string.wrapping_add(2 + index))?)
+}
+
+fn string_set_char_at(
+    machine: &mut VirtualMachine,
+    string: u16,
+    index: u16,
+    c: u16,
+) -> Result<u16, Stop> {
+    if index >= machine.invoke("String.length", &[string])? {
+        return sys_error(machine, 16);
+    }
+    machine.store(string.wrapping_add(2 + index), c)?;
+    Ok(0)
+}
+
+fn string_append_char(
+    machine: &mut VirtualMachine,
+    string: u16,
+    c: u16,
+) -> Result<u16, Stop> {
+    let length = machine.load(string.wrapping_add(1))?;
+    if length >= machine.load(string)? {
+        return sys_error(machine, 17);
+    }
+    machine.store(string.wrapping_add(2 + length), c)?;
+    machine.store(string.wrapping_add(1), length + 1)?;
+    Ok(string)
+}
+
+fn string_erase_last_char(
+    machine: &mut VirtualMachine,
+    string: u16,
+) -> Result<u16, Stop> {
+    let length = machine.load(string.wrapping_add(1))?;
+    if length == 0 {
+        return sys_error(machine, 18);
+    }
+    machine.store(string.wrapping_add(1), length - 1)?;
+    Ok(0)
+}
+
+// the number at the start of the string, with an optional minus sign
+fn string_int_value(
+    machine: &mut VirtualMachine,
+    string: u16,
+) -> Result<u16, Stop> {
+    let length = machine.load(string.wrapping_add(1))?;
+    let mut value: u16 = 0;
+    let mut negative = false;
+    for index in 0..length {
+        let c = machine.load(string.wrapping_add(2 + index))?;
+        match c {
+            45 if index == 0 => negative = true,
+            48..=57 => value = value.wrapping_mul(10).wrapping_add(c - 48),
+            _ => break,
+        }
+    }
+    Ok(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+fn string_set_int(
+    machine: &mut VirtualMachine,
+    string: u16,
+    number: u16,
+) -> Result<u16, Stop> {
+    let digits = int(number).to_string();
+    if digits.len() as u16 > machine.load(string)? {
+        return sys_error(machine, 19);
+    }
+    for (index, c) in digits.bytes().enumerate() {
+        machine.store(string.wrapping_add(2 + index as u16), c as u16)?;
+    }
+    machine.store(string.wrapping_add(1), digits.len() as u16)?;
+    Ok(0)
+}
+
+fn output_move_cursor(
+    machine: &mut VirtualMachine,
+    row: u16,
+    column: u16,
+) -> Result<u16, Stop> {
+    if row >= ROWS || column >= COLUMNS {
+        return sys_error(machine, 20);
+    }
+    machine.os.row = row;
+    machine.os.column = column;
+    draw_char(machine, b' ' as u16);
+    Ok(0)
+}
+
+fn output_print_char(
+    machine: &mut VirtualMachine,
+    c: u16,
+) -> Result<u16, Stop> {
+    match c {
+        NEW_LINE => machine.invoke("Output.println", &[]),
+        BACKSPACE => machine.invoke("Output.backSpace", &[]),
+        c => {
+            draw_char(machine, c);
+            machine.os.column += 1;
+            if machine.os.column == COLUMNS {
+                machine.invoke("Output.println", &[])?;
+            }
+            Ok(0)
+        }
+    }
+}
+
+fn output_print_string(
+    machine: &mut VirtualMachine,
+    string: u16,
+) -> Result<u16, Stop> {
+    let length = machine.invoke("String.length", &[string])?;
+    for index in 0..length {
+        let c = machine.invoke("String.charAt", &[string, index])?;
+        machine.invoke("Output.printChar", &[c])?;
+    }
+    Ok(0)
+}
+
+fn output_print_int(
+    machine: &mut VirtualMachine,
+    number: u16,
+) -> Result<u16, Stop> {
+    let string = match machine.os.number {
+        Some(string) => string,
+        None => {
+            let string = machine.invoke("String.new", &[6])?;
+            machine.os.number = Some(string);
+            string
+        }
+    };
+    machine.invoke("String.setInt", &[string, number])?;
+    machine.invoke("Output.printString", &[string])
+}
+
+// the next row, or the first one after the last
+fn output_println(machine: &mut VirtualMachine) -> Result<u16, Stop> {
+    machine.os.row = (machine.os.row + 1) % ROWS;
+    machine.os.column = 0;
+    Ok(0)
+}
+
+// the previous column, or the end of the previous row, erasing what's there
+fn output_backspace(machine: &mut VirtualMachine) -> Result<u16, Stop> {
+    if machine.os.column > 0 {
+        machine.os.column -= 1;
+    } else {
+        machine.os.column = COLUMNS - 1;
+        machine.os.row = (machine.os.row + ROWS - 1) % ROWS;
+    }
+    draw_char(machine, b' ' as u16);
+    Ok(0)
+}
+
+// Draws the character at the cursor. Two columns share each word of the
+// screen, the even one in the low byte.
+fn draw_char(machine: &mut VirtualMachine, c: u16) {
+    let map = match c {
+        32..=126 => &FONT[c as usize - 31],
+        _ => &FONT[0],
+    };
+    let (row, column) = (machine.os.row, machine.os.column);
+    for (line, &bits) in map.iter().enumerate() {
+        let address = TEXT + (row * 11 + line as u16) * 32 + column / 2;
+        let word = machine.peek(address);
+        let word = if column % 2 == 0 {
+            word & 0xFF00 | bits
+        } else {
+            word & 0x00FF | bits << 8
+        };
+        machine.poke(address, word);
+    }
+}
+
+fn on_screen(x: i16, y: i16) -> bool {
+    (0..512).contains(&x) && (0..256).contains(&y)
+}
+
+fn draw_pixel(machine: &mut VirtualMachine, x: i16, y: i16) {
+    let address = SCREEN + y as u16 * 32 + x as u16 / 16;
+    let bit = 1 << (x % 16);
+    let word = machine.peek(address);
+    let word = if machine.os.white {
+        word & !bit
+    } else {
+        word | bit
+    };
+    machine.poke(address, word);
+}
+
+fn draw_horizontal(machine: &mut VirtualMachine, x1: i16, x2: i16, y: i16) {
+    for x in x1..=x2 {
+        draw_pixel(machine, x, y);
+    }
+}
+
+fn screen_draw_pixel(
+    machine: &mut VirtualMachine,
+    x: u16,
+    y: u16,
+) -> Result<u16, Stop> {
+    if !on_screen(int(x), int(y)) {
+        return sys_error(machine, 7);
+    }
+    draw_pixel(machine, int(x), int(y));
+    Ok(0)
+}
+
+// Bresenham's algorithm, as the course's OS draws lines: one pixel for
+// each step along the longer side, from the end with the lower coordinate
+// on that side
+fn screen_draw_line(
+    machine: &mut VirtualMachine,
+    x1: u16,
+    y1: u16,
+    x2: u16,
+    y2: u16,
+) -> Result<u16, Stop> {
+    let (x1, y1, x2, y2) = (int(x1), int(y1), int(x2), int(y2));
+    if !on_screen(x1, y1) || !on_screen(x2, y2) {
+        return sys_error(machine, 8);
+    }
+    // a runs along the longer side and b across it
+    let steep = (x2 - x1).abs() < (y2 - y1).abs();
+    let (mut a, mut b, mut end, mut b_end) = if steep {
+        (y1, x1, y2, x2)
+    } else {
+        (x1, y1, x2, y2)
+    };
+    if end < a {
+        (a, b, end, b_end) = (end, b_end, a, b);
+    }
+    let (long, short) = (end - a, (b_end - b).abs());
+    let step = if b_end < b { -1 } else { 1 };
+    let mut error = 2 * short - long;
+    loop {
+        if steep {
+            draw_pixel(machine, b, a);
+        } else {
+            draw_pixel(machine, a, b);
+        }
+        if a >= end {
+            return Ok(0);
+        }
+        if error < 0 {
+            error += 2 * short;
+        } else {
+            error += 2 * (short - long);
+            b += step;
+        }
+        a += 1;
+    }
+}
+
+fn screen_draw_rectangle(
+    machine: &mut VirtualMachine,
+    x1: u16,
+    y1: u16,
+    x2: u16,
+    y2: u16,
+) -> Result<u16, Stop> {
+    let (x1, y1, x2, y2) = (int(x1), int(y1), int(x2), int(y2));
+    if x1 > x2 || y1 > y2 || !on_screen(x1, y1) || !on_screen(x2, y2) {
+        return sys_error(machine, 9);
+    }
+    for y in y1..=y2 {
+        draw_horizontal(machine, x1, x2, y);
+    }
+    Ok(0)
+}
+
+// The midpoint algorithm, as the course's OS fills circles: for each
+// point of the outline in one eighth of the circle, the four lines across
+// it that its reflections are the ends of
+fn screen_draw_circle(
+    machine: &mut VirtualMachine,
+    x: u16,
+    y: u16,
+    radius: u16,
+) -> Result<u16, Stop> {
+    let (x, y, r) = (int(x), int(y), int(radius));
+    if !on_screen(x, y) {
+        return sys_error(machine, 12);
+    }
+    if r < 0 || !on_screen(x - r, y - r) || !on_screen(x + r, y + r) {
+        return sys_error(machine, 13);
+    }
+    let (mut a, mut b, mut decision) = (0, r, 1 - r);
+    loop {
+        draw_horizontal(machine, x - a, x + a, y - b);
+        draw_horizontal(machine, x - a, x + a, y + b);
+        draw_horizontal(machine, x - b, x + b, y - a);
+        draw_horizontal(machine, x - b, x + b, y + a);
+        if b <= a {
+            return Ok(0);
+        }
+        if decision < 0 {
+            decision += 2 * a + 3;
+        } else {
+            decision += 2 * (a - b) + 5;
+            b -= 1;
+        }
+        a += 1;
+    }
+}
+
+// Shows a cursor, then waits for a key to be pressed and let go, and
+// prints it in the cursor's place. Each call that has to wait returns
+// `Stop::Wait` and picks up where it left off when called again.
+fn read_char(machine: &mut VirtualMachine) -> Result<u16, Stop> {
+    let pressed = machine.peek(KEYBOARD);
+    match machine.os.key {
+        None => {
+            machine.invoke("Output.printChar", &[0])?;
+            machine.os.key = Some(pressed);
+            Err(Stop::Wait)
+        }
+        Some(_) if pressed != 0 => {
+            machine.os.key = Some(pressed);
+            Err(Stop::Wait)
+        }
+        Some(0) => Err(Stop::Wait),
+        Some(key) => {
+            machine.os.key = None;
+            machine.invoke("Output.printChar", &[BACKSPACE])?;
+            machine.invoke("Output.printChar", &[key])?;
+            Ok(key)
+        }
+    }
+}
+
+// reads keys into a new string until a new line, erasing the last one on
+// a backspace
+fn read_line(machine: &mut VirtualMachine, message: u16) -> Result<u16, Stop> {
+    let line = match machine.os.line {
+        Some(line) => line,
+        None => {
+            machine.invoke("Output.printString", &[message])?;
+            let line = machine.invoke("String.new", &[LINE_LENGTH])?;
+            machine.os.line = Some(line);
+            line
+        }
+    };
+    match read_char(machine)? {
+        NEW_LINE => {
+            machine.os.line = None;
+            return Ok(line);
+        }
+        BACKSPACE => {
+            if machine.invoke("String.length", &[line])? > 0 {
+                machine.invoke("String.eraseLastChar", &[line])?;
+            }
+        }
+        c => {
+            machine.invoke("String.appendChar", &[line, c])?;
+        }
+    }
+    Err(Stop::Wait)
+}
+
+// First fit, taking the end of the first free block that's big enough,
+// or all of it if what's left would be too small to keep. A block's size
+// is in the word before it. When nothing fits, the free blocks are merged
+// with their neighbours and the search tried again.
+fn memory_alloc(machine: &mut VirtualMachine, size: u16) -> Result<u16, Stop> {
+    if int(size) < 0 {
+        return sys_error(machine, 5);
+    }
+    let needed = size.max(1) + 1;
+    if machine.os.free.is_none() {
+        machine.invoke("Memory.init", &[])?;
+    }
+    for attempt in 0..2 {
+        if attempt == 1 {
+            merge_free_blocks(machine)?;
+        }
+        let mut previous = None;
+        let mut visited = HashSet::new();
+        let mut block = machine.os.free.unwrap_or(0);
+        while block != 0 {
+            visit_free_block(machine, &mut visited, block)?;
+            let length = machine.load(block)?;
+            let next = machine.load(heap_address(machine, block, 1)?)?;
+            if length >= needed + 2 {
+                let allocated = heap_address(machine, block, length - needed)?;
+                machine.store(block, length - needed)?;
+                machine.store(allocated, needed)?;
+                return Ok(allocated + 1);
+            }
+            if length >= needed {
+                match previous {
+                    Some(previous) => machine.store(previous + 1, next)?,
+                    None => machine.os.free = Some(next),
+                }
+                return Ok(block + 1);
+            }
+            previous = Some(block);
+            block = next;
+        }
+    }
+    sys_error(machine, 6)
+}
+
+fn memory_dealloc(
+    machine: &mut VirtualMachine,
+    object: u16,
+) -> Result<u16, Stop> {
+    let block = object.wrapping_sub(1);
+    machine.store(block.wrapping_add(1), machine.os.free.unwrap_or(0))?;
+    machine.os.free = Some(block);
+    Ok(0)
+}
+
+// A word of a free block. The blocks' sizes and links are in RAM, where the
+// program can have overwritten them with anything.
+fn heap_address(
+    machine: &VirtualMachine,
+    block: u16,
+    offset: u16,
+) -> Result<u16, RuntimeError> {
+    match block.checked_add(offset) {
+        Some(address) if (address as usize) < RAM_SIZE => Ok(address),
+        _ => Err(machine.error(format!(
+            "heap block at {} runs past the end of RAM",
+            block
+        ))),
+    }
+}
+
+// Freeing an object twice links its block to itself, so the free list can
+// loop, and walking it would never end
+fn visit_free_block(
+    machine: &VirtualMachine,
+    visited: &mut HashSet<u16>,
+    block: u16,
+) -> Result<(), RuntimeError> {
+    if visited.insert(block) {
+        Ok(())
+    } else {
+        Err(machine.error(format!(
+            "free list loops back to the heap block at {}",
+            block
+        )))
+    }
+}
+
+// sorts the free blocks by address and joins the ones next to each other
+fn merge_free_blocks(machine: &mut VirtualMachine) -> Result<(), RuntimeError> {
+    let mut blocks = vec![];
+    let mut visited = HashSet::new();
+    let mut block = machine.os.free.unwrap_or(0);
+    while block != 0 {
+        visit_free_block(machine, &mut visited, block)?;
+        blocks.push((block, machine.load(block)?));
+        block = machine.load(heap_address(machine, block, 1)?)?;
+    }
+    blocks.sort();
+
+    let mut merged: Vec<(u16, u16)> = vec![];
+    for (block, length) in blocks {
+        match merged.last_mut() {
+            Some((last, last_length))
+                if last.checked_add(*last_length) == Some(block) =>
+            {
+                *last_length = last_length.saturating_add(length)
+            }
+            _ => merged.push((block, length)),
+        }
+    }
+    let mut next = 0;
+    for &(block, length) in merged.iter().rev() {
+        machine.store(block, length)?;
+        machine.store(heap_address(machine, block, 1)?, next)?;
+        next = block;
+    }
+    machine.os.free = Some(next);
+    Ok(())
+}
+
+// Sets up the OS and goes on to Main.main, which returns to the end of
+// the program, halting it
+fn sys_init(machine: &mut VirtualMachine) -> Result<u16, Stop> {
+    for class in ["Memory", "Math", "Screen", "Output", "Keyboard"] {
+        machine.invoke(&format!("{}.init", class), &[])?;
+    }
+    let Some(&main) = machine.functions.get("Main.main") else {
+        let message = "no function Main.main is defined".to_owned();
+        return Err(Stop::Error(machine.error(message)));
+    };
+    let end = machine.code.len();
+    Err(Stop::Jump(machine.call(end, main, 0)?))
+}
@@ -0,0 +1,4 @@
+pub mod bytecode;
+pub mod emulator;
+pub mod sources;
+pub mod translator;
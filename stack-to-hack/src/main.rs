@@ -1,72 +1,1249 @@
 use std::env;
 use std::fs;
-use std::io::prelude::*;
-use std::path::Path;
-
-mod translator;
-
-fn translate<S>(path: S)
-where
-    S: AsRef<str>,
-{
-    if let Ok(contents) = read_file(&path) {
-        let name: &str = Path::new(path.as_ref())
-            .file_stem()
-            .unwrap()
-            .to_str()
-            .unwrap();
-        contents.lines().map(|line| line.trim()).for_each(|line| {
-            if line.is_empty() || line.starts_with("//") {
-                println!("{}", line);
-            } else {
-                for instruction in translator::translate(line, name) {
-                    println!("{}", instruction);
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use stack_to_hack::sources::{self, parse_sources, source_paths};
+use stack_to_hack::translator::{
+    self, Bootstrap, Source, SourceLine, Translator,
+};
+
+const USAGE: &str = "USAGE: ./stack-to-hack file.vm|file.vmb|directory \
+                     [-o out.asm|-] [--bootstrap|--no-bootstrap] \
+                     [--entry FUNCTION] [--sp N] [--lcl N] [--arg N] \
+                     [--this N] [--that N] [--inline] \
+                     [--optimize] [--optimize-size] [--cache-top] [--prune] \
+                     [--analyze] [--call-graph out.dot] \
+                     [--source-map out.map] [--extended] \
+                     [--target hack|c|wat]";
+const ENTRY: &str = "Sys.init";
+
+// what the program is translated to
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    #[default]
+    Hack,
+    C,
+    Wat,
+}
+
+#[derive(Default)]
+struct Options {
+    path: String,
+    output: Option<String>,
+    bootstrap: Option<bool>,
+    entry: Option<String>,
+    registers: Vec<(String, u16)>,
+    inline: bool,
+    optimize: bool,
+    optimize_size: bool,
+    cache_top: bool,
+    prune: bool,
+    analyze: bool,
+    call_graph: Option<String>,
+    source_map: Option<String>,
+    extended: bool,
+    target: Target,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut args = env::args().skip(1);
+    let mut options = Options::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-o" => options.output = Some(value()?),
+            "--bootstrap" => options.bootstrap = Some(true),
+            "--no-bootstrap" => options.bootstrap = Some(false),
+            "--entry" => options.entry = Some(value()?),
+            "--inline" => options.inline = true,
+            "--optimize" => options.optimize = true,
+            "--optimize-size" => options.optimize_size = true,
+            "--cache-top" => options.cache_top = true,
+            "--prune" => options.prune = true,
+            "--analyze" => options.analyze = true,
+            "--call-graph" => options.call_graph = Some(value()?),
+            "--source-map" => options.source_map = Some(value()?),
+            "--extended" => options.extended = true,
+            "--target" => {
+                options.target = match value()?.as_str() {
+                    "hack" => Target::Hack,
+                    "c" => Target::C,
+                    "wat" => Target::Wat,
+                    target => return Err(format!("unknown target {}", target)),
+                }
+            }
+            "--sp" | "--lcl" | "--arg" | "--this" | "--that" => {
+                let register = arg[2..].to_uppercase();
+                let value = value()?;
+                let value = value
+                    .parse()
+                    .map_err(|_| format!("invalid value {}", value))?;
+                // the bootstrap loads it with an A-instruction
+                if value > 32767 {
+                    return Err(format!(
+                        "{} value {} is out of range 0-32767",
+                        arg, value
+                    ));
                 }
-                println!();
+                options.registers.retain(|(name, _)| *name != register);
+                options.registers.push((register, value));
+            }
+            _ if options.path.is_empty() && !arg.starts_with('-') => {
+                options.path = arg
             }
-        });
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+
+    if options.path.is_empty() {
+        return Err(USAGE.into());
+    }
+    if options.target != Target::Hack {
+        for (flag, used) in [
+            ("--optimize-size", options.optimize_size),
+            ("--cache-top", options.cache_top),
+            ("--source-map", options.source_map.is_some()),
+        ] {
+            if used {
+                return Err(format!(
+                    "{} only works with the hack target",
+                    flag
+                ));
+            }
+        }
+    }
+    Ok(options)
+}
+
+// By default the bootstrap sets SP to 256 and calls Sys.init if the program
+// has one, as the course's test scripts for programs without it set up the
+// pointers themselves. Registers set with flags are set either way.
+fn bootstrap(
+    options: &Options,
+    sources: &[Source],
+) -> Result<Bootstrap, String> {
+    let entry = options.entry.as_deref().unwrap_or(ENTRY);
+    let call = options.bootstrap.unwrap_or(
+        options.entry.is_some() || translator::defines_function(sources, entry),
+    );
+
+    let mut bootstrap = Bootstrap::default();
+    if call {
+        if !translator::defines_function(sources, entry) {
+            return Err(format!("no entry function {} is defined", entry));
+        }
+        bootstrap.registers.push(("SP".into(), 256));
+        bootstrap.entry = Some(entry.into());
+    }
+    for (register, value) in &options.registers {
+        bootstrap.registers.retain(|(name, _)| name != register);
+        bootstrap.registers.push((register.clone(), *value));
+    }
+    Ok(bootstrap)
+}
+
+// `Dir/Dir.asm` for a directory and `File.asm` next to a file, or `.c` or
+// `.wat`
+fn output_path(path: &Path, target: Target) -> PathBuf {
+    let extension = match target {
+        Target::Hack => "asm",
+        Target::C => "c",
+        Target::Wat => "wat",
+    };
+    sources::output_path(path, extension)
+}
+
+// the .vm line a chunk of assembly comes from
+struct Origin<'a> {
+    file: &'a str,
+    line: &'a SourceLine,
+}
+
+fn translate<'a>(
+    translator: &mut Translator,
+    source: &'a Source,
+) -> Vec<(Option<Origin<'a>>, Vec<String>)> {
+    let mut chunks = vec![];
+    for line in &source.lines {
+        if line.instruction.is_none() {
+            chunks.push((None, vec![line.text.clone()]));
+        } else {
+            let mut code = translator.translate_line(line, &source.name);
+            code.push(String::new());
+            let origin = Origin {
+                file: &source.file,
+                line,
+            };
+            chunks.push((Some(origin), code));
+        }
+    }
+    chunks
+}
+
+fn translate_program(
+    options: &Options,
+    sources: &[Source],
+) -> Result<String, String> {
+    translate_with_source_map(options, sources).map(|(assembly, _)| assembly)
+}
+
+// The assembly and a line for each .vm line that generated instructions:
+// the first ROM address, the address after the last, the file, the line
+// number and the instruction, separated by tabs. The bootstrap and the
+// shared routines aren't in the map.
+fn translate_with_source_map(
+    options: &Options,
+    sources: &[Source],
+) -> Result<(String, String), String> {
+    let bootstrap = bootstrap(options, sources)?;
+    let sources = inline_and_optimize(options, sources);
+
+    let mut translator = Translator::new()
+        .shared_routines(options.optimize_size)
+        .cache_top(options.cache_top);
+    let mut chunks = vec![];
+    let mut code = translator.bootstrap(&bootstrap);
+    if !code.is_empty() {
+        code.push(String::new());
+    }
+    chunks.push((None, code));
+    for source in &sources {
+        chunks.append(&mut translate(&mut translator, source));
+    }
+    chunks.push((None, translator.finish()));
+    // the runtime goes first, but only holds the routines used by the rest
+    let mut runtime = translator.runtime();
+    if !runtime.is_empty() {
+        runtime.push(String::new());
+    }
+    chunks.insert(0, (None, runtime));
+
+    let mut output = vec![];
+    let mut source_map = String::new();
+    let mut address = 0;
+    for (origin, mut code) in chunks {
+        let words = rom_words(&code.join("\n"));
+        if let (Some(origin), true) = (origin, words > 0) {
+            let file = Path::new(origin.file)
+                .file_name()
+                .map(|name| name.to_string_lossy())
+                .unwrap_or_default();
+            let text = origin.line.text.split("//").next().unwrap_or("");
+            source_map += &format!(
+                "{}\t{}\t{}\t{}\t{}\n",
+                address,
+                address + words,
+                file,
+                origin.line.number,
+                text.trim()
+            );
+        }
+        address += words;
+        output.append(&mut code);
+    }
+    output.push(String::new());
+    Ok((output.join("\n"), source_map))
+}
+
+// the program as a single C file
+fn translate_to_c(
+    options: &Options,
+    sources: &[Source],
+) -> Result<String, String> {
+    let bootstrap = bootstrap(options, sources)?;
+    Ok(translator::to_c(
+        &inline_and_optimize(options, sources),
+        &bootstrap,
+    ))
+}
+
+// the program as a WebAssembly module in the text format
+fn translate_to_wat(
+    options: &Options,
+    sources: &[Source],
+) -> Result<String, String> {
+    let bootstrap = bootstrap(options, sources)?;
+    Ok(translator::to_wat(
+        &inline_and_optimize(options, sources),
+        &bootstrap,
+    ))
+}
+
+fn inline_and_optimize(options: &Options, sources: &[Source]) -> Vec<Source> {
+    let mut sources = if options.inline {
+        translator::inline(sources)
     } else {
-        panic!("ERROR: unable to read file {}", path.as_ref());
+        sources.to_vec()
+    };
+    if options.optimize {
+        for source in &mut sources {
+            source.lines = translator::optimize(mem::take(&mut source.lines));
+        }
     }
+    sources
+}
+
+// Drops the functions the entry function never calls, directly or not, if
+// the bootstrap calls one
+fn prune(
+    options: &Options,
+    sources: &[Source],
+) -> Result<(Vec<Source>, Vec<String>), String> {
+    Ok(match bootstrap(options, sources)?.entry {
+        Some(entry) => translator::remove_dead_functions(sources, &entry),
+        None => (sources.to_vec(), vec![]),
+    })
 }
 
-fn read_file<S>(path: S) -> std::io::Result<String>
-where
-    S: AsRef<str>,
-{
-    let mut file = fs::File::open(path.as_ref())?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    Ok(contents)
+// Prints how deep the stack gets in each function instead of translating,
+// failing if any function leaves the stack unbalanced
+fn analyze(options: &Options, sources: &[Source]) -> Result<(), Vec<String>> {
+    let entry = bootstrap(options, sources)
+        .map_err(|error| vec![error])?
+        .entry;
+    let report = translator::analyze(sources);
+    for line in translator::describe(&report, entry.as_deref()) {
+        println!("{}", line);
+    }
+
+    if report.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(report
+            .errors
+            .iter()
+            .map(|error| error.to_string())
+            .collect())
+    }
+}
+
+// the number of instructions in the assembly, leaving out labels
+fn rom_words(assembly: &str) -> usize {
+    assembly
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| {
+            !(line.is_empty()
+                || line.starts_with("//")
+                || line.starts_with('('))
+        })
+        .count()
+}
+
+fn run(options: Options) -> Result<(), Vec<String>> {
+    let path = Path::new(&options.path);
+    let paths = source_paths(path).map_err(|error| vec![error])?;
+    let sources = parse_sources(&paths)?;
+    let mut errors = translator::validate(&sources);
+    if !options.extended {
+        errors.append(&mut translator::check_extended(&sources));
+    }
+    if !errors.is_empty() {
+        return Err(errors.iter().map(|error| error.to_string()).collect());
+    }
+
+    if let Some(file) = &options.call_graph {
+        let dot = translator::to_dot(&translator::call_graph(&sources));
+        fs::write(file, dot)
+            .map_err(|error| vec![format!("{}: {}", file, error)])?;
+    }
+    if options.analyze {
+        return analyze(&options, &sources);
+    }
+
+    let (pruned, removed) = if options.prune {
+        prune(&options, &sources).map_err(|error| vec![error])?
+    } else {
+        (sources.clone(), vec![])
+    };
+    let output = match options.target {
+        Target::Hack => {
+            let (output, source_map) =
+                translate_with_source_map(&options, &pruned)
+                    .map_err(|error| vec![error])?;
+            if !removed.is_empty() {
+                let full = translate_program(&options, &sources)
+                    .map_err(|error| vec![error])?;
+                eprintln!(
+                    "removed {} unreachable functions, saving {} ROM words:",
+                    removed.len(),
+                    rom_words(&full) - rom_words(&output)
+                );
+            }
+            if let Some(file) = &options.source_map {
+                fs::write(file, source_map)
+                    .map_err(|error| vec![format!("{}: {}", file, error)])?;
+            }
+            output
+        }
+        Target::C | Target::Wat => {
+            if !removed.is_empty() {
+                eprintln!("removed {} unreachable functions:", removed.len());
+            }
+            if options.target == Target::C {
+                translate_to_c(&options, &pruned)
+            } else {
+                translate_to_wat(&options, &pruned)
+            }
+            .map_err(|error| vec![error])?
+        }
+    };
+    for function in removed {
+        eprintln!("    {}", function);
+    }
+
+    match options.output.as_deref() {
+        Some("-") => print!("{}", output),
+        Some(file) => fs::write(file, output)
+            .map_err(|error| vec![format!("{}: {}", file, error)])?,
+        None => {
+            let file = output_path(path, options.target);
+            fs::write(&file, output).map_err(|error| {
+                vec![format!("{}: {}", file.display(), error)]
+            })?
+        }
+    }
+    Ok(())
 }
 
 fn main() {
-    if let Some(path) = env::args().nth(1) {
-        for instruction in translator::bootstrap() {
-            println!("{}", instruction);
-        }
-        println!();
-
-        match fs::read_dir(&path) {
-            Ok(read_dir) => {
-                for maybe_entry in read_dir {
-                    match maybe_entry {
-                        Ok(entry) => {
-                            if entry.path().extension().unwrap() == "vm" {
-                                translate(entry.path().to_str().unwrap());
-                            }
+    let options = match parse_options() {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(2);
+        }
+    };
+
+    if let Err(errors) = run(options) {
+        for error in errors {
+            eprintln!("ERROR: {}", error);
+        }
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hack_emulator::computer::Computer;
+    use hack_emulator::program::Program;
+    use hack_emulator::source_map::SourceMap;
+    use stack_to_hack::emulator::{VirtualMachine, KEYBOARD};
+
+    #[test]
+    fn test_output_path() {
+        let directory = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../projects/08/FunctionCalls/FibonacciElement"
+        );
+
+        assert_eq!(
+            Path::new(directory).join("FibonacciElement.asm"),
+            output_path(Path::new(directory), Target::Hack)
+        );
+        assert_eq!(
+            PathBuf::from("StackTest/StackTest.asm"),
+            output_path(Path::new("StackTest/StackTest.vm"), Target::Hack)
+        );
+        assert_eq!(
+            PathBuf::from("StackTest/StackTest.c"),
+            output_path(Path::new("StackTest/StackTest.vm"), Target::C)
+        );
+        assert_eq!(
+            PathBuf::from("StackTest/StackTest.wat"),
+            output_path(Path::new("StackTest/StackTest.vm"), Target::Wat)
+        );
+    }
+
+    fn run_program(directory: &str, options: &Options) -> Computer {
+        let directory =
+            format!("{}/../projects/{}", env!("CARGO_MANIFEST_DIR"), directory);
+        let paths = source_paths(Path::new(&directory)).unwrap();
+        let sources = parse_sources(&paths).unwrap();
+        let assembly = translate_program(options, &sources).unwrap();
+
+        let mut computer = Computer::new(Program::assemble(&assembly).rom);
+        computer.run(1_000_000);
+        assert!(computer.halted());
+        computer
+    }
+
+    #[test]
+    fn test_source_map_points_at_the_vm_lines() {
+        let directory = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../projects/08/FunctionCalls/FibonacciElement"
+        );
+        let sources =
+            parse_sources(&source_paths(Path::new(directory)).unwrap())
+                .unwrap();
+        let options = Options {
+            optimize_size: true,
+            cache_top: true,
+            ..Options::default()
+        };
+        let (assembly, source_map) =
+            translate_with_source_map(&options, &sources).unwrap();
+        let source_map = SourceMap::parse(&source_map).unwrap();
+        let program = Program::assemble(&assembly);
+        let address = |label: &str| {
+            program
+                .labels
+                .iter()
+                .find(|(name, _)| name == label)
+                .unwrap()
+                .1
+        };
+
+        // a function without locals has no code of its own
+        assert_eq!(
+            "Main.vm:12 push argument 0",
+            source_map
+                .lookup(address("Main.fibonacci"))
+                .unwrap()
+                .to_string()
+        );
+        // the bootstrap and the shared routines aren't from any file
+        assert!(source_map.lookup(0).is_none());
+
+        let mut computer = Computer::new(program.rom);
+        computer.run(1_000_000);
+        let location = source_map.lookup(computer.pc).unwrap();
+        assert_eq!(
+            ("Sys.vm", "goto WHILE"),
+            (&location.file[..], &location.text[..])
+        );
+    }
+
+    #[test]
+    fn test_shared_routines_make_smaller_programs() {
+        let directory = "08/FunctionCalls/FibonacciElement";
+        let options = Options {
+            optimize_size: true,
+            ..Options::default()
+        };
+        let inline = run_program(directory, &Options::default());
+        let shared = run_program(directory, &options);
+
+        assert_eq!(3, shared.peek(261));
+        assert_eq!(262, shared.peek(0));
+        assert!(shared.rom().len() < inline.rom().len());
+    }
+
+    #[test]
+    fn test_optimized_programs_behave_the_same() {
+        let options = Options {
+            optimize: true,
+            ..Options::default()
+        };
+        for (directory, results) in [
+            ("08/FunctionCalls/FibonacciElement", 261..262),
+            ("08/FunctionCalls/StaticsTest", 261..263),
+            ("08/FunctionCalls/NestedCall", 261..262),
+        ] {
+            let plain = run_program(directory, &Options::default());
+            let optimized = run_program(directory, &options);
+
+            for address in results.chain([0]) {
+                assert_eq!(plain.peek(address), optimized.peek(address));
+            }
+            assert!(optimized.rom().len() < plain.rom().len());
+        }
+    }
+
+    // Runs one of the course's test scripts on the translated directory and
+    // returns the rows it outputs and the rows in its compare file. Only
+    // the commands the CPU emulator scripts in projects 07 and 08 use are
+    // understood.
+    fn run_test_script(
+        script: &Path,
+        options: &Options,
+    ) -> (Vec<Vec<i16>>, Vec<Vec<i16>>) {
+        let directory = script.parent().unwrap();
+        let sources = parse_sources(&source_paths(directory).unwrap()).unwrap();
+        let assembly = translate_program(options, &sources).unwrap();
+        let mut computer = Computer::new(Program::assemble(&assembly).rom);
+
+        let mut outputs = vec![];
+        let mut list = vec![];
+        let mut repeat = 1;
+        for command in script_commands(script) {
+            match command.iter().map(String::as_str).collect::<Vec<&str>>()[..]
+            {
+                ["output-list", ref cells @ ..] => {
+                    list = cells.iter().map(|cell| ram_cell(cell)).collect()
+                }
+                ["set", cell, value] => computer
+                    .poke(ram_cell(cell), value.parse::<i16>().unwrap() as u16),
+                ["repeat", count] => repeat = count.parse().unwrap(),
+                ["ticktock"] => {
+                    for _ in 0..repeat {
+                        computer.step();
+                    }
+                    repeat = 1;
+                }
+                ["output"] => outputs.push(
+                    list.iter()
+                        .map(|&address| computer.peek(address) as i16)
+                        .collect(),
+                ),
+                ["load" | "output-file" | "compare-to", _] | [] => {}
+                _ => panic!("unexpected command {:?}", command),
+            }
+        }
+        (outputs, compare_rows(script))
+    }
+
+    // the words of each command in a test script
+    fn script_commands(script: &Path) -> Vec<Vec<String>> {
+        let contents = fs::read_to_string(script).unwrap();
+        let commands: String = contents
+            .lines()
+            .map(|line| line.split("//").next().unwrap())
+            .collect::<Vec<&str>>()
+            .join(" ");
+        commands
+            .split([',', ';', '{', '}'])
+            .map(|command| {
+                command.split_whitespace().map(String::from).collect()
+            })
+            .collect()
+    }
+
+    fn ram_cell(cell: &str) -> u16 {
+        let cell = cell.split('%').next().unwrap();
+        cell.strip_prefix("RAM[")
+            .and_then(|cell| cell.strip_suffix(']'))
+            .and_then(|address| address.parse().ok())
+            .unwrap_or_else(|| panic!("unexpected cell {}", cell))
+    }
+
+    fn compare_rows(script: &Path) -> Vec<Vec<i16>> {
+        let compare = fs::read_to_string(script.with_extension("cmp")).unwrap();
+        compare
+            .lines()
+            .filter(|line| !line.contains("RAM"))
+            .map(|line| {
+                line.split('|')
+                    .map(|cell| cell.trim())
+                    .filter(|cell| !cell.is_empty())
+                    .map(|cell| cell.parse().unwrap())
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn test_scripts() -> Vec<PathBuf> {
+        let mut scripts = vec![];
+        for project in ["07", "08"] {
+            let project = format!(
+                "{}/../projects/{}",
+                env!("CARGO_MANIFEST_DIR"),
+                project
+            );
+            for group in fs::read_dir(project).unwrap() {
+                for program in fs::read_dir(group.unwrap().path()).unwrap() {
+                    let program = program.unwrap().path();
+                    let name = program.file_name().unwrap().to_owned();
+                    let script = program.join(name).with_extension("tst");
+                    if script.exists() {
+                        scripts.push(script);
+                    }
+                }
+            }
+        }
+        scripts.sort();
+        scripts
+    }
+
+    #[test]
+    fn test_course_test_scripts() {
+        let scripts = test_scripts();
+        assert_eq!(11, scripts.len());
+
+        for options in [
+            Options::default(),
+            Options {
+                cache_top: true,
+                ..Options::default()
+            },
+            Options {
+                cache_top: true,
+                optimize: true,
+                inline: true,
+                ..Options::default()
+            },
+        ] {
+            for script in &scripts {
+                let (outputs, expected) = run_test_script(script, &options);
+                assert_eq!(expected, outputs, "{}", script.display());
+            }
+        }
+    }
+
+    fn source(name: &str, contents: &str) -> Source {
+        Source {
+            file: format!("{}.vm", name),
+            name: name.into(),
+            lines: translator::parse_file(name, contents).unwrap(),
+        }
+    }
+
+    fn run_sources(sources: &[Source], options: &Options) -> Computer {
+        let assembly = translate_program(options, sources).unwrap();
+        let mut computer = Computer::new(Program::assemble(&assembly).rom);
+        computer.run(1_000_000);
+        assert!(computer.halted());
+        computer
+    }
+
+    #[test]
+    fn test_inlined_functions_behave_the_same() {
+        let sources = [
+            source(
+                "Sys",
+                "function Sys.init 0\ncall Main.main 0\npop temp 0\n\
+                 label HALT\ngoto HALT",
+            ),
+            source(
+                "Main",
+                "function Main.main 0\npush constant 1234\npop pointer 0\n\
+                 push constant 3000\npush constant 7\ncall Point.setX 2\n\
+                 pop temp 0\npush constant 3000\ncall Point.getX 1\n\
+                 push constant 5\ncall Point.sum 2\npush constant 8\n\
+                 call Point.sum 2\npop temp 6\npush pointer 0\npop temp 7\n\
+                 push constant 0\nreturn",
+            ),
+            source(
+                "Point",
+                "function Point.getX 0\npush argument 0\npop pointer 0\n\
+                 push this 0\nreturn\n\
+                 function Point.setX 0\npush argument 0\npop pointer 0\n\
+                 push argument 1\npop this 0\npush constant 0\nreturn\n\
+                 function Point.sum 1\npush argument 0\npush argument 1\n\
+                 add\npop local 0\npush local 0\nreturn",
+            ),
+        ];
+
+        for options in [
+            Options::default(),
+            Options {
+                inline: true,
+                ..Options::default()
+            },
+            Options {
+                inline: true,
+                optimize: true,
+                cache_top: true,
+                ..Options::default()
+            },
+        ] {
+            let computer = run_sources(&sources, &options);
+            assert_eq!(20, computer.peek(11));
+            assert_eq!(1234, computer.peek(12));
+            assert_eq!(261, computer.peek(0));
+        }
+        let assembly = translate_program(
+            &Options {
+                inline: true,
+                ..Options::default()
+            },
+            &sources,
+        )
+        .unwrap();
+        assert!(!assembly.contains("@Point."));
+    }
+
+    #[test]
+    fn test_tail_calls_reuse_the_frame() {
+        // counts down from 3000 with one call each, which needs 7 words of
+        // stack per call without tail calls
+        let sources = [source(
+            "Sys",
+            "function Sys.init 0\npush constant 0\npush constant 3000\n\
+             call Sys.count 2\npop temp 0\nlabel HALT\ngoto HALT\n\
+             function Sys.count 0\npush argument 1\nif-goto MORE\n\
+             push argument 0\nreturn\nlabel MORE\n\
+             push argument 0\npush constant 1\nadd\n\
+             push argument 1\npush constant 1\nsub\n\
+             call Sys.count 2\nreturn",
+        )];
+        let options = Options {
+            optimize: true,
+            ..Options::default()
+        };
+        let assembly = translate_program(&options, &sources).unwrap();
+        let mut computer = Computer::new(Program::assemble(&assembly).rom);
+
+        let mut deepest = 0;
+        while !computer.halted() {
+            computer.step();
+            deepest = deepest.max(computer.peek(0));
+        }
+        assert_eq!(3000, computer.peek(5));
+        assert!(deepest < 280, "{}", deepest);
+    }
+
+    #[test]
+    fn test_prune_the_os() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+        let mut paths = source_paths(&root.join("tools/OS")).unwrap();
+        paths.push(root.join("projects/11/Seven/Main.vm"));
+        let sources = parse_sources(&paths).unwrap();
+        let options = Options {
+            prune: true,
+            ..Options::default()
+        };
+
+        let (pruned, removed) = prune(&options, &sources).unwrap();
+
+        assert!(translator::validate(&pruned).is_empty());
+        for function in ["Keyboard.readLine", "Screen.drawCircle"] {
+            assert!(removed.contains(&function.to_owned()));
+        }
+        for function in ["Main.main", "Output.printInt", "Math.multiply"] {
+            assert!(translator::defines_function(&pruned, function));
+        }
+        let full = translate_program(&options, &sources).unwrap();
+        let smaller = translate_program(&options, &pruned).unwrap();
+        assert!(rom_words(&smaller) + 10_000 < rom_words(&full));
+        assert!(rom_words(&full) < full.lines().count());
+    }
+
+    #[test]
+    fn test_analyzed_depth_matches_the_emulator() {
+        let directory = format!(
+            "{}/../projects/08/FunctionCalls/NestedCall",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let sources =
+            parse_sources(&source_paths(Path::new(&directory)).unwrap())
+                .unwrap();
+        let report = translator::analyze(&sources);
+        let assembly =
+            translate_program(&Options::default(), &sources).unwrap();
+        let mut computer = Computer::new(Program::assemble(&assembly).rom);
+
+        let mut deepest = 0;
+        while !computer.halted() {
+            computer.step();
+            deepest = deepest.max(computer.peek(0));
+        }
+        assert_eq!(
+            Some(&format!(
+                "at most {} words of stack from Sys.init",
+                deepest - 256
+            )),
+            translator::describe(&report, Some("Sys.init")).last()
+        );
+    }
+
+    #[test]
+    fn test_cached_top_makes_smaller_programs() {
+        let directory = "07/StackArithmetic/StackTest";
+        let options = Options {
+            cache_top: true,
+            ..Options::default()
+        };
+        let plain = run_program(directory, &Options::default());
+        let cached = run_program(directory, &options);
+
+        assert!(cached.rom().len() < plain.rom().len());
+    }
+
+    #[test]
+    fn test_extended_instructions() {
+        // few enough for the program to fit in ROM
+        let values: [i16; 9] = [0, 1, -1, 3, -7, 16, -1000, 32767, -32768];
+        let push = |value: i16| match value {
+            -32768 => "push constant 32767\nneg\npush constant 1\nsub\n".into(),
+            _ if value < 0 => format!("push constant {}\nneg\n", -value),
+            _ => format!("push constant {}\n", value),
+        };
+        // the results go from RAM[3000] up
+        let mut code =
+            "function Sys.init 0\npush constant 3000\npop pointer 1\n"
+                .to_owned();
+        let mut expected = vec![];
+        for mnemonic in ["mul", "div", "mod", "shl", "shr", "xor"] {
+            let instruction = translator::parse(mnemonic).unwrap();
+            for x in values {
+                for y in values {
+                    code += &push(x);
+                    code += &push(y);
+                    code +=
+                        &format!("{}\npop that {}\n", mnemonic, expected.len());
+                    expected.push(
+                        instruction.extended_result(x as u16, y as u16).unwrap()
+                            as i16,
+                    );
+                }
+            }
+        }
+        code += "label HALT\ngoto HALT";
+        let sources = [source("Sys", &code)];
+
+        let result = |mnemonic: &str, x: i16, y: i16| {
+            let instruction = translator::parse(mnemonic).unwrap();
+            instruction.extended_result(x as u16, y as u16).unwrap() as i16
+        };
+        assert_eq!(-3, result("div", -7, 2));
+        assert_eq!(-1, result("mod", -7, 2));
+        assert_eq!(-32768, result("div", -32768, -1));
+        assert_eq!(0, result("shl", 1, 16));
+        assert_eq!(-4, result("shr", -7, 1));
+        assert_eq!(
+            vec!["Sys.vm:6: mul is only in the extended instruction set"],
+            translator::check_extended(&sources)
+                .iter()
+                .map(|error| error.to_string())
+                .collect::<Vec<String>>()[..1]
+        );
+        for (cache_top, optimize_size) in [(false, false), (true, true)] {
+            let options = Options {
+                extended: true,
+                cache_top,
+                optimize_size,
+                ..Options::default()
+            };
+            let computer = run_sources(&sources, &options);
+            let results: Vec<i16> = (0..expected.len())
+                .map(|index| computer.peek(3000 + index as u16) as i16)
+                .collect();
+            assert_eq!(expected, results);
+        }
+    }
+
+    // Translates the program to C, builds it with the system's compiler,
+    // along with the given display and keyboard hooks if any, and runs it
+    // with the arguments, returning the words it dumps, or None when
+    // there's no compiler to run, so the tests that use it pass on
+    // machines without one
+    fn run_c(
+        name: &str,
+        options: &Options,
+        sources: &[Source],
+        arguments: &[String],
+        hooks: Option<&str>,
+    ) -> Option<Vec<i16>> {
+        let path = env::temp_dir().join(format!(
+            "stack-to-hack-{}-{}",
+            process::id(),
+            name
+        ));
+        let mut program = translate_to_c(options, sources).unwrap();
+        if let Some(hooks) = hooks {
+            program = format!("#define VM_HOOKS\n{}\n{}", program, hooks);
+        }
+        fs::write(path.with_extension("c"), program).unwrap();
+        let build = process::Command::new("cc")
+            .args(["-std=c99", "-Wall", "-pedantic", "-Werror", "-o"])
+            .arg(&path)
+            .arg(path.with_extension("c"))
+            .output();
+        let Ok(build) = build else {
+            eprintln!("no cc to build the C backend's output with, skipping");
+            fs::remove_file(path.with_extension("c")).unwrap();
+            return None;
+        };
+        assert!(
+            build.status.success(),
+            "{}",
+            String::from_utf8_lossy(&build.stderr)
+        );
+        let run = process::Command::new(&path).args(arguments).output();
+        fs::remove_file(path.with_extension("c")).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let output = String::from_utf8(run.unwrap().stdout).unwrap();
+        Some(
+            output
+                .lines()
+                .filter_map(|line| line.split_once('='))
+                .map(|(_, value)| value.parse().unwrap())
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_c_backend_passes_course_test_scripts() {
+        for (index, options) in [
+            Options::default(),
+            Options {
+                inline: true,
+                optimize: true,
+                ..Options::default()
+            },
+        ]
+        .iter()
+        .enumerate()
+        {
+            for script in test_scripts() {
+                let directory = script.parent().unwrap();
+                let sources =
+                    parse_sources(&source_paths(directory).unwrap()).unwrap();
+                // The C program runs until it halts rather than for as many
+                // cycles as the script says, and dumps the cells of every
+                // output list then, which is when the scripts output them.
+                let mut arguments = vec![];
+                for command in script_commands(&script) {
+                    match &command[..] {
+                        [set, cell, value] if set == "set" => {
+                            arguments.extend([
+                                "--set".to_owned(),
+                                format!("{}={}", ram_cell(cell), value),
+                            ])
                         }
-                        Err(error) => {
-                            panic!("{:?}", error);
+                        [list, cells @ ..] if list == "output-list" => {
+                            for cell in cells {
+                                let address = ram_cell(cell);
+                                arguments.extend([
+                                    "--dump".to_owned(),
+                                    format!("{}-{}", address, address),
+                                ]);
+                            }
                         }
+                        _ => {}
                     }
                 }
+                let name = format!(
+                    "{}-{}",
+                    directory.file_name().unwrap().to_string_lossy(),
+                    index
+                );
+
+                let Some(outputs) =
+                    run_c(&name, options, &sources, &arguments, None)
+                else {
+                    return;
+                };
+                assert_eq!(
+                    compare_rows(&script).concat(),
+                    outputs,
+                    "{}",
+                    script.display()
+                );
             }
-            Err(_) => {
-                translate(path);
+        }
+    }
+
+    // the program in a project 11 directory, with the course's OS
+    fn os_program(program: &str) -> Vec<PathBuf> {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+        let mut paths = source_paths(&root.join("tools/OS")).unwrap();
+        paths.extend(
+            source_paths(&root.join("projects/11").join(program))
+                .unwrap()
+                .into_iter()
+                .filter(|path| !path.to_string_lossy().ends_with(".out.vm")),
+        );
+        paths
+    }
+
+    // the pruned program, small enough for the ROM, and the computer
+    // running its Hack translation
+    fn fit_in_rom(program: &str) -> (Vec<Source>, Computer) {
+        let options = Options {
+            optimize: true,
+            optimize_size: true,
+            cache_top: true,
+            prune: true,
+            ..Options::default()
+        };
+        let (sources, _) =
+            prune(&options, &parse_sources(&os_program(program)).unwrap())
+                .unwrap();
+        let assembly = translate_program(&options, &sources).unwrap();
+        let computer = Computer::new(Program::assemble(&assembly).rom);
+        (sources, computer)
+    }
+
+    #[test]
+    fn test_c_backend_matches_hack_on_the_os_programs() {
+        // ConvertToBin reads a number from RAM[8000] and leaves its bits in
+        // the 16 words after it
+        let arguments: Vec<String> = [
+            "--set",
+            "8000=1234",
+            "--steps",
+            "5000000",
+            "--dump",
+            "16384-24575",
+            "--dump",
+            "8001-8016",
+        ]
+        .map(String::from)
+        .to_vec();
+
+        // Average and Pong read keys, so they're run below
+        for program in ["Seven", "ComplexArrays", "Square", "ConvertToBin"] {
+            let (sources, mut computer) = fit_in_rom(program);
+            computer.poke(8000, 1234);
+            computer.run(30_000_000);
+
+            let options = Options {
+                optimize: true,
+                ..Options::default()
+            };
+            let Some(outputs) =
+                run_c(program, &options, &sources, &arguments, None)
+            else {
+                return;
+            };
+            let expected: Vec<i16> = (16384..24576)
+                .chain(8001..8017)
+                .map(|address| computer.peek(address) as i16)
+                .collect();
+            assert_eq!(expected, outputs, "{}", program);
+            if program == "ConvertToBin" {
+                assert_eq!(
+                    [0, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0],
+                    outputs[8192..]
+                );
             }
         }
-    } else {
-        println!("USAGE: ./stack-to-hack file.vm");
+    }
+
+    // the key held down at each read of the keyboard, each key for 2000
+    // reads, then none for as many
+    fn held_key(keys: &[u8], read: u64) -> u16 {
+        let phase = (read / 2000) as usize;
+        match keys.get(phase / 2) {
+            Some(&key) if phase.is_multiple_of(2) => key as u16,
+            _ => 0,
+        }
+    }
+
+    // Runs the Hack program until it halts, its cycles run out or it's
+    // about to read the keyboard for the time numbered `stop`, counting
+    // from 0, with the keys of held_key. Returns the number of reads.
+    fn run_reading_keys(
+        computer: &mut Computer,
+        keys: &[u8],
+        stop: Option<u64>,
+        cycles: u64,
+    ) -> u64 {
+        let mut reads = 0;
+        while computer.cycles < cycles && !computer.halted() {
+            let pc = computer.pc as usize;
+            let instruction = computer.rom().get(pc).copied().unwrap_or(0);
+            // a C-instruction computing with M
+            if instruction & 0x9000 == 0x9000 && computer.a == KEYBOARD {
+                if Some(reads) == stop {
+                    break;
+                }
+                computer.poke(KEYBOARD, held_key(keys, reads));
+                reads += 1;
+            }
+            computer.step();
+        }
+        reads
+    }
+
+    // C hooks doing the same as run_reading_keys, dumping the screen when
+    // they stop the program. The keys end in a 0, as C has no empty arrays.
+    fn key_hooks(keys: &[u8], stop: Option<u64>) -> String {
+        let values: Vec<String> =
+            keys.iter().chain([&0]).map(|key| key.to_string()).collect();
+        format!(
+            "static const int16_t keys[] = {{{}}};\n\
+             void vm_refresh(const int16_t *screen) {{ (void)screen; }}\n\
+             int16_t vm_key(void) {{\n\
+             \x20   static long reads;\n\
+             \x20   long phase = reads / 2000, address;\n\
+             \x20   if (reads++ == {}) {{\n\
+             \x20       for (address = 16384; address < 24576; address++)\n\
+             \x20           printf(\"RAM[%ld]=%d\\n\", address, ram[address]);\n\
+             \x20       exit(0);\n\
+             \x20   }}\n\
+             \x20   if (phase % 2 || phase / 2 >= {}) return 0;\n\
+             \x20   return keys[phase / 2];\n\
+             }}\n",
+            values.join(", "),
+            stop.map_or(-1, |stop| stop as i64),
+            keys.len()
+        )
+    }
+
+    fn screen(computer: &Computer) -> Vec<i16> {
+        (16384..24576)
+            .map(|address| computer.peek(address) as i16)
+            .collect()
+    }
+
+    #[test]
+    fn test_c_backend_matches_hack_and_the_emulator_on_average() {
+        // the average of 10, 20 and 36
+        let keys =
+            [b'3', 128, b'1', b'0', 128, b'2', b'0', 128, b'3', b'6', 128];
+        let (sources, mut computer) = fit_in_rom("Average");
+        run_reading_keys(&mut computer, &keys, None, 200_000_000);
+        assert!(computer.halted());
+
+        // the emulator can't count the reads, so it's given each key for a
+        // while instead
+        let mut machine = VirtualMachine::new(&sources).unwrap();
+        machine.bootstrap().unwrap();
+        // the OS takes a while to set up
+        machine.run(2_000_000).unwrap();
+        for &key in &keys {
+            machine.run(200_000).unwrap();
+            machine.poke(KEYBOARD, key as u16);
+            machine.run(200_000).unwrap();
+            machine.poke(KEYBOARD, 0);
+        }
+        machine.run(5_000_000).unwrap();
+        let expected: Vec<i16> =
+            machine.screen().iter().map(|&word| word as i16).collect();
+        assert!(expected.iter().any(|&word| word != 0));
+        assert_eq!(expected, screen(&computer));
+
+        let arguments: Vec<String> =
+            ["--steps", "5000000", "--dump", "16384-24575"]
+                .map(String::from)
+                .to_vec();
+        let options = Options {
+            optimize: true,
+            ..Options::default()
+        };
+        let hooks = key_hooks(&keys, None);
+        let Some(outputs) =
+            run_c("Average", &options, &sources, &arguments, Some(&hooks))
+        else {
+            return;
+        };
+        assert_eq!(expected, outputs);
+    }
+
+    #[test]
+    fn test_c_backend_matches_hack_on_pong() {
+        // Pong never finishes, so both are stopped as they're about to
+        // read the keyboard for the 100th time, by when the ball has moved
+        // 99 times
+        let (sources, mut computer) = fit_in_rom("Pong");
+        assert_eq!(
+            99,
+            run_reading_keys(&mut computer, &[], Some(99), 200_000_000)
+        );
+
+        let arguments: Vec<String> =
+            ["--steps", "100000000"].map(String::from).to_vec();
+        let options = Options {
+            optimize: true,
+            ..Options::default()
+        };
+        let hooks = key_hooks(&[], Some(99));
+        let Some(outputs) =
+            run_c("Pong", &options, &sources, &arguments, Some(&hooks))
+        else {
+            return;
+        };
+        let expected = screen(&computer);
+        assert!(expected.iter().any(|&word| word != 0));
+        assert_eq!(expected, outputs);
     }
 }
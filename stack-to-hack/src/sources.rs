@@ -0,0 +1,206 @@
+use crate::bytecode;
+use crate::translator::{self, Source};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// The .vm files in a directory sorted by name, so the output doesn't depend
+// on the order the file system lists them in, or just the given file, which
+// can also be a .vmb bytecode file
+pub fn source_paths(path: &Path) -> Result<Vec<PathBuf>, String> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut paths = vec![];
+    let read_dir = fs::read_dir(path)
+        .map_err(|error| format!("{}: {}", path.display(), error))?;
+    for maybe_entry in read_dir {
+        let entry = maybe_entry
+            .map_err(|error| format!("{}: {}", path.display(), error))?;
+        let path = entry.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "vm") {
+            paths.push(path);
+        }
+    }
+    if paths.is_empty() {
+        return Err(format!("{}: no .vm files", path.display()));
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+// Parses every file, reporting the errors in all of them at once. A
+// bytecode file gives each of the files in it, named as if they were next
+// to it.
+pub fn parse_sources(paths: &[PathBuf]) -> Result<Vec<Source>, Vec<String>> {
+    let mut sources = vec![];
+    let mut errors = vec![];
+
+    for path in paths {
+        let file = path.display().to_string();
+        if is_bytecode(path) {
+            match read_bytecode(path) {
+                Ok(mut decoded) => sources.append(&mut decoded),
+                Err(error) => errors.push(format!("{}: {}", file, error)),
+            }
+            continue;
+        }
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) => {
+                errors
+                    .push(format!("{}: unable to read file: {}", file, error));
+                continue;
+            }
+        };
+        match translator::parse_file(&file, &contents) {
+            Ok(lines) => sources.push(Source {
+                file,
+                name: module_name(path),
+                lines,
+            }),
+            Err(parse_errors) => errors
+                .extend(parse_errors.iter().map(|error| error.to_string())),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(sources)
+    } else {
+        Err(errors)
+    }
+}
+
+pub fn is_bytecode(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "vmb")
+}
+
+// the file names are checked to be just names, so they stay next to it
+fn read_bytecode(path: &Path) -> io::Result<Vec<Source>> {
+    Ok(bytecode::decode_lines(&fs::read(path)?)?
+        .into_iter()
+        .map(|(name, lines)| {
+            let path = path.with_file_name(name);
+            Source {
+                file: path.display().to_string(),
+                name: module_name(&path),
+                lines,
+            }
+        })
+        .collect())
+}
+
+// Where what's made from the program at the path goes: `Dir/Dir.ext` for
+// a directory and `File.ext` next to a file
+pub fn output_path(path: &Path, extension: &str) -> PathBuf {
+    if path.is_dir() {
+        let name = path
+            .canonicalize()
+            .ok()
+            .and_then(|path| path.file_name().map(|name| name.to_owned()))
+            .unwrap_or_else(|| "out".into());
+        path.join(format!("{}.{}", name.to_string_lossy(), extension))
+    } else {
+        path.with_extension(extension)
+    }
+}
+
+pub fn module_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_paths_are_sorted() {
+        let directory = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../projects/08/FunctionCalls/StaticsTest"
+        );
+        let names: Vec<String> = source_paths(Path::new(directory))
+            .unwrap()
+            .iter()
+            .map(|path| module_name(path))
+            .collect();
+
+        assert_eq!(vec!["Class1", "Class2", "Sys"], names);
+    }
+
+    #[test]
+    fn test_bytecode_files_give_the_same_sources() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../projects/08/FunctionCalls/StaticsTest");
+        let paths = source_paths(&directory).unwrap();
+        let files: Vec<bytecode::TextFile> = paths
+            .iter()
+            .map(|path| bytecode::TextFile {
+                name: path.file_name().unwrap().to_string_lossy().into(),
+                contents: fs::read_to_string(path).unwrap(),
+            })
+            .collect();
+        let bytecode_path = std::env::temp_dir().join(format!(
+            "stack-to-hack-{}-StaticsTest.vmb",
+            std::process::id()
+        ));
+        fs::write(&bytecode_path, bytecode::encode(&files).unwrap()).unwrap();
+
+        let decoded = parse_sources(&source_paths(&bytecode_path).unwrap());
+        fs::remove_file(&bytecode_path).unwrap();
+        let decoded = decoded.unwrap();
+        let sources = parse_sources(&paths).unwrap();
+        assert_eq!(sources.len(), decoded.len());
+        for (source, decoded) in sources.iter().zip(&decoded) {
+            assert_eq!(source.name, decoded.name);
+            assert_eq!(source.lines, decoded.lines);
+            assert_eq!(
+                Path::new(&source.file).file_name(),
+                Path::new(&decoded.file).file_name()
+            );
+        }
+    }
+
+    #[test]
+    fn test_output_path_keeps_dotted_directory_names() {
+        let directory = std::env::temp_dir()
+            .join(format!("stack-to-hack-{}.v2", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = output_path(&directory, "vmb");
+        fs::remove_dir(&directory).unwrap();
+
+        assert_eq!(
+            directory
+                .join(format!("stack-to-hack-{}.v2.vmb", std::process::id())),
+            path
+        );
+        assert_eq!(
+            PathBuf::from("StackTest/StackTest.vmb"),
+            output_path(Path::new("StackTest/StackTest.vm"), "vmb")
+        );
+    }
+
+    #[test]
+    fn test_bytecode_file_names_stay_next_to_it() {
+        let path = std::env::temp_dir()
+            .join(format!("stack-to-hack-{}-Evil.vmb", std::process::id()));
+        let file = bytecode::TextFile {
+            name: "../Sys.vm".into(),
+            contents: "push constant 1".into(),
+        };
+        fs::write(&path, bytecode::encode(&[file]).unwrap()).unwrap();
+
+        let result = parse_sources(std::slice::from_ref(&path));
+        fs::remove_file(&path).unwrap();
+        let Err(errors) = result else {
+            panic!("expected the file name to be rejected");
+        };
+        assert_eq!(
+            vec![format!("{}: invalid file name ../Sys.vm", path.display())],
+            errors
+        );
+    }
+}
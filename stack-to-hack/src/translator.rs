@@ -1,23 +1,148 @@
+mod analysis;
+mod c;
+mod call_graph;
 mod code;
+mod inliner;
 mod instruction;
+mod optimizer;
 mod parser;
+mod validator;
+mod wat;
+#[cfg(test)]
+mod wat_interpreter;
+
+use std::collections::BTreeSet;
+
+pub use analysis::{analyze, describe};
+pub use c::to_c;
+pub use call_graph::{call_graph, remove_dead_functions, to_dot};
+pub use inliner::inline;
+pub use instruction::{Instruction, Segment};
+pub use optimizer::optimize;
+pub use parser::{parse, parse_file, parse_segment, SourceLine};
+pub use validator::{
+    check_extended, defines_function, validate, validate_with_builtins,
+};
+pub use wat::to_wat;
+
+// a parsed .vm file and the name its statics are prefixed with
+#[derive(Clone)]
+pub struct Source {
+    pub file: String,
+    pub name: String,
+    pub lines: Vec<SourceLine>,
+}
+
+// What runs before the translated program: initial values for pointers such
+// as SP, then a call to the entry function, if any
+#[derive(Default)]
+pub struct Bootstrap {
+    pub registers: Vec<(String, u16)>,
+    pub entry: Option<String>,
+}
+
+// the address of a pointer a bootstrap can set
+pub(crate) fn register_address(register: &str) -> u16 {
+    match register {
+        "SP" => 0,
+        "LCL" => 1,
+        "ARG" => 2,
+        "THIS" => 3,
+        "THAT" => 4,
+        _ => panic!("unknown register: {}", register),
+    }
+}
 
-pub fn bootstrap() -> Vec<String> {
-    code::bootstrap()
+// The state that carries over from one line to the next while translating
+// a program, so labels generated for different lines don't collide
+#[derive(Default)]
+pub struct Translator {
+    call_count: u16,
+    comparison_count: u16,
+    current_function: String,
+    shared_routines: bool,
+    routines: BTreeSet<&'static str>,
+    cache_top: bool,
+    top_in_d: bool,
 }
 
-pub fn translate<S>(line: S, name: S) -> Vec<String>
-where
-    S: Into<String>,
-{
-    let line = line.into();
-    code::to_hack(parser::parse(line.clone()), line, name.into())
+impl Translator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Emits calls, returns and comparisons as jumps to routines that are
+    // included once, which makes the code much smaller and a bit slower
+    pub fn shared_routines(mut self, enabled: bool) -> Self {
+        self.shared_routines = enabled;
+        self
+    }
+
+    // Keeps the top of the stack in D between instructions where it can,
+    // which saves most of the loads and stores around arithmetic
+    pub fn cache_top(mut self, enabled: bool) -> Self {
+        self.cache_top = enabled;
+        self
+    }
+
+    // the shared routines used so far, to be put before the program
+    pub fn runtime(&mut self) -> Vec<String> {
+        code::runtime(self)
+    }
+
+    pub fn bootstrap(&mut self, bootstrap: &Bootstrap) -> Vec<String> {
+        code::bootstrap(self, bootstrap)
+    }
+
+    // stores the top of the stack if it's still in D, after the last line
+    pub fn finish(&mut self) -> Vec<String> {
+        code::spill_top(self)
+    }
+
+    pub fn translate_line(
+        &mut self,
+        line: &SourceLine,
+        name: &str,
+    ) -> Vec<String> {
+        match &line.instruction {
+            Some(instruction) if self.cache_top => code::to_hack_cached(
+                self,
+                instruction.clone(),
+                line.text.as_str(),
+                name,
+            ),
+            Some(instruction) => code::to_hack(
+                self,
+                instruction.clone(),
+                line.text.as_str(),
+                name,
+            ),
+            None => vec![],
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn translate_with(
+        translator: &mut Translator,
+        line: &str,
+        name: &str,
+    ) -> Vec<String> {
+        let line = SourceLine {
+            number: 1,
+            text: line.into(),
+            instruction: Some(parser::parse(line).unwrap()),
+        };
+        translator.translate_line(&line, name)
+    }
+
+    fn translate(line: &str, name: &str) -> Vec<String> {
+        translate_with(&mut Translator::new(), line, name)
+    }
+
     #[test]
     fn test_ignores_inline_comments() {
         assert_eq!(
@@ -187,9 +312,28 @@ mod tests {
     fn test_eq() {
         assert_eq!(
             vec![
-                "// eq", "@SP", "M=M-1", "A=M", "D=M", "@SP", "M=M-1", "A=M",
-                "D=M-D", "@EQUAL", "D;JEQ", "D=0", "@DONE", "0;JMP", "(EQUAL)",
-                "D=-1", "(DONE)", "@SP", "A=M", "M=D", "@SP", "M=M+1"
+                "// eq",
+                "@SP",
+                "M=M-1",
+                "A=M",
+                "D=M",
+                "@SP",
+                "M=M-1",
+                "A=M",
+                "D=M-D",
+                "@EQUAL.0",
+                "D;JEQ",
+                "D=0",
+                "@DONE.0",
+                "0;JMP",
+                "(EQUAL.0)",
+                "D=-1",
+                "(DONE.0)",
+                "@SP",
+                "A=M",
+                "M=D",
+                "@SP",
+                "M=M+1"
             ],
             translate("eq", "Foo")
         );
@@ -199,9 +343,54 @@ mod tests {
     fn test_gt() {
         assert_eq!(
             vec![
-                "// gt", "@SP", "M=M-1", "A=M", "D=M", "@SP", "M=M-1", "A=M",
-                "D=M-D", "@GT", "D;JGT", "D=0", "@DONE", "0;JMP", "(GT)",
-                "D=-1", "(DONE)", "@SP", "A=M", "M=D", "@SP", "M=M+1"
+                "// gt",
+                "@SP",
+                "M=M-1",
+                "A=M",
+                "D=M",
+                "@R13",
+                "M=D",
+                "@SP",
+                "M=M-1",
+                "A=M",
+                "D=M",
+                "@NEGATIVE.0",
+                "D;JLT",
+                "@R13",
+                "D=M",
+                "@SAME_SIGN.0",
+                "D;JGE",
+                "D=1",
+                "@COMPARED.0",
+                "0;JMP",
+                "(NEGATIVE.0)",
+                "@R13",
+                "D=M",
+                "@SAME_SIGN.0",
+                "D;JLT",
+                "D=-1",
+                "@COMPARED.0",
+                "0;JMP",
+                "(SAME_SIGN.0)",
+                "@SP",
+                "A=M",
+                "D=M",
+                "@R13",
+                "D=D-M",
+                "(COMPARED.0)",
+                "@GT.0",
+                "D;JGT",
+                "D=0",
+                "@DONE.0",
+                "0;JMP",
+                "(GT.0)",
+                "D=-1",
+                "(DONE.0)",
+                "@SP",
+                "A=M",
+                "M=D",
+                "@SP",
+                "M=M+1"
             ],
             translate("gt", "Foo")
         );
@@ -211,9 +400,54 @@ mod tests {
     fn test_lt() {
         assert_eq!(
             vec![
-                "// lt", "@SP", "M=M-1", "A=M", "D=M", "@SP", "M=M-1", "A=M",
-                "D=M-D", "@LT", "D;JLT", "D=0", "@DONE", "0;JMP", "(LT)",
-                "D=-1", "(DONE)", "@SP", "A=M", "M=D", "@SP", "M=M+1"
+                "// lt",
+                "@SP",
+                "M=M-1",
+                "A=M",
+                "D=M",
+                "@R13",
+                "M=D",
+                "@SP",
+                "M=M-1",
+                "A=M",
+                "D=M",
+                "@NEGATIVE.0",
+                "D;JLT",
+                "@R13",
+                "D=M",
+                "@SAME_SIGN.0",
+                "D;JGE",
+                "D=1",
+                "@COMPARED.0",
+                "0;JMP",
+                "(NEGATIVE.0)",
+                "@R13",
+                "D=M",
+                "@SAME_SIGN.0",
+                "D;JLT",
+                "D=-1",
+                "@COMPARED.0",
+                "0;JMP",
+                "(SAME_SIGN.0)",
+                "@SP",
+                "A=M",
+                "D=M",
+                "@R13",
+                "D=D-M",
+                "(COMPARED.0)",
+                "@LT.0",
+                "D;JLT",
+                "D=0",
+                "@DONE.0",
+                "0;JMP",
+                "(LT.0)",
+                "D=-1",
+                "(DONE.0)",
+                "@SP",
+                "A=M",
+                "M=D",
+                "@SP",
+                "M=M+1"
             ],
             translate("lt", "Foo")
         );
@@ -381,7 +615,7 @@ mod tests {
             vec![
                 "// call Foo.bar 3",
                 // push retAddr
-                "@Foo.bar$return.0",
+                "@Foo$return.0",
                 "D=A",
                 "@SP",
                 "A=M",
@@ -436,9 +670,259 @@ mod tests {
                 "@Foo.bar",
                 "0;JMP",
                 // return address label"
-                "(Foo.bar$return.0)"
+                "(Foo$return.0)"
             ],
             translate("call Foo.bar 3", "Foo")
         );
     }
+
+    #[test]
+    fn test_comparison_labels_are_unique() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../projects/07/StackArithmetic/StackTest/StackTest.vm"
+        );
+        let contents = std::fs::read_to_string(path).unwrap();
+
+        let mut translator = Translator::new();
+        let mut labels = std::collections::HashSet::new();
+        for line in contents.lines().map(|line| line.trim()) {
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+            for instruction in
+                translate_with(&mut translator, line, "StackTest")
+            {
+                if instruction.starts_with('(') {
+                    assert!(
+                        labels.insert(instruction.clone()),
+                        "{}",
+                        instruction
+                    );
+                }
+            }
+        }
+        assert_eq!(36, labels.len());
+    }
+
+    // pushes any 16-bit value using only non-negative constants
+    fn push_value(value: i16) -> Vec<String> {
+        match value {
+            i16::MIN => vec![
+                "push constant 32767".into(),
+                "neg".into(),
+                "push constant 1".into(),
+                "sub".into(),
+            ],
+            0.. => vec![format!("push constant {}", value)],
+            _ => vec![format!("push constant {}", -value), "neg".into()],
+        }
+    }
+
+    #[test]
+    fn test_comparisons_on_the_emulator() {
+        use hack_emulator::computer::Computer;
+        use hack_emulator::program::Program;
+
+        let values = [
+            i16::MIN,
+            i16::MIN + 1,
+            -20000,
+            -2,
+            -1,
+            0,
+            1,
+            2,
+            20000,
+            i16::MAX - 1,
+            i16::MAX,
+        ];
+        let mut lines = vec![];
+        let mut expected = vec![];
+        for x in values {
+            for y in values {
+                for (operation, result) in
+                    [("eq", x == y), ("gt", x > y), ("lt", x < y)]
+                {
+                    lines.append(&mut push_value(x));
+                    lines.append(&mut push_value(y));
+                    lines.push(operation.into());
+                    expected
+                        .push((format!("{} {} {}", x, operation, y), result));
+                }
+            }
+        }
+        for shared_routines in [false, true] {
+            let mut translator =
+                Translator::new().shared_routines(shared_routines);
+            let mut assembly: Vec<String> = lines
+                .iter()
+                .flat_map(|line| translate_with(&mut translator, line, "Foo"))
+                .collect();
+            assembly.splice(0..0, translator.runtime());
+
+            let mut computer =
+                Computer::new(Program::assemble(&assembly.join("\n")).rom);
+            computer.poke(0, 256);
+            computer.run(10_000_000);
+
+            assert!(computer.halted());
+            assert_eq!(256 + expected.len() as u16, computer.peek(0));
+            for (index, (comparison, result)) in expected.iter().enumerate() {
+                let value = computer.peek(256 + index as u16);
+                assert_eq!(
+                    if *result { 0xffff } else { 0 },
+                    value,
+                    "{}",
+                    comparison
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_optimized_jumps_on_the_emulator() {
+        use hack_emulator::computer::Computer;
+        use hack_emulator::program::Program;
+
+        let values = [i16::MIN, -20000, -1, 0, 1, 20000, i16::MAX];
+        let mut lines = vec![];
+        let mut expected = vec![];
+        for x in values {
+            for y in values {
+                for (operation, result) in
+                    [("eq", x == y), ("gt", x > y), ("lt", x < y)]
+                {
+                    for negated in [false, true] {
+                        // through temp, so the comparison isn't folded
+                        lines.append(&mut push_value(x));
+                        lines.push("pop temp 0".into());
+                        lines.append(&mut push_value(y));
+                        lines.push("pop temp 1".into());
+                        lines.push("push temp 0".into());
+                        lines.push("push temp 1".into());
+                        lines.push(operation.into());
+                        if negated {
+                            lines.push("not".into());
+                        }
+                        let count = expected.len();
+                        lines.extend([
+                            format!("if-goto TAKEN.{}", count),
+                            "push constant 0".into(),
+                            format!("goto DONE.{}", count),
+                            format!("label TAKEN.{}", count),
+                            "push constant 1".into(),
+                            format!("label DONE.{}", count),
+                        ]);
+                        expected.push((
+                            format!("{} {} {} {}", negated, x, operation, y),
+                            result != negated,
+                        ));
+                    }
+                }
+            }
+        }
+
+        let lines = optimize(parse_file("Foo.vm", &lines.join("\n")).unwrap());
+        assert!(lines.iter().all(|line| !matches!(
+            line.instruction,
+            Some(instruction::Instruction::IfGoto(_))
+        )));
+        let mut translator = Translator::new();
+        let assembly: Vec<String> = lines
+            .iter()
+            .flat_map(|line| translator.translate_line(line, "Foo"))
+            .collect();
+
+        let mut computer =
+            Computer::new(Program::assemble(&assembly.join("\n")).rom);
+        computer.poke(0, 256);
+        computer.run(10_000_000);
+
+        assert!(computer.halted());
+        assert_eq!(256 + expected.len() as u16, computer.peek(0));
+        for (index, (case, result)) in expected.iter().enumerate() {
+            let value = computer.peek(256 + index as u16);
+            assert_eq!(*result as u16, value, "{}", case);
+        }
+    }
+
+    #[test]
+    fn test_return_labels_name_the_call_site() {
+        let mut translator = Translator::new();
+        let labels: Vec<String> = [
+            "call Foo.bar 0",
+            "function Foo.main 0",
+            "call Foo.bar 0",
+            "call Foo.bar 0",
+            "function Foo.other 0",
+            "call Foo.bar 0",
+        ]
+        .into_iter()
+        .flat_map(|line| translate_with(&mut translator, line, "Foo"))
+        .filter(|instruction| instruction.contains("$return."))
+        .filter(|instruction| instruction.starts_with('('))
+        .collect();
+
+        assert_eq!(
+            vec![
+                "(Foo$return.0)",
+                "(Foo.main$return.1)",
+                "(Foo.main$return.2)",
+                "(Foo.other$return.3)"
+            ],
+            labels
+        );
+    }
+
+    #[test]
+    fn test_translators_do_not_share_state() {
+        let mut first = Translator::new();
+        let mut second = Translator::new();
+        for line in ["function Foo.bar 0", "eq", "call Foo.bar 0"] {
+            translate_with(&mut first, line, "Foo");
+        }
+
+        assert_eq!(
+            vec!["// label LOOP", "(LOOP)"],
+            translate_with(&mut second, "label LOOP", "Foo")
+        );
+        assert!(translate_with(&mut second, "eq", "Foo")
+            .contains(&"(EQUAL.0)".to_owned()));
+        assert!(translate_with(&mut second, "call Foo.bar 0", "Foo")
+            .contains(&"(Foo$return.0)".to_owned()));
+    }
+
+    #[test]
+    fn test_bootstrap() {
+        let bootstrap = Bootstrap {
+            registers: vec![("SP".into(), 256), ("LCL".into(), 300)],
+            entry: Some("Main.main".into()),
+        };
+        let instructions = Translator::new().bootstrap(&bootstrap);
+
+        assert_eq!(
+            vec![
+                "// SP = 256",
+                "@256",
+                "D=A",
+                "@SP",
+                "M=D",
+                "// LCL = 300",
+                "@300",
+                "D=A",
+                "@LCL",
+                "M=D",
+                "// call Main.main",
+            ],
+            instructions[..11]
+        );
+        assert_eq!(
+            vec!["@Main.main", "0;JMP", "(Sys$return.0)"],
+            instructions[instructions.len() - 3..]
+        );
+        assert!(Translator::new()
+            .bootstrap(&Bootstrap::default())
+            .is_empty());
+    }
 }
@@ -0,0 +1,360 @@
+use crate::translator::call_graph::{call_graph, is_recursive, CallGraph};
+use crate::translator::instruction::Instruction::*;
+use crate::translator::instruction::*;
+use crate::translator::validator::{function_bodies, ValidationError};
+use crate::translator::Source;
+use std::collections::{BTreeMap, HashMap};
+
+// the words a call adds to the stack besides its arguments
+const FRAME_SIZE: u16 = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Depth {
+    // the most words a call to the function can use above its arguments,
+    // counting its frame and everything it calls
+    Bounded(u16),
+    Recursive,
+    // calls a recursive function, directly or not
+    CallsRecursive(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionStack {
+    pub name: String,
+    pub locals: u16,
+    // the most values the function itself has on its stack
+    pub stack: u16,
+    pub depth: Depth,
+}
+
+pub struct StackReport {
+    pub functions: Vec<FunctionStack>,
+    pub errors: Vec<ValidationError>,
+}
+
+// what one function does to the stack
+struct Body<'a> {
+    locals: u16,
+    stack: u16,
+    // the functions it calls, and the stack depth with their arguments on
+    calls: Vec<(&'a str, u16)>,
+}
+
+// Follows every path through each function, checking that the stack never
+// underflows, that paths meeting at a label agree on its depth and that
+// every `return` leaves exactly one value, and then works out how deep the
+// stack can get through each function's calls
+pub fn analyze(sources: &[Source]) -> StackReport {
+    let mut errors = vec![];
+    let mut bodies = BTreeMap::new();
+    for source in sources {
+        for (function, lines) in function_bodies(source) {
+            if let Some(function) = function {
+                let body = analyze_body(source, function, &lines, &mut errors);
+                bodies.insert(function, body);
+            }
+        }
+    }
+
+    let graph = call_graph(sources);
+    let mut depths = HashMap::new();
+    let functions = bodies
+        .iter()
+        .map(|(&name, body)| FunctionStack {
+            name: name.into(),
+            locals: body.locals,
+            stack: body.stack,
+            depth: depth(name, &bodies, &graph, &mut depths),
+        })
+        .collect();
+    StackReport { functions, errors }
+}
+
+// the report, one function a line, and how deep the stack gets from the
+// entry function, as called by the bootstrap
+pub fn describe(report: &StackReport, entry: Option<&str>) -> Vec<String> {
+    let mut lines: Vec<String> = report
+        .functions
+        .iter()
+        .map(|function| {
+            let depth = match &function.depth {
+                Depth::Bounded(words) => format!("{} words", words),
+                Depth::Recursive => "recursive".into(),
+                Depth::CallsRecursive(callee) => {
+                    format!("unbounded, calls recursive {}", callee)
+                }
+            };
+            format!(
+                "{}: {} locals, {} on the stack, {}",
+                function.name, function.locals, function.stack, depth
+            )
+        })
+        .collect();
+
+    let entry = entry.and_then(|entry| {
+        report
+            .functions
+            .iter()
+            .find(|function| function.name == entry)
+    });
+    if let Some(FunctionStack {
+        name,
+        depth: Depth::Bounded(words),
+        ..
+    }) = entry
+    {
+        lines.push(format!("at most {} words of stack from {}", words, name));
+    }
+    lines
+}
+
+fn analyze_body<'a>(
+    source: &Source,
+    function: &str,
+    lines: &[(usize, &'a Instruction)],
+    errors: &mut Vec<ValidationError>,
+) -> Body<'a> {
+    let mut error = |line: usize, message: String| {
+        errors.push(ValidationError {
+            file: source.file.clone(),
+            line,
+            message,
+        })
+    };
+    let labels: HashMap<&str, usize> = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(index, (_, instruction))| match instruction {
+            Label(label) => Some((label.as_str(), index)),
+            _ => None,
+        })
+        .collect();
+    let locals = match lines[0].1 {
+        Function(_, locals) => *locals,
+        _ => 0,
+    };
+
+    let mut body = Body {
+        locals,
+        stack: 0,
+        calls: vec![],
+    };
+    // the depth before each instruction, on the paths seen so far
+    let mut depths: Vec<Option<u16>> = vec![None; lines.len()];
+    let mut pending = vec![(1, 0)];
+    while let Some((index, depth)) = pending.pop() {
+        let Some(&(line, instruction)) = lines.get(index) else {
+            error(
+                lines[index - 1].0,
+                format!("{} runs past its end", function),
+            );
+            continue;
+        };
+        match depths[index] {
+            Some(seen) if seen != depth => {
+                error(
+                    line,
+                    format!(
+                        "paths meet here with stacks of {} and {} values",
+                        seen, depth
+                    ),
+                );
+                continue;
+            }
+            Some(_) => continue,
+            None => depths[index] = Some(depth),
+        }
+
+        let (pops, pushes) = instruction.stack_effect();
+        if depth < pops {
+            error(
+                line,
+                format!("pops {} values from a stack of {}", pops, depth),
+            );
+            continue;
+        }
+        let after = depth - pops + pushes;
+        body.stack = body.stack.max(after);
+
+        let target = |label: &String| labels.get(label.as_str()).copied();
+        match instruction {
+            Return if depth != 1 => error(
+                line,
+                format!("returns with {} values on the stack", depth),
+            ),
+            TailCall(_, arguments) if depth != *arguments => error(
+                line,
+                format!("returns with {} values on the stack", depth + 1),
+            ),
+            Return | TailCall(..) => {}
+            Goto(label) => pending.extend(target(label).map(|i| (i, after))),
+            IfGoto(label) | JumpIf(_, label) => {
+                pending.extend(target(label).map(|i| (i, after)));
+                pending.push((index + 1, after));
+            }
+            _ => pending.push((index + 1, after)),
+        }
+        if let Call(callee, _) | TailCall(callee, _) = instruction {
+            body.calls.push((callee.as_str(), depth));
+        }
+    }
+    body
+}
+
+fn depth<'a>(
+    function: &'a str,
+    bodies: &BTreeMap<&'a str, Body<'a>>,
+    graph: &CallGraph,
+    depths: &mut HashMap<&'a str, Depth>,
+) -> Depth {
+    if let Some(depth) = depths.get(function) {
+        return depth.clone();
+    }
+    if is_recursive(graph, function) {
+        depths.insert(function, Depth::Recursive);
+        return Depth::Recursive;
+    }
+    let Some(body) = bodies.get(function) else {
+        return Depth::Bounded(0);
+    };
+
+    let mut result = Depth::Bounded(FRAME_SIZE + body.locals + body.stack);
+    for &(callee, stack) in &body.calls {
+        result = match (result, depth(callee, bodies, graph, depths)) {
+            (Depth::Bounded(words), Depth::Bounded(callee_words)) => {
+                Depth::Bounded(
+                    words.max(FRAME_SIZE + body.locals + stack + callee_words),
+                )
+            }
+            (Depth::Bounded(_), Depth::Recursive) => {
+                Depth::CallsRecursive(callee.into())
+            }
+            (Depth::Bounded(_), calls_recursive) => calls_recursive,
+            (unbounded, _) => unbounded,
+        };
+    }
+    depths.insert(function, result.clone());
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::translator::parse_file;
+
+    fn source(contents: &str) -> Source {
+        Source {
+            file: "Main.vm".into(),
+            name: "Main".into(),
+            lines: parse_file("Main", contents).unwrap(),
+        }
+    }
+
+    fn messages(contents: &str) -> Vec<String> {
+        analyze(&[source(contents)])
+            .errors
+            .iter()
+            .map(|error| error.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_every_path_returns_one_value() {
+        assert!(messages(
+            "function Main.max 0\npush argument 0\npush argument 1\ngt\n\
+             if-goto FIRST\npush argument 1\nreturn\nlabel FIRST\n\
+             push argument 0\nreturn"
+        )
+        .is_empty());
+        assert_eq!(
+            vec!["Main.vm:4: returns with 2 values on the stack"],
+            messages(
+                "function Main.f 0\npush constant 1\npush constant 2\nreturn"
+            )
+        );
+        assert_eq!(
+            vec!["Main.vm:2: pops 2 values from a stack of 0"],
+            messages("function Main.f 0\nadd\nreturn")
+        );
+        assert_eq!(
+            vec![
+                "Main.vm:7: returns with 2 values on the stack",
+                "Main.vm:5: paths meet here with stacks of 1 and 0 values",
+            ],
+            messages(
+                "function Main.f 0\npush constant 1\nif-goto L\n\
+                 push constant 1\nlabel L\npush constant 0\nreturn"
+            )
+        );
+        assert_eq!(
+            vec!["Main.vm:2: Main.f runs past its end"],
+            messages("function Main.f 0\npush constant 1")
+        );
+    }
+
+    #[test]
+    fn test_depth_through_calls() {
+        let report = analyze(&[source(
+            "function Main.main 1\npush constant 1\npush constant 2\n\
+             call Main.add 2\nreturn\n\
+             function Main.add 0\npush argument 0\npush argument 1\nadd\n\
+             return\n\
+             function Main.fib 0\npush argument 0\ncall Main.fib 1\nreturn\n\
+             function Main.g 0\ncall Main.fib 0\nreturn",
+        )]);
+        let depths: Vec<(&str, Depth)> = report
+            .functions
+            .iter()
+            .map(|function| (function.name.as_str(), function.depth.clone()))
+            .collect();
+
+        assert!(report.errors.is_empty());
+        assert_eq!(
+            vec![
+                ("Main.add", Depth::Bounded(7)),
+                ("Main.fib", Depth::Recursive),
+                ("Main.g", Depth::CallsRecursive("Main.fib".into())),
+                ("Main.main", Depth::Bounded(15)),
+            ],
+            depths
+        );
+        assert_eq!(
+            vec![
+                "Main.add: 0 locals, 2 on the stack, 7 words",
+                "Main.fib: 0 locals, 1 on the stack, recursive",
+                "Main.g: 0 locals, 1 on the stack, unbounded, calls \
+                 recursive Main.fib",
+                "Main.main: 1 locals, 2 on the stack, 15 words",
+                "at most 15 words of stack from Main.main",
+            ],
+            describe(&report, Some("Main.main"))
+        );
+    }
+
+    #[test]
+    fn test_the_os_is_balanced() {
+        let directory = concat!(env!("CARGO_MANIFEST_DIR"), "/../tools/OS");
+        let mut sources = vec![];
+        for entry in std::fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            let contents = std::fs::read_to_string(&path).unwrap();
+            sources.push(Source {
+                file: path.display().to_string(),
+                name: String::new(),
+                lines: parse_file("", &contents).unwrap(),
+            });
+        }
+
+        let report = analyze(&sources);
+
+        assert!(report.errors.is_empty());
+        assert_eq!(
+            Some(&Depth::Recursive),
+            report
+                .functions
+                .iter()
+                .find(|function| function.name == "Math.divide")
+                .map(|function| &function.depth)
+        );
+    }
+}
@@ -0,0 +1,596 @@
+use crate::translator::instruction::Instruction::*;
+use crate::translator::instruction::*;
+use crate::translator::{register_address, Bootstrap, Source};
+use std::collections::{HashMap, HashSet};
+
+// Everything the translated code needs: the RAM, stack helpers that wrap
+// like the Hack CPU does without relying on signed overflow, and a `main`
+// that runs the program like `vm-emulate`
+const PRELUDE: &str = r#"#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#define MASK 0x7FFF
+#define SCREEN 16384
+#define KBD 24576
+#define U(value) ((uint16_t)(value))
+#ifdef __GNUC__
+#define HELPER static __attribute__((unused))
+#else
+#define HELPER static
+#endif
+
+int16_t ram[32768];
+int vm_halted;
+static uint16_t pc;
+
+/* Define VM_HOOKS to connect a display and a keyboard: vm_refresh gets the
+   screen every VM_REFRESH_STEPS steps of main's loop, and vm_key is asked
+   for the key held down whenever the program reads the keyboard. */
+#ifdef VM_HOOKS
+void vm_refresh(const int16_t *screen);
+int16_t vm_key(void);
+#endif
+#ifndef VM_REFRESH_STEPS
+#define VM_REFRESH_STEPS 100000
+#endif
+
+HELPER int16_t wrap(int32_t value) {
+    value &= 0xFFFF;
+    return (int16_t)(value > 32767 ? value - 65536 : value);
+}
+
+HELPER int16_t load(uint16_t address) {
+    address &= MASK;
+#ifdef VM_HOOKS
+    if (address == KBD) ram[KBD] = vm_key();
+#endif
+    return ram[address];
+}
+
+HELPER void store(uint16_t address, int16_t value) {
+    ram[address & MASK] = value;
+}
+
+HELPER void push(int16_t value) {
+    store(U(ram[0]), value);
+    ram[0] = wrap(ram[0] + 1);
+}
+
+HELPER int16_t pop(void) {
+    ram[0] = wrap(ram[0] - 1);
+    return ram[U(ram[0]) & MASK];
+}
+
+HELPER int16_t *top(void) {
+    return &ram[U(ram[0] - 1) & MASK];
+}
+
+HELPER void pop_to(uint16_t address) {
+    int16_t value = pop();
+    store(address, value);
+}
+
+HELPER void op_add(void) { int16_t y = pop(); *top() = wrap(*top() + y); }
+HELPER void op_sub(void) { int16_t y = pop(); *top() = wrap(*top() - y); }
+HELPER void op_neg(void) { *top() = wrap(-*top()); }
+HELPER void op_eq(void) { int16_t y = pop(); *top() = *top() == y ? -1 : 0; }
+HELPER void op_gt(void) { int16_t y = pop(); *top() = *top() > y ? -1 : 0; }
+HELPER void op_lt(void) { int16_t y = pop(); *top() = *top() < y ? -1 : 0; }
+HELPER void op_and(void) { int16_t y = pop(); *top() &= y; }
+HELPER void op_or(void) { int16_t y = pop(); *top() |= y; }
+HELPER void op_not(void) { *top() = ~*top(); }
+HELPER void op_xor(void) { int16_t y = pop(); *top() ^= y; }
+
+HELPER void op_mul(void) {
+    int16_t y = pop();
+    *top() = wrap((int32_t)*top() * y);
+}
+
+HELPER void op_div(void) {
+    int16_t y = pop();
+    *top() = y == 0 ? 0 : wrap((int32_t)*top() / y);
+}
+
+HELPER void op_mod(void) {
+    int16_t y = pop();
+    if (y != 0) *top() = wrap((int32_t)*top() % y);
+}
+
+HELPER void op_shl(void) {
+    uint16_t count = U(pop());
+    *top() = count > 15 ? 0 : wrap((int32_t)U(*top()) << count);
+}
+
+HELPER void op_shr(void) {
+    uint16_t count = U(pop());
+    int16_t x = *top();
+    if (count > 15) count = 15;
+    *top() = x < 0 ? ~(~x >> count) : x >> count;
+}
+
+HELPER void call(uint16_t ret, uint16_t arguments) {
+    push(wrap(ret));
+    push(ram[1]);
+    push(ram[2]);
+    push(ram[3]);
+    push(ram[4]);
+    ram[2] = wrap(ram[0] - 5 - arguments);
+    ram[1] = ram[0];
+}
+
+/* copies the caller's frame and the arguments down to its own arguments,
+   so the callee returns straight to the caller's caller */
+HELPER void tail_call(uint16_t arguments) {
+    uint16_t frame = U(ram[1]), size = arguments + 5, i;
+    uint16_t from, to = U(ram[2]);
+    for (i = 5; i >= 1; i--) push(load(frame - i));
+    from = U(ram[0] - size);
+    for (i = 0; i < size; i++) store(to + i, load(from + i));
+    ram[0] = ram[1] = wrap(to + size);
+}
+
+HELPER uint16_t op_return(void) {
+    uint16_t frame = U(ram[1]);
+    uint16_t ret = U(load(frame - 5));
+    store(U(ram[2]), pop());
+    ram[0] = wrap(ram[2] + 1);
+    ram[4] = load(frame - 1);
+    ram[3] = load(frame - 2);
+    ram[2] = load(frame - 3);
+    ram[1] = load(frame - 4);
+    return ret;
+}
+
+/* each function entry and label is a step, where running can stop and
+   carry on later */
+#define STEP(id) case id: if (steps-- == 0) { pc = id; return 0; }
+"#;
+
+const MAIN: &str = r#"
+#ifndef VM_NO_MAIN
+/* USAGE: ./program [--steps N] [--set ADDRESS=VALUE] [--dump FROM-TO] */
+int main(int argc, char **argv) {
+    long steps = 1000000, left, slice;
+    int i;
+    for (i = 1; i < argc; i++) {
+        if (!strcmp(argv[i], "--steps") && i + 1 < argc) {
+            steps = atol(argv[++i]);
+        } else if (!strcmp(argv[i], "--set") && i + 1 < argc) {
+            char *value = strchr(argv[++i], '=');
+            if (!value) return 2;
+            store(U(atol(argv[i])), wrap(atol(value + 1)));
+        } else if (strcmp(argv[i], "--dump") || i + 1 >= argc) {
+            fprintf(stderr, "unexpected argument %s\n", argv[i]);
+            return 2;
+        } else {
+            i++;
+        }
+    }
+
+    left = steps;
+    do {
+        slice = left < VM_REFRESH_STEPS ? left : VM_REFRESH_STEPS;
+        left -= slice - vm_run(slice);
+#ifdef VM_HOOKS
+        vm_refresh(ram + SCREEN);
+#endif
+    } while (!vm_halted && left > 0);
+    printf("%s after %ld steps\n", vm_halted ? "halted" : "stopped",
+           steps - left);
+
+    for (i = 1; i < argc; i++) {
+        if (!strcmp(argv[i], "--dump")) {
+            char *to = strchr(argv[++i], '-');
+            long address = atol(argv[i]);
+            for (; address <= (to ? atol(to + 1) : address); address++) {
+                printf("RAM[%ld]=%d\n", address, ram[address & MASK]);
+            }
+        } else if (argv[i][0] == '-') {
+            i++;
+        }
+    }
+    return 0;
+}
+#endif
+"#;
+
+// Translates the whole program to a single C file. The VM runs on a 32K
+// array of RAM laid out like the Hack computer's, with statics from 16 in
+// the order the assembler would allocate them, so results match the Hack
+// backend word for word. Calls and returns go through a switch on return
+// sites, and returning anywhere else halts.
+pub fn to_c(sources: &[Source], bootstrap: &Bootstrap) -> String {
+    let mut program = Program::new(sources);
+    let mut code = vec![];
+
+    code.push("    case 0:".to_owned());
+    for (register, value) in &bootstrap.registers {
+        code.push(format!(
+            "        ram[{}] = {};  // {} = {}",
+            register_address(register),
+            *value as i16,
+            register,
+            value
+        ));
+    }
+    if let Some(entry) = &bootstrap.entry {
+        program.targets.insert(entry.clone());
+        code.push(format!("        // call {}", entry));
+        code.push(format!(
+            "        call(1, 0); goto f{};",
+            program.function(entry)
+        ));
+    }
+    code.push("    case 1:".to_owned());
+
+    for source in sources {
+        let mut previous: Option<&Instruction> = None;
+        for line in &source.lines {
+            let Some(instruction) = &line.instruction else {
+                continue;
+            };
+            let text = line.text.split("//").next().unwrap_or("").trim();
+            code.push(format!("        // {}", text));
+            code.append(&mut program.translate(
+                instruction,
+                previous,
+                &source.name,
+            ));
+            previous = Some(instruction);
+        }
+    }
+
+    let mut output = vec![
+        format!(
+            "/* translated from {} .vm files by stack-to-hack */",
+            sources.len()
+        ),
+        PRELUDE.to_owned(),
+        "/* Runs until the program halts or has taken `steps` steps, and \
+         returns\n   the steps left. Calling it again carries on. */"
+            .to_owned(),
+        "long vm_run(long steps) {".to_owned(),
+        "    if (vm_halted) return steps;".to_owned(),
+    ];
+    if program.returns {
+        output.push("dispatch:".to_owned());
+    }
+    output.push("    switch (pc) {".to_owned());
+    output.append(&mut code);
+    output.push("    default:".to_owned());
+    if program.halts {
+        output.push("    halt:".to_owned());
+    }
+    output.append(
+        &mut [
+            "        vm_halted = 1;",
+            "        return steps;",
+            "    }",
+            "}",
+            MAIN,
+        ]
+        .map(String::from)
+        .to_vec(),
+    );
+    output.join("\n")
+}
+
+// The switch cases and C labels: 0 is the bootstrap and 1 the start of the
+// code after it, then functions and labels, then return sites as they're
+// translated
+struct Program {
+    functions: HashMap<String, usize>,
+    labels: HashMap<String, usize>,
+    // the labels and functions something jumps to, which are the only ones
+    // C needs labels for
+    targets: HashSet<String>,
+    // whether anything jumps to `halt`, or returns through `dispatch`
+    halts: bool,
+    returns: bool,
+    statics: HashMap<(String, u16), u16>,
+    current_function: String,
+    next_id: usize,
+}
+
+impl Program {
+    fn new(sources: &[Source]) -> Self {
+        let mut program = Program {
+            functions: HashMap::new(),
+            labels: HashMap::new(),
+            targets: HashSet::new(),
+            halts: false,
+            returns: false,
+            statics: HashMap::new(),
+            current_function: String::new(),
+            next_id: 2,
+        };
+        for source in sources {
+            let mut previous = None;
+            for line in &source.lines {
+                match &line.instruction {
+                    Some(instruction) if instruction.halts(previous) => {}
+                    Some(Goto(label) | IfGoto(label) | JumpIf(_, label)) => {
+                        let label = program.label_name(label);
+                        program.targets.insert(label);
+                    }
+                    Some(Call(name, _) | TailCall(name, _)) => {
+                        program.targets.insert(name.clone());
+                    }
+                    Some(Function(name, _)) => {
+                        program.current_function = name.clone();
+                        let id = program.next_id();
+                        program.functions.insert(name.clone(), id);
+                    }
+                    Some(Label(label)) => {
+                        let id = program.next_id();
+                        let label = program.label_name(label);
+                        program.labels.insert(label, id);
+                    }
+                    _ => {}
+                }
+                if line.instruction.is_some() {
+                    previous = line.instruction.as_ref();
+                }
+            }
+        }
+        program.current_function.clear();
+        program
+    }
+
+    fn next_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    fn function(&self, name: &str) -> usize {
+        *self
+            .functions
+            .get(name)
+            .unwrap_or_else(|| panic!("no function {}", name))
+    }
+
+    // labels are local to the function they're in, as in the Hack code
+    fn label_name(&self, label: &str) -> String {
+        format!("{}${}", self.current_function, label)
+    }
+
+    fn label(&self, label: &str) -> usize {
+        *self
+            .labels
+            .get(&self.label_name(label))
+            .unwrap_or_else(|| panic!("no label {}", label))
+    }
+
+    // the address of a segment entry, as a C expression
+    fn address(&mut self, segment: &Segment, index: u16, name: &str) -> String {
+        match segment {
+            Segment::Local => format!("U(ram[1]) + {}", index),
+            Segment::Argument => format!("U(ram[2]) + {}", index),
+            Segment::This => format!("U(ram[3]) + {}", index),
+            Segment::That => format!("U(ram[4]) + {}", index),
+            Segment::Stack => format!("U(ram[0]) - {}", index),
+            Segment::Pointer => (index + 3).to_string(),
+            Segment::Temp => (index + 5).to_string(),
+            Segment::Static => {
+                let count = self.statics.len() as u16;
+                let address = *self
+                    .statics
+                    .entry((name.to_owned(), index))
+                    .or_insert(16 + count);
+                address.to_string()
+            }
+            Segment::Constant => {
+                panic!("the constant segment has no addresses")
+            }
+        }
+    }
+
+    fn value(&mut self, segment: &Segment, index: u16, name: &str) -> String {
+        match segment {
+            Segment::Constant => (index as i16).to_string(),
+            _ => format!("load({})", self.address(segment, index, name)),
+        }
+    }
+
+    fn translate(
+        &mut self,
+        instruction: &Instruction,
+        previous: Option<&Instruction>,
+        name: &str,
+    ) -> Vec<String> {
+        let statement = |code: String| vec![format!("        {}", code)];
+        let operation =
+            |operation: &str| statement(format!("op_{}();", operation));
+
+        match instruction {
+            Push(segment, index) => statement(format!(
+                "push({});",
+                self.value(segment, *index, name)
+            )),
+            Pop(segment, index) => statement(format!(
+                "pop_to({});",
+                self.address(segment, *index, name)
+            )),
+            Move(from, from_index, to, to_index) => {
+                let value = self.value(from, *from_index, name);
+                let address = self.address(to, *to_index, name);
+                statement(format!("store({}, {});", address, value))
+            }
+            Discard(count) => {
+                statement(format!("ram[0] = wrap(ram[0] - {});", count))
+            }
+            Add => operation("add"),
+            Subtract => operation("sub"),
+            Negate => operation("neg"),
+            Equal => operation("eq"),
+            GreaterThan => operation("gt"),
+            LessThan => operation("lt"),
+            And => operation("and"),
+            Or => operation("or"),
+            Not => operation("not"),
+            Multiply => operation("mul"),
+            Divide => operation("div"),
+            Modulo => operation("mod"),
+            ShiftLeft => operation("shl"),
+            ShiftRight => operation("shr"),
+            Xor => operation("xor"),
+            Label(label) => {
+                let id = self.label(label);
+                if self.targets.contains(&self.label_name(label)) {
+                    vec![format!("    l{}: STEP({})", id, id)]
+                } else {
+                    vec![format!("    STEP({})", id)]
+                }
+            }
+            _ if instruction.halts(previous) => {
+                self.halts = true;
+                statement("goto halt;".into())
+            }
+            Goto(label) => statement(format!("goto l{};", self.label(label))),
+            IfGoto(label) => {
+                statement(format!("if (pop()) goto l{};", self.label(label)))
+            }
+            JumpIf(condition, label) => {
+                let label = self.label(label);
+                let test = match condition {
+                    Condition::NotTrue => {
+                        return statement(format!(
+                            "if (pop() != -1) goto l{};",
+                            label
+                        ))
+                    }
+                    Condition::Equal => "==",
+                    Condition::NotEqual => "!=",
+                    Condition::Greater => ">",
+                    Condition::GreaterOrEqual => ">=",
+                    Condition::Less => "<",
+                    Condition::LessOrEqual => "<=",
+                };
+                statement(format!(
+                    "{{ int16_t y = pop(), x = pop(); if (x {} y) goto l{}; }}",
+                    test, label
+                ))
+            }
+            Function(function, locals) => {
+                self.current_function = function.clone();
+                let id = self.function(function);
+                let mut code = if self.targets.contains(function) {
+                    vec![format!("    f{}: STEP({})", id, id)]
+                } else {
+                    vec![format!("    STEP({})", id)]
+                };
+                if *locals > 0 {
+                    code.push(format!(
+                        "        {{ int i; for (i = 0; i < {}; i++) push(0); }}",
+                        locals
+                    ));
+                }
+                code
+            }
+            Call(function, arguments) => {
+                let ret = self.next_id();
+                vec![
+                    format!(
+                        "        call({}, {}); goto f{};",
+                        ret,
+                        arguments,
+                        self.function(function)
+                    ),
+                    format!("    case {}:", ret),
+                ]
+            }
+            TailCall(function, arguments) => statement(format!(
+                "tail_call({}); goto f{};",
+                arguments,
+                self.function(function)
+            )),
+            Return => {
+                self.returns = true;
+                statement("pc = op_return(); goto dispatch;".into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::translator::parse_file;
+
+    fn source(name: &str, contents: &str) -> Source {
+        Source {
+            file: format!("{}.vm", name),
+            name: name.into(),
+            lines: parse_file(name, contents).unwrap(),
+        }
+    }
+
+    fn lines(sources: &[Source]) -> Vec<String> {
+        to_c(sources, &Bootstrap::default())
+            .lines()
+            .map(|line| line.trim().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_statics_in_the_order_they_are_used() {
+        let lines = lines(&[
+            source("A", "push static 3\npop static 1\npush static 3"),
+            source("B", "push static 3"),
+        ]);
+
+        for line in [
+            "push(load(16));",
+            "pop_to(17);",
+            "push(load(16));",
+            "push(load(18));",
+        ] {
+            assert!(lines.contains(&line.to_owned()), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_labels_and_halting() {
+        let lines = lines(&[source(
+            "Sys",
+            "function Sys.init 0\nlabel LOOP\nlabel UNUSED\n\
+             push constant 1\nif-goto LOOP\nlabel HALT\ngoto HALT",
+        )]);
+        let code = lines.iter().skip_while(|line| *line != "case 1:");
+
+        assert_eq!(
+            vec![
+                "STEP(2)",
+                "l3: STEP(3)",
+                "STEP(4)",
+                "push(1);",
+                "if (pop()) goto l3;",
+                "STEP(5)",
+                "goto halt;",
+                "default:",
+                "halt:",
+            ],
+            code.filter(|line| !line.starts_with("//"))
+                .skip(1)
+                .take(9)
+                .collect::<Vec<&String>>()
+        );
+    }
+
+    #[test]
+    fn test_calls_return_to_their_case() {
+        let lines = lines(&[source(
+            "Main",
+            "function Main.main 0\npush constant 4\ncall Main.double 1\n\
+             return\nfunction Main.double 0\npush argument 0\n\
+             push argument 0\nadd\nreturn",
+        )]);
+
+        assert!(lines.contains(&"call(4, 1); goto f3;".to_owned()));
+        assert!(lines.contains(&"case 4:".to_owned()));
+        assert!(lines.contains(&"f3: STEP(3)".to_owned()));
+        assert!(lines.contains(&"pc = op_return(); goto dispatch;".to_owned()));
+    }
+}
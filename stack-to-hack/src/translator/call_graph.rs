@@ -0,0 +1,201 @@
+use crate::translator::instruction::Instruction::*;
+use crate::translator::instruction::*;
+use crate::translator::Source;
+use std::collections::{BTreeMap, BTreeSet};
+
+// each function defined in the program and the functions it calls
+pub type CallGraph<'a> = BTreeMap<&'a str, BTreeSet<&'a str>>;
+
+pub fn call_graph(sources: &[Source]) -> CallGraph<'_> {
+    let mut graph = CallGraph::new();
+    for source in sources {
+        let mut function = None;
+        for instruction in instructions(source) {
+            match instruction {
+                Function(name, _) => {
+                    graph.entry(name.as_str()).or_default();
+                    function = Some(name.as_str());
+                }
+                Call(callee, _) | TailCall(callee, _) => {
+                    if let Some(function) = function {
+                        graph.entry(function).or_default().insert(callee);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    graph
+}
+
+// The graph in Graphviz's format, with recursive functions in red
+pub fn to_dot(graph: &CallGraph) -> String {
+    let mut lines = vec!["digraph calls {".to_owned()];
+    for (&function, callees) in graph {
+        if is_recursive(graph, function) {
+            lines.push(format!("    \"{}\" [color=red];", function));
+        } else {
+            lines.push(format!("    \"{}\";", function));
+        }
+        for callee in callees {
+            lines.push(format!("    \"{}\" -> \"{}\";", function, callee));
+        }
+    }
+    lines.push("}".into());
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+// whether the function can end up calling itself
+pub fn is_recursive(graph: &CallGraph, function: &str) -> bool {
+    reachable(graph, graph.get(function).into_iter().flatten().copied())
+        .contains(function)
+}
+
+// Drops the functions that can't be reached from the entry function or from
+// code outside of functions, returning what's left of each file and the
+// names of the functions removed, in the order they were defined
+pub fn remove_dead_functions(
+    sources: &[Source],
+    entry: &str,
+) -> (Vec<Source>, Vec<String>) {
+    let graph = call_graph(sources);
+    let top_level_calls = sources.iter().flat_map(|source| {
+        instructions(source)
+            .take_while(|instruction| !matches!(instruction, Function(..)))
+            .filter_map(|instruction| match instruction {
+                Call(callee, _) | TailCall(callee, _) => Some(callee.as_str()),
+                _ => None,
+            })
+    });
+    let live = reachable(&graph, std::iter::once(entry).chain(top_level_calls));
+
+    let mut removed = vec![];
+    let sources = sources
+        .iter()
+        .map(|source| {
+            let mut keep = true;
+            let lines = source
+                .lines
+                .iter()
+                .filter(|line| {
+                    if let Some(Function(name, _)) = &line.instruction {
+                        keep = live.contains(name.as_str());
+                        if !keep {
+                            removed.push(name.clone());
+                        }
+                    }
+                    keep
+                })
+                .cloned()
+                .collect();
+            Source {
+                file: source.file.clone(),
+                name: source.name.clone(),
+                lines,
+            }
+        })
+        .collect();
+    (sources, removed)
+}
+
+// the functions called by the roots, directly or not, and the roots
+fn reachable<'a>(
+    graph: &CallGraph<'a>,
+    roots: impl Iterator<Item = &'a str>,
+) -> BTreeSet<&'a str> {
+    let mut seen = BTreeSet::new();
+    let mut pending: Vec<&str> = roots.collect();
+
+    while let Some(function) = pending.pop() {
+        if seen.insert(function) {
+            pending.extend(graph.get(function).into_iter().flatten());
+        }
+    }
+    seen
+}
+
+fn instructions(source: &Source) -> impl Iterator<Item = &Instruction> {
+    source
+        .lines
+        .iter()
+        .filter_map(|line| line.instruction.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::translator::parse_file;
+
+    fn source(name: &str, contents: &str) -> Source {
+        Source {
+            file: format!("{}.vm", name),
+            name: name.into(),
+            lines: parse_file(name, contents).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_recursion() {
+        let main = source(
+            "Main",
+            "function Main.even 0\ncall Main.odd 1\nreturn\n\
+             function Main.odd 0\ncall Main.even 1\nreturn\n\
+             function Main.main 0\ncall Main.even 1\nreturn",
+        );
+        let graph = call_graph(std::slice::from_ref(&main));
+
+        assert!(is_recursive(&graph, "Main.even"));
+        assert!(is_recursive(&graph, "Main.odd"));
+        assert!(!is_recursive(&graph, "Main.main"));
+        assert_eq!(
+            "digraph calls {\n    \"Main.even\" [color=red];\n    \
+             \"Main.even\" -> \"Main.odd\";\n    \"Main.main\";\n    \
+             \"Main.main\" -> \"Main.even\";\n    \
+             \"Main.odd\" [color=red];\n    \"Main.odd\" -> \"Main.even\";\n}\n",
+            to_dot(&graph)
+        );
+    }
+
+    #[test]
+    fn test_remove_dead_functions() {
+        let sys = source(
+            "Sys",
+            "function Sys.init 0\ncall Main.main 0\nreturn\n\
+             function Sys.error 0\n// never called\ncall Main.main 0\n\
+             return",
+        );
+        let main = source(
+            "Main",
+            "function Main.main 0\npush constant 0\nreturn\n\
+             function Main.unused 0\ncall Main.unused 0\nreturn",
+        );
+
+        let (sources, removed) =
+            remove_dead_functions(&[sys, main], "Sys.init");
+
+        assert_eq!(vec!["Sys.error", "Main.unused"], removed);
+        assert_eq!(
+            vec!["function Sys.init 0", "call Main.main 0", "return"],
+            sources[0]
+                .lines
+                .iter()
+                .map(|line| line.text.as_str())
+                .collect::<Vec<&str>>()
+        );
+        assert_eq!(3, sources[1].lines.len());
+    }
+
+    #[test]
+    fn test_code_outside_functions_is_a_root() {
+        let main = source(
+            "Main",
+            "call Main.helper 0\nfunction Main.helper 0\npush constant 1\n\
+             return\nfunction Main.unused 0\npush constant 1\nreturn",
+        );
+
+        let (_, removed) = remove_dead_functions(&[main], "Sys.init");
+
+        assert_eq!(vec!["Main.unused"], removed);
+    }
+}
@@ -1,22 +1,576 @@
 use crate::translator::instruction::Instruction::*;
 use crate::translator::instruction::*;
-use std::cell::RefCell;
+use crate::translator::{Bootstrap, Translator};
 
-thread_local!( static CALL_COUNT: RefCell<u16> = RefCell::new(0));
-thread_local!( static CURRENT_FUNCTION: RefCell<String> = RefCell::new("".into()));
+// The routines shared by calls, returns and comparisons when optimizing for
+// size. Their labels start with a `$`, which the VM's function names can't.
+const CALL_ROUTINE: &str = "$CALL";
+const RETURN_ROUTINE: &str = "$RETURN";
+const EQUAL_ROUTINE: &str = "$EQUAL";
+const GT_ROUTINE: &str = "$GT";
+const LT_ROUTINE: &str = "$LT";
+// The extended arithmetic is always in routines, which, like the
+// comparisons', pop x and y, push the result and return to the address in
+// D. `div` and `mod` both go to the division.
+const MUL_ROUTINE: &str = "$MUL";
+const DIV_ROUTINE: &str = "$DIV";
+const MOD_ROUTINE: &str = "$MOD";
+const DIVIDE_ROUTINE: &str = "$DIVIDE";
+const SHL_ROUTINE: &str = "$SHL";
+const SHR_ROUTINE: &str = "$SHR";
 
-pub fn bootstrap() -> Vec<String> {
+// The shared routines the translated code used, behind a jump so execution
+// doesn't run into them
+pub fn runtime(translator: &mut Translator) -> Vec<String> {
+    if translator.routines.is_empty() {
+        return vec![];
+    }
+
+    translator.current_function.clear();
     let mut instructions =
-        to_strings(vec!["// SP = 256", "@256", "D=A", "@SP", "M=D"]);
-    instructions.append(&mut to_hack(
-        Call("Sys.init".into(), 0),
-        "call Sys.init",
-        "Sys",
-    ));
+        to_strings(vec!["// shared routines", "@$START", "0;JMP"]);
+    for routine in [
+        CALL_ROUTINE,
+        RETURN_ROUTINE,
+        EQUAL_ROUTINE,
+        GT_ROUTINE,
+        LT_ROUTINE,
+        MUL_ROUTINE,
+        DIV_ROUTINE,
+        MOD_ROUTINE,
+        DIVIDE_ROUTINE,
+        SHL_ROUTINE,
+        SHR_ROUTINE,
+    ] {
+        if !translator.routines.contains(routine) {
+            continue;
+        }
+
+        instructions.push(format!("({})", routine));
+        match routine {
+            CALL_ROUTINE => instructions.append(&mut call_routine()),
+            MUL_ROUTINE => instructions.append(&mut multiply_routine()),
+            // the division tells which result to leave by D
+            DIV_ROUTINE | MOD_ROUTINE => {
+                instructions.append(&mut to_strings(vec![
+                    "@R15",
+                    "M=D",
+                    if routine == DIV_ROUTINE {
+                        "D=0"
+                    } else {
+                        "D=-1"
+                    },
+                    "@$DIVIDE",
+                    "0;JMP",
+                ]))
+            }
+            DIVIDE_ROUTINE => instructions.append(&mut divide_routine()),
+            SHL_ROUTINE => instructions.append(&mut shift_left_routine()),
+            SHR_ROUTINE => instructions.append(&mut shift_right_routine()),
+            RETURN_ROUTINE => {
+                instructions.append(&mut save_lcl_into_r14());
+                instructions.append(&mut save_return_address_into_r15());
+                instructions.append(&mut reset_stack_with_return_value());
+                instructions.append(&mut restore_frame_at_r14());
+                instructions.append(&mut jump_back_to_caller());
+            }
+            _ => {
+                let count = next_comparison(translator);
+                instructions.append(&mut to_strings(vec!["@R15", "M=D"]));
+                if routine == EQUAL_ROUTINE {
+                    binary_operation(&mut instructions, "D=M-D");
+                    instructions.append(&mut comparison(
+                        translator, "EQUAL", "D;JEQ", count,
+                    ));
+                } else {
+                    let (name, jump) = if routine == GT_ROUTINE {
+                        ("GT", "D;JGT")
+                    } else {
+                        ("LT", "D;JLT")
+                    };
+                    instructions
+                        .append(&mut signed_difference(translator, count));
+                    instructions
+                        .append(&mut comparison(translator, name, jump, count));
+                }
+                instructions.append(&mut increment_stack_pointer());
+                instructions.append(&mut jump_back_to_caller());
+            }
+        }
+    }
+    instructions.push("($START)".into());
+    instructions
+}
+
+// pushes the frame of a call, given its size in R13, the function in R14
+// and the return address in D, and jumps to the function
+fn call_routine() -> Vec<String> {
+    let mut instructions = to_strings(vec!["@SP", "A=M", "M=D"]);
+    for pointer in ["LCL", "ARG", "THIS", "THAT"] {
+        instructions.append(&mut vec![
+            format!("@{}", pointer),
+            "D=M".into(),
+            "@SP".into(),
+            "AM=M+1".into(),
+            "M=D".into(),
+        ]);
+    }
+    instructions.append(&mut to_strings(vec![
+        // LCL = SP
+        "@SP", "MD=M+1", "@LCL", "M=D", // ARG = SP - frame size
+        "@R13", "D=D-M", "@ARG", "M=D", "@R14", "A=M", "0;JMP",
+    ]));
+    instructions
+}
+
+// Adds x for each bit of y, doubling x from one bit to the next, until no
+// bits of y are left. A negative y has its top bit set, so both are negated
+// first: that gives the same product and keeps small multipliers fast. The
+// bit being tested is kept above the stack.
+fn multiply_routine() -> Vec<String> {
+    let mut instructions = to_strings(vec![
+        "@R15",
+        "M=D",
+        // if y < 0, x * y = -x * -y
+        "@SP",
+        "A=M-1",
+        "D=M",
+        "@$MUL.POSITIVE",
+        "D;JGE",
+        "@SP",
+        "A=M-1",
+        "M=-M",
+        "A=A-1",
+        "M=-M",
+        "($MUL.POSITIVE)",
+        // R13 = y
+        "@SP",
+        "A=M-1",
+        "D=M",
+        "@R13",
+        "M=D",
+        // R14 = x, and the product where x was starts at 0
+        "@SP",
+        "A=M-1",
+        "A=A-1",
+        "D=M",
+        "M=0",
+        "@R14",
+        "M=D",
+        // the bit above the stack
+        "@SP",
+        "A=M",
+        "M=1",
+        "($MUL.LOOP)",
+        "@R13",
+        "D=M",
+        "@$MUL.DONE",
+        "D;JEQ",
+        // if y has the bit, clear it and add x
+        "@SP",
+        "A=M",
+        "D=M",
+        "@R13",
+        "D=D&M",
+        "@$MUL.NEXT",
+        "D;JEQ",
+        "@R13",
+        "M=M-D",
+        "@R14",
+        "D=M",
+        "@SP",
+        "A=M-1",
+        "A=A-1",
+        "M=D+M",
+        "($MUL.NEXT)",
+        // double x and the bit
+        "@R14",
+        "D=M",
+        "M=D+M",
+        "@SP",
+        "A=M",
+        "D=M",
+        "M=D+M",
+        "@$MUL.LOOP",
+        "0;JMP",
+        "($MUL.DONE)",
+        // drop y
+        "@SP",
+        "M=M-1",
+    ]);
+    instructions.append(&mut jump_back_to_caller());
+    instructions
+}
+
+// Long division of the magnitudes, one bit of x at a time from the top,
+// with R13 = |y| and R14 = what's left of |x|. Above the stack are whether
+// the remainder is wanted, the remainder so far, the quotient so far and
+// the bits left. Both magnitudes can be 32768, so they're compared as
+// unsigned. The quotient is negative when x and y have different signs,
+// the remainder when x is negative.
+fn divide_routine() -> Vec<String> {
+    let mut instructions = to_strings(vec![
+        // whether the remainder is wanted
+        "@SP",
+        "A=M",
+        "M=D",
+        // R13 = |y|
+        "@SP",
+        "A=M-1",
+        "D=M",
+        "@$DIVIDE.BY_ZERO",
+        "D;JEQ",
+        "@$DIVIDE.Y_POSITIVE",
+        "D;JGE",
+        "D=-D",
+        "($DIVIDE.Y_POSITIVE)",
+        "@R13",
+        "M=D",
+        // R14 = |x|
+        "@SP",
+        "A=M-1",
+        "A=A-1",
+        "D=M",
+        "@$DIVIDE.X_POSITIVE",
+        "D;JGE",
+        "D=-D",
+        "($DIVIDE.X_POSITIVE)",
+        "@R14",
+        "M=D",
+        // 16 bits left, and the remainder and quotient start at 0
+        "@16",
+        "D=A",
+        "@SP",
+        "A=M+1",
+        "A=A+1",
+        "A=A+1",
+        "M=D",
+        "@SP",
+        "A=M+1",
+        "M=0",
+        "A=A+1",
+        "M=0",
+        "($DIVIDE.LOOP)",
+        // shift the top bit of R14 into the remainder
+        "@SP",
+        "A=M+1",
+        "D=M",
+        "M=D+M",
+        "@R14",
+        "D=M",
+        "@$DIVIDE.NO_BIT",
+        "D;JGE",
+        "@SP",
+        "A=M+1",
+        "M=M+1",
+        "($DIVIDE.NO_BIT)",
+        "@R14",
+        "D=M",
+        "M=D+M",
+        // double the quotient
+        "@SP",
+        "A=M+1",
+        "A=A+1",
+        "D=M",
+        "M=D+M",
+        // subtract |y| if the remainder is at least |y|, as unsigned
+        "@SP",
+        "A=M+1",
+        "D=M",
+        "@$DIVIDE.HIGH",
+        "D;JLT",
+        "@R13",
+        "D=M",
+        "@$DIVIDE.NEXT",
+        "D;JLT",
+        "@SP",
+        "A=M+1",
+        "D=M",
+        "@R13",
+        "D=D-M",
+        "@$DIVIDE.NEXT",
+        "D;JLT",
+        "@$DIVIDE.SUBTRACT",
+        "0;JMP",
+        "($DIVIDE.HIGH)",
+        "@R13",
+        "D=M",
+        "@$DIVIDE.SUBTRACT",
+        "D;JGE",
+        "@SP",
+        "A=M+1",
+        "D=M",
+        "@R13",
+        "D=D-M",
+        "@$DIVIDE.NEXT",
+        "D;JLT",
+        "($DIVIDE.SUBTRACT)",
+        "@R13",
+        "D=M",
+        "@SP",
+        "A=M+1",
+        "M=M-D",
+        "A=A+1",
+        "M=M+1",
+        "($DIVIDE.NEXT)",
+        "@SP",
+        "A=M+1",
+        "A=A+1",
+        "A=A+1",
+        "MD=M-1",
+        "@$DIVIDE.LOOP",
+        "D;JGT",
+        // R14 = the quotient, negated if the signs differ
+        "@SP",
+        "A=M",
+        "D=M",
+        "@$DIVIDE.REMAINDER",
+        "D;JNE",
+        "@SP",
+        "A=M+1",
+        "A=A+1",
+        "D=M",
+        "@R14",
+        "M=D",
+        "@SP",
+        "A=M-1",
+        "D=M",
+        "@$DIVIDE.Y_NEGATIVE",
+        "D;JLT",
+        "@SP",
+        "A=M-1",
+        "A=A-1",
+        "D=M",
+        "@$DIVIDE.NEGATE",
+        "D;JLT",
+        "@$DIVIDE.STORE",
+        "0;JMP",
+        "($DIVIDE.Y_NEGATIVE)",
+        "@SP",
+        "A=M-1",
+        "A=A-1",
+        "D=M",
+        "@$DIVIDE.STORE",
+        "D;JLT",
+        "($DIVIDE.NEGATE)",
+        "@R14",
+        "M=-M",
+        // replace x and y with R14
+        "($DIVIDE.STORE)",
+        "@R14",
+        "D=M",
+        "@SP",
+        "AM=M-1",
+        "A=A-1",
+        "M=D",
+        "@R15",
+        "A=M",
+        "0;JMP",
+        // R14 = the remainder, negated if x is negative
+        "($DIVIDE.REMAINDER)",
+        "@SP",
+        "A=M+1",
+        "D=M",
+        "@R14",
+        "M=D",
+        "@SP",
+        "A=M-1",
+        "A=A-1",
+        "D=M",
+        "@$DIVIDE.NEGATE",
+        "D;JLT",
+        "@$DIVIDE.STORE",
+        "0;JMP",
+        // x / 0 = 0, and x % 0 = x
+        "($DIVIDE.BY_ZERO)",
+        "@SP",
+        "A=M",
+        "D=M",
+        "@$DIVIDE.KEEP_X",
+        "D;JNE",
+        "@SP",
+        "A=M-1",
+        "A=A-1",
+        "M=0",
+        "($DIVIDE.KEEP_X)",
+        "@SP",
+        "M=M-1",
+    ]);
+    instructions.append(&mut jump_back_to_caller());
+    instructions
+}
+
+// doubles x as many times as the count, if it's under 16
+fn shift_left_routine() -> Vec<String> {
+    let mut instructions = to_strings(vec!["@R15", "M=D"]);
+    instructions.append(&mut pop_count_into_d("$SHL.ALL"));
+    instructions.append(&mut to_strings(vec![
+        "@R13",
+        "M=D",
+        "($SHL.LOOP)",
+        "@R13",
+        "MD=M-1",
+        "@$SHL.DONE",
+        "D;JLT",
+        "@SP",
+        "A=M-1",
+        "D=M",
+        "M=D+M",
+        "@$SHL.LOOP",
+        "0;JMP",
+        // every bit is shifted out
+        "($SHL.ALL)",
+        "@SP",
+        "A=M-1",
+        "M=0",
+        "($SHL.DONE)",
+    ]));
+    instructions.append(&mut jump_back_to_caller());
+    instructions
+}
+
+// Hack can't shift right, so each bit of x from the count up is copied
+// down into the result, kept where the count was, testing with the mask in
+// R13 and setting with the one in R14. Then a negative x fills the bits
+// left at the top.
+fn shift_right_routine() -> Vec<String> {
+    let mut instructions = to_strings(vec!["@R15", "M=D"]);
+    instructions.append(&mut pop_count_into_d("$SHR.ALL"));
+    instructions.append(&mut to_strings(vec![
+        // R13 = 1 shifted left by the count
+        "@R14",
+        "M=D",
+        "@R13",
+        "M=1",
+        "($SHR.MASK)",
+        "@R14",
+        "MD=M-1",
+        "@$SHR.MASKED",
+        "D;JLT",
+        "@R13",
+        "D=M",
+        "M=D+M",
+        "@$SHR.MASK",
+        "0;JMP",
+        "($SHR.MASKED)",
+        "@R14",
+        "M=1",
+        "@SP",
+        "A=M",
+        "M=0",
+        "($SHR.LOOP)",
+        "@R13",
+        "D=M",
+        "@$SHR.FILL",
+        "D;JEQ",
+        // copy the bit if x has it
+        "@SP",
+        "A=M-1",
+        "D=D&M",
+        "@$SHR.NEXT",
+        "D;JEQ",
+        "@R14",
+        "D=M",
+        "@SP",
+        "A=M",
+        "M=D|M",
+        "($SHR.NEXT)",
+        "@R13",
+        "D=M",
+        "M=D+M",
+        "@R14",
+        "D=M",
+        "M=D+M",
+        "@$SHR.LOOP",
+        "0;JMP",
+        // set the bits from R14 up if x is negative
+        "($SHR.FILL)",
+        "@SP",
+        "A=M-1",
+        "D=M",
+        "@$SHR.STORE",
+        "D;JGE",
+        "@R14",
+        "D=M-1",
+        "D=!D",
+        "@SP",
+        "A=M",
+        "M=D|M",
+        "($SHR.STORE)",
+        "@SP",
+        "A=M",
+        "D=M",
+        "A=A-1",
+        "M=D",
+        "@R15",
+        "A=M",
+        "0;JMP",
+        // every bit is shifted out, leaving the sign
+        "($SHR.ALL)",
+        "@SP",
+        "A=M-1",
+        "D=M",
+        "@$SHR.NEGATIVE",
+        "D;JLT",
+        "@SP",
+        "A=M-1",
+        "M=0",
+        "@R15",
+        "A=M",
+        "0;JMP",
+        "($SHR.NEGATIVE)",
+        "@SP",
+        "A=M-1",
+        "M=-1",
+    ]));
+    instructions.append(&mut jump_back_to_caller());
+    instructions
+}
+
+// pops a shift count into D, jumping to `all` if it's negative or over 15
+fn pop_count_into_d(all: &str) -> Vec<String> {
+    vec![
+        "@SP".into(),
+        "AM=M-1".into(),
+        "D=M".into(),
+        format!("@{}", all),
+        "D;JLT".into(),
+        "@16".into(),
+        "D=D-A".into(),
+        format!("@{}", all),
+        "D;JGE".into(),
+        "@16".into(),
+        "D=D+A".into(),
+    ]
+}
+
+pub fn bootstrap(
+    translator: &mut Translator,
+    bootstrap: &Bootstrap,
+) -> Vec<String> {
+    let mut instructions = vec![];
+    for (register, value) in &bootstrap.registers {
+        instructions.append(&mut vec![
+            format!("// {} = {}", register, value),
+            load_address(*value),
+            "D=A".into(),
+            format!("@{}", register),
+            "M=D".into(),
+        ]);
+    }
+    if let Some(entry) = &bootstrap.entry {
+        instructions.append(&mut to_hack(
+            translator,
+            Call(entry.clone(), 0),
+            format!("call {}", entry).as_str(),
+            "Sys",
+        ));
+    }
     instructions
 }
 
 pub fn to_hack<S>(
+    translator: &mut Translator,
     instruction: Instruction,
     line: S,
     module_name: S,
@@ -41,55 +595,45 @@ where
             instructions.append(&mut to_strings(vec!["@SP", "A=M", "M=-M"]));
             instructions.append(&mut increment_stack_pointer());
         }
-        Equal => {
-            binary_operation(&mut instructions, "D=M-D");
+        Equal | GreaterThan | LessThan if translator.shared_routines => {
+            let count = next_comparison(translator);
+            let (routine, name) = match instruction {
+                Equal => (EQUAL_ROUTINE, "EQUAL"),
+                GreaterThan => (GT_ROUTINE, "GT"),
+                _ => (LT_ROUTINE, "LT"),
+            };
+            let return_label =
+                format_label(translator, &format!("{}.{}", name, count));
+            // the routine returns to the address in D
             instructions.append(&mut vec![
-                load_label_address("EQUAL"),
-                "D;JEQ".into(),
-                "D=0".into(),
-                load_label_address("DONE"),
+                format!("@{}", return_label),
+                "D=A".into(),
+                format!("@{}", routine),
                 unconditional_jump(),
-                create_label("EQUAL"),
-                "D=-1".into(),
-                create_label("DONE"),
-                "@SP".into(),
-                "A=M".into(),
-                "M=D".into(),
+                format!("({})", return_label),
             ]);
+            translator.routines.insert(routine);
+        }
+        Equal => {
+            // x - y is zero exactly when x = y, even if it overflows
+            let count = next_comparison(translator);
+            binary_operation(&mut instructions, "D=M-D");
+            instructions
+                .append(&mut comparison(translator, "EQUAL", "D;JEQ", count));
             instructions.append(&mut increment_stack_pointer());
         }
         GreaterThan => {
-            binary_operation(&mut instructions, "D=M-D");
-            instructions.append(&mut vec![
-                load_label_address("GT"),
-                "D;JGT".into(),
-                "D=0".into(),
-                load_label_address("DONE"),
-                unconditional_jump(),
-                create_label("GT"),
-                "D=-1".into(),
-                create_label("DONE"),
-                "@SP".into(),
-                "A=M".into(),
-                "M=D".into(),
-            ]);
+            let count = next_comparison(translator);
+            instructions.append(&mut signed_difference(translator, count));
+            instructions
+                .append(&mut comparison(translator, "GT", "D;JGT", count));
             instructions.append(&mut increment_stack_pointer());
         }
         LessThan => {
-            binary_operation(&mut instructions, "D=M-D");
-            instructions.append(&mut vec![
-                load_label_address("LT"),
-                "D;JLT".into(),
-                "D=0".into(),
-                load_label_address("DONE"),
-                unconditional_jump(),
-                create_label("LT"),
-                "D=-1".into(),
-                create_label("DONE"),
-                "@SP".into(),
-                "A=M".into(),
-                "M=D".into(),
-            ]);
+            let count = next_comparison(translator);
+            instructions.append(&mut signed_difference(translator, count));
+            instructions
+                .append(&mut comparison(translator, "LT", "D;JLT", count));
             instructions.append(&mut increment_stack_pointer());
         }
         And => {
@@ -105,6 +649,35 @@ where
             instructions.append(&mut to_strings(vec!["@SP", "A=M", "M=!M"]));
             instructions.append(&mut increment_stack_pointer());
         }
+        // x ^ y = (x | y) & !(x & y)
+        Xor => instructions.append(&mut to_strings(vec![
+            "@SP", "AM=M-1", "D=M", "@R13", "M=D", "@SP", "A=M-1", "D=D|M",
+            "@R14", "M=D", "@R13", "D=M", "@SP", "A=M-1", "D=D&M", "D=!D",
+            "@R14", "D=D&M", "@SP", "A=M-1", "M=D",
+        ])),
+        Multiply | Divide | Modulo | ShiftLeft | ShiftRight => {
+            let (routine, name) = match instruction {
+                Multiply => (MUL_ROUTINE, "MUL"),
+                Divide => (DIV_ROUTINE, "DIV"),
+                Modulo => (MOD_ROUTINE, "MOD"),
+                ShiftLeft => (SHL_ROUTINE, "SHL"),
+                _ => (SHR_ROUTINE, "SHR"),
+            };
+            let count = next_comparison(translator);
+            let return_label =
+                format_label(translator, &format!("{}.{}", name, count));
+            instructions.append(&mut vec![
+                format!("@{}", return_label),
+                "D=A".into(),
+                format!("@{}", routine),
+                unconditional_jump(),
+                format!("({})", return_label),
+            ]);
+            translator.routines.insert(routine);
+            if matches!(instruction, Divide | Modulo) {
+                translator.routines.insert(DIVIDE_ROUTINE);
+            }
+        }
         Push(segment, offset) => {
             instructions.append(&mut load_d_from_segment(
                 segment,
@@ -121,24 +694,144 @@ where
             ));
             instructions.append(&mut pop_stack_into_r13_pointer());
         }
-        Label(label) => {
-            instructions.append(&mut vec![create_label(label.as_ref())])
-        }
+        Label(label) => instructions
+            .append(&mut vec![create_label(translator, label.as_ref())]),
         Goto(label) => instructions.append(&mut vec![
-            load_label_address(label.as_ref()),
+            load_label_address(translator, label.as_ref()),
             unconditional_jump(),
         ]),
         IfGoto(label) => {
             instructions.append(&mut pop_stack_into_d());
             instructions.append(&mut vec![
-                load_label_address(label.as_ref()),
+                load_label_address(translator, label.as_ref()),
                 jump_if_d_is_not_zero(),
             ]);
         }
+        JumpIf(condition, label) => {
+            let jump = match condition {
+                Condition::Equal => "D;JEQ",
+                Condition::NotTrue | Condition::NotEqual => "D;JNE",
+                Condition::Greater => "D;JGT",
+                Condition::GreaterOrEqual => "D;JGE",
+                Condition::Less => "D;JLT",
+                Condition::LessOrEqual => "D;JLE",
+            };
+            match condition {
+                Condition::NotTrue => {
+                    instructions.append(&mut pop_stack_into_d());
+                    instructions.push("D=D+1".into());
+                }
+                Condition::Equal | Condition::NotEqual => {
+                    binary_operation(&mut instructions, "D=M-D")
+                }
+                _ => {
+                    let count = next_comparison(translator);
+                    instructions
+                        .append(&mut signed_difference(translator, count));
+                }
+            }
+            instructions.append(&mut vec![
+                load_label_address(translator, label.as_ref()),
+                jump.into(),
+            ]);
+        }
+        Move(from, from_offset, to, to_offset) => {
+            let module_name = module_name.as_ref();
+            match to {
+                Segment::Static | Segment::Temp | Segment::Pointer => {
+                    instructions.append(&mut load_d_without_r13(
+                        from,
+                        from_offset,
+                        module_name,
+                    ));
+                    instructions.push(fixed_address(
+                        &to,
+                        to_offset,
+                        module_name,
+                    ));
+                }
+                _ => {
+                    instructions.append(&mut calculate_address_into_r13(
+                        to,
+                        to_offset,
+                        module_name,
+                    ));
+                    instructions.append(&mut load_d_without_r13(
+                        from,
+                        from_offset,
+                        module_name,
+                    ));
+                    instructions.append(&mut to_strings(vec!["@R13", "A=M"]));
+                }
+            }
+            instructions.push("M=D".into());
+        }
+        Discard(count) => {
+            instructions.append(&mut vec![
+                load_address(count),
+                "D=A".into(),
+                "@SP".into(),
+                "M=M-D".into(),
+            ]);
+        }
+        TailCall(name, arity) => {
+            let copy_label =
+                format_call_label(translator, module_name.as_ref(), "tail");
+            // the size of the arguments and the frame together
+            let size = arity + 5;
+
+            // push the caller's frame above the arguments
+            for offset in (1..=5).rev() {
+                instructions.append(&mut vec![
+                    "@LCL".into(),
+                    "D=M".into(),
+                    load_address(offset),
+                    "A=D-A".into(),
+                    "D=M".into(),
+                ]);
+                instructions.append(&mut push_d_onto_stack());
+            }
+            // then copy both down to ARG, which they can only overlap from
+            // above, one word at a time from the bottom
+            instructions.append(&mut vec![
+                "@SP".into(),
+                "D=M".into(),
+                load_address(size),
+                "D=D-A".into(),
+                "@R14".into(),
+                "M=D".into(),
+                "@ARG".into(),
+                "D=M".into(),
+                "@R13".into(),
+                "M=D".into(),
+                load_address(size),
+                "D=A".into(),
+                "@R15".into(),
+                "M=D".into(),
+                create_function_label(&copy_label),
+            ]);
+            instructions.append(&mut to_strings(vec![
+                "@R14", "A=M", "D=M", "@R13", "A=M", "M=D", "@R14", "M=M+1",
+                "@R13", "M=M+1", "@R15", "MD=M-1",
+            ]));
+            instructions.append(&mut vec![
+                format!("@{}", copy_label),
+                "D;JGT".into(),
+                // ARG stays, and the callee's locals start after the copy
+                "@R13".into(),
+                "D=M".into(),
+                "@SP".into(),
+                "M=D".into(),
+                "@LCL".into(),
+                "M=D".into(),
+                format!("@{}", name),
+                unconditional_jump(),
+            ]);
+
+            translator.call_count += 1;
+        }
         Function(name, arity) => {
-            CURRENT_FUNCTION.with(|function| {
-                *function.borrow_mut() = name.clone();
-            });
+            translator.current_function = name.clone();
             instructions
                 .append(&mut vec![create_function_label(name.as_ref())]);
             for _ in 1..=arity {
@@ -150,13 +843,38 @@ where
                 instructions.append(&mut increment_stack_pointer());
             }
         }
+        Call(name, arity) if translator.shared_routines => {
+            let return_label =
+                format_return_label(translator, module_name.as_ref());
+            // the routine takes the frame size in R13, the function in R14
+            // and the return address in D
+            instructions.append(&mut vec![
+                format!("@{}", arity + 5),
+                "D=A".into(),
+                "@R13".into(),
+                "M=D".into(),
+                format!("@{}", name),
+                "D=A".into(),
+                "@R14".into(),
+                "M=D".into(),
+                format!("@{}", return_label),
+                "D=A".into(),
+                format!("@{}", CALL_ROUTINE),
+                unconditional_jump(),
+                create_function_label(&return_label),
+            ]);
+            translator.routines.insert(CALL_ROUTINE);
+            translator.call_count += 1;
+        }
         Call(name, arity) => {
-            let return_label = format_function_return_label(name.as_ref());
+            let return_label =
+                format_return_label(translator, module_name.as_ref());
             // five values: return location, LCL, ARG, THIS, THAT
             let stack_size = arity + 5;
 
             // push retAddr
-            instructions.append(&mut push_label_onto_stack(return_label));
+            instructions
+                .append(&mut push_label_onto_stack(return_label.clone()));
             // push LCL
             instructions.append(&mut push_pointer_onto_stack("LCL".into()));
             // push ARG
@@ -182,11 +900,16 @@ where
                 .append(&mut vec![format!("@{}", name), "0;JMP".into()]);
             // return address label
             instructions
-                .append(&mut vec![create_function_return_label(name.as_ref())]);
+                .append(&mut vec![create_function_label(&return_label)]);
 
-            CALL_COUNT.with(|call_count| {
-                *call_count.borrow_mut() += 1;
-            });
+            translator.call_count += 1;
+        }
+        Return if translator.shared_routines => {
+            instructions.append(&mut vec![
+                format!("@{}", RETURN_ROUTINE),
+                unconditional_jump(),
+            ]);
+            translator.routines.insert(RETURN_ROUTINE);
         }
         Return => {
             instructions.append(&mut save_lcl_into_r14());
@@ -200,31 +923,287 @@ where
     instructions
 }
 
-fn create_label(name: &str) -> String {
-    format!("({})", format_label(name))
+// The code generator that keeps the top of the stack in D instead of
+// memory while it can. With `translator.top_in_d` set, the stack is the
+// values below SP followed by D. Each instruction either works on D
+// directly or spills it first and falls back to `to_hack`; the top is
+// always spilled at labels, jumps, calls and returns, so every path into a
+// label agrees on where it is.
+pub fn to_hack_cached<S>(
+    translator: &mut Translator,
+    instruction: Instruction,
+    line: S,
+    module_name: S,
+) -> Vec<String>
+where
+    S: AsRef<str>,
+{
+    let mut instructions = vec![format!("// {}", line.as_ref())];
+
+    match instruction {
+        Push(segment, offset) => {
+            instructions.append(&mut spill_top(translator));
+            instructions.append(&mut load_d_from_segment(
+                segment,
+                offset,
+                module_name.as_ref(),
+            ));
+            translator.top_in_d = true;
+        }
+        Pop(segment, offset) if segment != Segment::Stack => {
+            instructions.append(&mut fill_top(translator));
+            instructions.append(&mut store_d_into_segment(
+                segment,
+                offset,
+                module_name.as_ref(),
+            ));
+            translator.top_in_d = false;
+        }
+        Add | Subtract | And | Or => {
+            let operation = match instruction {
+                Add => "D=D+M",
+                Subtract => "D=M-D",
+                And => "D=D&M",
+                _ => "D=D|M",
+            };
+            instructions.append(&mut fill_top(translator));
+            instructions
+                .append(&mut to_strings(vec!["@SP", "AM=M-1", operation]));
+        }
+        Xor => {
+            instructions.append(&mut fill_top(translator));
+            instructions.append(&mut to_strings(vec![
+                "@R13", "M=D", "@SP", "AM=M-1", "D=D|M", "@R14", "M=D", "@R13",
+                "D=M", "@SP", "A=M", "D=D&M", "D=!D", "@R14", "D=D&M",
+            ]));
+        }
+        Negate | Not => {
+            instructions.append(&mut fill_top(translator));
+            instructions.push(
+                if instruction == Negate {
+                    "D=-D"
+                } else {
+                    "D=!D"
+                }
+                .into(),
+            );
+        }
+        Equal if !translator.shared_routines => {
+            instructions.append(&mut fill_top(translator));
+            let count = next_comparison(translator);
+            instructions
+                .append(&mut to_strings(vec!["@SP", "AM=M-1", "D=M-D"]));
+            instructions
+                .append(&mut truth_value(translator, "EQUAL", "D;JEQ", count));
+        }
+        GreaterThan | LessThan if !translator.shared_routines => {
+            let (name, jump) = if instruction == GreaterThan {
+                ("GT", "D;JGT")
+            } else {
+                ("LT", "D;JLT")
+            };
+            instructions.append(&mut fill_top(translator));
+            let count = next_comparison(translator);
+            instructions
+                .append(&mut signed_difference_with_y_in_d(translator, count));
+            instructions
+                .append(&mut truth_value(translator, name, jump, count));
+        }
+        IfGoto(label) => {
+            instructions.append(&mut fill_top(translator));
+            instructions.append(&mut vec![
+                load_label_address(translator, label.as_ref()),
+                jump_if_d_is_not_zero(),
+            ]);
+            translator.top_in_d = false;
+        }
+        _ => {
+            let mut spill = spill_top(translator);
+            instructions = to_hack(translator, instruction, line, module_name);
+            instructions.splice(1..1, spill.drain(..));
+        }
+    }
+
+    instructions
+}
+
+// moves the top of the stack from D to memory, if it's in D
+pub fn spill_top(translator: &mut Translator) -> Vec<String> {
+    if !translator.top_in_d {
+        return vec![];
+    }
+    translator.top_in_d = false;
+    to_strings(vec!["@SP", "AM=M+1", "A=A-1", "M=D"])
+}
+
+// pops the top of the stack into D, unless it's there already
+fn fill_top(translator: &mut Translator) -> Vec<String> {
+    if translator.top_in_d {
+        return vec![];
+    }
+    translator.top_in_d = true;
+    pop_stack_into_d()
+}
+
+// Stores D without going through the stack. Small offsets are reached by
+// incrementing A, larger ones need the value kept in R14 while the address
+// is calculated.
+fn store_d_into_segment(
+    segment: Segment,
+    offset: u16,
+    module_name: &str,
+) -> Vec<String> {
+    use Segment::*;
+
+    match segment {
+        Local | Argument | This | That if offset < 8 => {
+            let mut instructions =
+                vec![load_segment_base(segment), "A=M".into()];
+            instructions.extend((0..offset).map(|_| "A=A+1".into()));
+            instructions.push("M=D".into());
+            instructions
+        }
+        Local | Argument | This | That => {
+            let mut instructions = to_strings(vec!["@R14", "M=D"]);
+            instructions.append(&mut calculate_address_into_r13(
+                segment,
+                offset,
+                module_name,
+            ));
+            instructions.append(&mut to_strings(vec![
+                "@R14", "D=M", "@R13", "A=M", "M=D",
+            ]));
+            instructions
+        }
+        _ => vec![fixed_address(&segment, offset, module_name), "M=D".into()],
+    }
+}
+
+fn create_label(translator: &Translator, name: &str) -> String {
+    format!("({})", format_label(translator, name))
 }
 
 fn create_function_label(name: &str) -> String {
     format!("({})", name)
 }
 
-fn create_function_return_label(name: &str) -> String {
-    format!("({})", format_function_return_label(name))
+fn format_label(translator: &Translator, name: &str) -> String {
+    if translator.current_function.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}${}", translator.current_function, name)
+    }
 }
 
-fn format_label(name: &str) -> String {
-    CURRENT_FUNCTION.with(|function| {
-        if function.borrow().is_empty() {
-            name.to_owned()
-        } else {
-            format!("{}${}", function.borrow(), name)
-        }
-    })
+fn format_return_label(translator: &Translator, module_name: &str) -> String {
+    format_call_label(translator, module_name, "return")
+}
+
+// named after the calling function, or the module outside of functions, and
+// numbered by call site
+fn format_call_label(
+    translator: &Translator,
+    module_name: &str,
+    kind: &str,
+) -> String {
+    let caller = if translator.current_function.is_empty() {
+        module_name
+    } else {
+        &translator.current_function
+    };
+    format!("{}${}.{}", caller, kind, translator.call_count)
 }
 
-fn format_function_return_label(name: &str) -> String {
-    CALL_COUNT
-        .with(|call_count| format!("{}$return.{}", name, call_count.borrow()))
+fn next_comparison(translator: &mut Translator) -> u16 {
+    translator.comparison_count += 1;
+    translator.comparison_count - 1
+}
+
+// Pops y and x and leaves a value with the sign of x - y in D, with SP
+// pointing at x
+fn signed_difference(translator: &Translator, count: u16) -> Vec<String> {
+    let mut instructions = pop_stack_into_d();
+    instructions.append(&mut signed_difference_with_y_in_d(translator, count));
+    instructions
+}
+
+// The same once y has been popped into D. x - y overflows when the operands
+// have different signs and are far apart, so it is only computed when the
+// signs are the same; otherwise the sign of x decides.
+fn signed_difference_with_y_in_d(
+    translator: &Translator,
+    count: u16,
+) -> Vec<String> {
+    let negative = format!("NEGATIVE.{}", count);
+    let same_sign = format!("SAME_SIGN.{}", count);
+    let compared = format!("COMPARED.{}", count);
+
+    let mut instructions = to_strings(vec!["@R13", "M=D"]);
+    instructions.append(&mut pop_stack_address_into_a());
+    instructions.append(&mut vec![
+        "D=M".into(),
+        load_label_address(translator, &negative),
+        "D;JLT".into(),
+        // x >= 0, so x > y if y < 0
+        "@R13".into(),
+        "D=M".into(),
+        load_label_address(translator, &same_sign),
+        "D;JGE".into(),
+        "D=1".into(),
+        load_label_address(translator, &compared),
+        unconditional_jump(),
+        // x < 0, so x < y if y >= 0
+        create_label(translator, &negative),
+        "@R13".into(),
+        "D=M".into(),
+        load_label_address(translator, &same_sign),
+        "D;JLT".into(),
+        "D=-1".into(),
+        load_label_address(translator, &compared),
+        unconditional_jump(),
+        create_label(translator, &same_sign),
+        "@SP".into(),
+        "A=M".into(),
+        "D=M".into(),
+        "@R13".into(),
+        "D=D-M".into(),
+        create_label(translator, &compared),
+    ]);
+    instructions
+}
+
+// pushes -1 if `jump` is taken on D, 0 otherwise
+fn comparison(
+    translator: &Translator,
+    name: &str,
+    jump: &str,
+    count: u16,
+) -> Vec<String> {
+    let mut instructions = truth_value(translator, name, jump, count);
+    instructions.append(&mut to_strings(vec!["@SP", "A=M", "M=D"]));
+    instructions
+}
+
+// sets D to -1 if `jump` is taken on D, 0 otherwise
+fn truth_value(
+    translator: &Translator,
+    name: &str,
+    jump: &str,
+    count: u16,
+) -> Vec<String> {
+    let name = format!("{}.{}", name, count);
+    let done = format!("DONE.{}", count);
+
+    vec![
+        load_label_address(translator, &name),
+        jump.into(),
+        "D=0".into(),
+        load_label_address(translator, &done),
+        unconditional_jump(),
+        create_label(translator, &name),
+        "D=-1".into(),
+        create_label(translator, &done),
+    ]
 }
 
 fn binary_operation<S>(instructions: &mut Vec<String>, operation: S)
@@ -279,7 +1258,7 @@ where
     use Segment::*;
 
     match segment {
-        Constant => vec![load_address(offset), "D=A".into()],
+        Constant => load_constant_into_d(offset),
         Temp => vec![format!("@R{}", offset + 5), "D=M".into()],
         Pointer => match offset {
             0 => vec!["@THIS".into(), "D=M".into()],
@@ -300,6 +1279,54 @@ where
             load_static_variable_address(offset, module_name.as_ref()),
             "D=M".into(),
         ],
+        Stack => vec![
+            "@SP".into(),
+            "D=M".into(),
+            load_address(offset),
+            "A=D-A".into(),
+            "D=M".into(),
+        ],
+    }
+}
+
+// Folded constants can be any 16-bit value, and those with the top bit set
+// don't fit in an A-instruction, but their complement does
+fn load_constant_into_d(value: u16) -> Vec<String> {
+    if value > 32767 {
+        vec![load_address(!value), "D=!A".into()]
+    } else {
+        vec![load_address(value), "D=A".into()]
+    }
+}
+
+// like `load_d_from_segment`, but leaving R13 alone
+fn load_d_without_r13(
+    segment: Segment,
+    offset: u16,
+    module_name: &str,
+) -> Vec<String> {
+    use Segment::*;
+
+    match segment {
+        Local | Argument | This | That => vec![
+            load_segment_base(segment),
+            "D=M".into(),
+            load_address(offset),
+            "A=D+A".into(),
+            "D=M".into(),
+        ],
+        _ => load_d_from_segment(segment, offset, module_name),
+    }
+}
+
+// loads the address of a segment entry that doesn't depend on a pointer
+fn fixed_address(segment: &Segment, offset: u16, module_name: &str) -> String {
+    match segment {
+        Segment::Temp => format!("@R{}", offset + 5),
+        Segment::Pointer if offset == 0 => "@THIS".into(),
+        Segment::Pointer => "@THAT".into(),
+        Segment::Static => load_static_variable_address(offset, module_name),
+        _ => panic!("segment has no fixed address: {:?}", segment),
     }
 }
 
@@ -359,6 +1386,14 @@ where
             "@R13".into(),
             "M=D".into(),
         ],
+        Stack => vec![
+            "@SP".into(),
+            "D=M".into(),
+            load_address(offset),
+            "D=D-A".into(),
+            "@R13".into(),
+            "M=D".into(),
+        ],
         _ => vec![
             load_segment_base(segment),
             "D=M".into(),
@@ -428,8 +1463,8 @@ fn load_static_variable_address(number: u16, module_name: &str) -> String {
     format!("@{}.{}", module_name, number)
 }
 
-fn load_label_address(name: &str) -> String {
-    format!("@{}", format_label(name))
+fn load_label_address(translator: &Translator, name: &str) -> String {
+    format!("@{}", format_label(translator, name))
 }
 
 fn to_strings<S>(strings: Vec<S>) -> Vec<String>
@@ -0,0 +1,325 @@
+use crate::translator::call_graph::{call_graph, is_recursive};
+use crate::translator::instruction::Instruction::*;
+use crate::translator::instruction::*;
+use crate::translator::{Source, SourceLine};
+use std::collections::HashMap;
+
+// functions with more instructions than this are called as usual
+const MAX_INSTRUCTIONS: usize = 12;
+
+// a function that can be inlined, and what its body needs from a call site
+struct Inlinable {
+    module: String,
+    locals: u16,
+    arguments: u16,
+    body: Vec<SourceLine>,
+    uses_statics: bool,
+    sets_pointers: bool,
+}
+
+// Replaces calls to small functions with their bodies. Only straight-line
+// functions that end in their only `return` and can't call themselves are
+// inlined; their arguments and locals are reached through the stack
+// segment, as the caller's frame stays in place, and THIS and THAT are put
+// back afterwards if the function changes them. Functions that use statics
+// are only inlined into their own file.
+pub fn inline(sources: &[Source]) -> Vec<Source> {
+    let functions = inlinable_functions(sources);
+
+    sources
+        .iter()
+        .map(|source| {
+            let mut lines = vec![];
+            for line in &source.lines {
+                match &line.instruction {
+                    Some(Call(name, arguments)) => match functions.get(name) {
+                        Some(function)
+                            if *arguments >= function.arguments
+                                && (!function.uses_statics
+                                    || function.module == source.name) =>
+                        {
+                            lines
+                                .append(&mut expand(line, function, *arguments))
+                        }
+                        _ => lines.push(line.clone()),
+                    },
+                    _ => lines.push(line.clone()),
+                }
+            }
+            Source {
+                file: source.file.clone(),
+                name: source.name.clone(),
+                lines,
+            }
+        })
+        .collect()
+}
+
+fn inlinable_functions(sources: &[Source]) -> HashMap<String, Inlinable> {
+    let mut bodies = vec![];
+    for source in sources {
+        for line in &source.lines {
+            match &line.instruction {
+                Some(Function(name, locals)) => {
+                    bodies.push((source, name, *locals, vec![]))
+                }
+                Some(_) => {
+                    if let Some((_, _, _, body)) = bodies.last_mut() {
+                        body.push(line);
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+
+    let graph = call_graph(sources);
+
+    let mut functions = HashMap::new();
+    for (source, name, locals, mut body) in bodies {
+        if body.len() > MAX_INSTRUCTIONS + 1
+            || body.pop().and_then(|line| line.instruction.as_ref())
+                != Some(&Return)
+            || is_recursive(&graph, name)
+        {
+            continue;
+        }
+        let instructions: Vec<&Instruction> = body
+            .iter()
+            .filter_map(|line| line.instruction.as_ref())
+            .collect();
+        if !is_straight_line(&instructions, locals) {
+            continue;
+        }
+
+        let arguments = instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Push(Segment::Argument, index)
+                | Pop(Segment::Argument, index) => Some(index + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        let uses_statics = instructions.iter().any(|instruction| {
+            matches!(
+                instruction,
+                Push(Segment::Static, _) | Pop(Segment::Static, _)
+            )
+        });
+        let sets_pointers = instructions
+            .iter()
+            .any(|instruction| matches!(instruction, Pop(Segment::Pointer, _)));
+
+        functions.insert(
+            name.clone(),
+            Inlinable {
+                module: source.name.clone(),
+                locals,
+                arguments,
+                body: body.into_iter().cloned().collect(),
+                uses_statics,
+                sets_pointers,
+            },
+        );
+    }
+    functions
+}
+
+// No jumps, no locals past the declared ones, and never popping more than
+// it pushed, so the depth of the stack is known at every instruction, with
+// a value left to return at the end
+fn is_straight_line(instructions: &[&Instruction], locals: u16) -> bool {
+    let mut depth = 0;
+    for instruction in instructions {
+        match instruction {
+            Label(_) | Goto(_) | IfGoto(_) | Return => return false,
+            Push(Segment::Local, index) | Pop(Segment::Local, index)
+                if *index >= locals =>
+            {
+                return false
+            }
+            _ => {}
+        }
+        let (pops, pushes) = instruction.stack_effect();
+        if depth < pops {
+            return false;
+        }
+        depth = depth - pops + pushes;
+    }
+    depth > 0
+}
+
+// The body in place of the call. The arguments are already on the stack,
+// so the locals and the saved pointers go on top of them; the returned
+// value ends up where the first argument was, just as after a call.
+fn expand(
+    call: &SourceLine,
+    function: &Inlinable,
+    arguments: u16,
+) -> Vec<SourceLine> {
+    let line = |instruction: Instruction, text: &str| SourceLine {
+        number: call.number,
+        text: format!("{} / {}", call.text, text),
+        instruction: Some(instruction),
+    };
+    let saved = if function.sets_pointers { 2 } else { 0 };
+    let mut depth = arguments + function.locals + saved;
+    let mut lines = vec![];
+
+    for _ in 0..function.locals {
+        lines.push(line(Push(Segment::Constant, 0), "local"));
+    }
+    if function.sets_pointers {
+        lines.push(line(Push(Segment::Pointer, 0), "save pointer 0"));
+        lines.push(line(Push(Segment::Pointer, 1), "save pointer 1"));
+    }
+
+    for body_line in &function.body {
+        let instruction = match body_line.instruction.clone().unwrap() {
+            Push(Segment::Argument, index) => {
+                Push(Segment::Stack, depth - index)
+            }
+            Push(Segment::Local, index) => {
+                Push(Segment::Stack, depth - arguments - index)
+            }
+            Pop(Segment::Argument, index) => Pop(Segment::Stack, depth - index),
+            Pop(Segment::Local, index) => {
+                Pop(Segment::Stack, depth - arguments - index)
+            }
+            instruction => instruction,
+        };
+        let (pops, pushes) = instruction.stack_effect();
+        depth = depth - pops + pushes;
+        lines.push(line(instruction, &body_line.text));
+    }
+
+    if function.sets_pointers {
+        let this = depth - arguments - function.locals;
+        lines.push(line(Push(Segment::Stack, this), "restore pointer 0"));
+        lines.push(line(Pop(Segment::Pointer, 0), "restore pointer 0"));
+        lines.push(line(Push(Segment::Stack, this - 1), "restore pointer 1"));
+        lines.push(line(Pop(Segment::Pointer, 1), "restore pointer 1"));
+    }
+    if depth > 1 {
+        lines.push(line(Pop(Segment::Stack, depth), "return"));
+    }
+    if depth > 2 {
+        lines.push(line(Discard(depth - 2), "return"));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::translator::parse_file;
+
+    fn source(name: &str, contents: &str) -> Source {
+        Source {
+            file: format!("{}.vm", name),
+            name: name.into(),
+            lines: parse_file(name, contents).unwrap(),
+        }
+    }
+
+    fn instructions(source: &Source) -> Vec<Instruction> {
+        source
+            .lines
+            .iter()
+            .filter_map(|line| line.instruction.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_inlines_a_getter() {
+        let main = source("Main", "function Main.main 0\ncall Point.getX 1");
+        let point = source(
+            "Point",
+            "function Point.getX 0\npush argument 0\npop pointer 0\n\
+             push this 0\nreturn",
+        );
+
+        let sources = inline(&[main, point]);
+
+        assert_eq!(
+            vec![
+                Function("Main.main".into(), 0),
+                Push(Segment::Pointer, 0),
+                Push(Segment::Pointer, 1),
+                Push(Segment::Stack, 3),
+                Pop(Segment::Pointer, 0),
+                Push(Segment::This, 0),
+                Push(Segment::Stack, 3),
+                Pop(Segment::Pointer, 0),
+                Push(Segment::Stack, 2),
+                Pop(Segment::Pointer, 1),
+                Pop(Segment::Stack, 4),
+                Discard(2),
+            ],
+            instructions(&sources[0])
+        );
+        assert_eq!("call Point.getX 1 / push this 0", sources[0].lines[5].text);
+    }
+
+    #[test]
+    fn test_locals_sit_above_the_arguments() {
+        let main = source(
+            "Main",
+            "function Main.sum 1\npush argument 0\npush argument 1\nadd\n\
+             pop local 0\npush local 0\nreturn\n\
+             function Main.main 0\ncall Main.sum 2",
+        );
+
+        assert_eq!(
+            vec![
+                Push(Segment::Constant, 0),
+                Push(Segment::Stack, 3),
+                Push(Segment::Stack, 3),
+                Add,
+                Pop(Segment::Stack, 2),
+                Push(Segment::Stack, 1),
+                Pop(Segment::Stack, 4),
+                Discard(2),
+            ],
+            instructions(&inline(&[main])[0])[8..]
+        );
+    }
+
+    #[test]
+    fn test_what_is_not_inlined() {
+        let main = source(
+            "Main",
+            &("function Main.main 0\ncall Main.loop 0\ncall Main.branch 0\n\
+             call Main.big 0\ncall Counter.next 0\ncall Main.first 0\n\
+             function Main.loop 0\ncall Main.loop 0\nreturn\n\
+             function Main.branch 0\nlabel L\npush constant 0\nreturn\n\
+             function Main.big 0\n"
+                .to_owned()
+                + &"push constant 1\n".repeat(MAX_INSTRUCTIONS + 1)
+                + "return\n\
+                   function Main.first 0\npush argument 0\nreturn"),
+        );
+        let counter =
+            source("Counter", "function Counter.next 0\npush static 0\nreturn");
+
+        let calls: Vec<Instruction> =
+            instructions(&inline(&[main, counter])[0])
+                .into_iter()
+                .take(6)
+                .collect();
+
+        assert_eq!(
+            vec![
+                Function("Main.main".into(), 0),
+                Call("Main.loop".into(), 0),
+                Call("Main.branch".into(), 0),
+                Call("Main.big".into(), 0),
+                Call("Counter.next".into(), 0),
+                // no argument to return
+                Call("Main.first".into(), 0),
+            ],
+            calls
+        );
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Instruction {
     Push(Segment, u16),
     Pop(Segment, u16),
@@ -11,15 +11,146 @@ pub enum Instruction {
     And,
     Or,
     Not,
+    // only with the extended instruction set
+    Multiply,
+    Divide,
+    Modulo,
+    ShiftLeft,
+    ShiftRight,
+    Xor,
     Label(String),
     Goto(String),
     IfGoto(String),
     Function(String, u16),
     Call(String, u16),
     Return,
+    // only made by the optimizer and the inliner
+    Move(Segment, u16, Segment, u16),
+    JumpIf(Condition, String),
+    TailCall(String, u16),
+    Discard(u16),
 }
 
-#[derive(Debug)]
+impl Instruction {
+    // how many values the instruction pops and then pushes
+    pub fn stack_effect(&self) -> (u16, u16) {
+        use Instruction::*;
+
+        match self {
+            Push(..) => (0, 1),
+            Pop(..) | IfGoto(_) | Return => (1, 0),
+            Add | Subtract | Equal | GreaterThan | LessThan | And | Or => {
+                (2, 1)
+            }
+            Multiply | Divide | Modulo | ShiftLeft | ShiftRight | Xor => (2, 1),
+            Negate | Not => (1, 1),
+            Label(_) | Goto(_) | Move(..) => (0, 0),
+            Function(_, locals) => (0, *locals),
+            Call(_, arguments) => (*arguments, 1),
+            TailCall(_, arguments) => (*arguments, 0),
+            JumpIf(Condition::NotTrue, _) => (1, 0),
+            JumpIf(..) => (2, 0),
+            Discard(count) => (*count, 0),
+        }
+    }
+
+    // `mul`, `div`, `mod`, `shl`, `shr` and `xor`, which standard .vm files
+    // don't have
+    pub fn is_extended(&self) -> bool {
+        use Instruction::*;
+
+        matches!(
+            self,
+            Multiply | Divide | Modulo | ShiftLeft | ShiftRight | Xor
+        )
+    }
+
+    // whether it's a jump to the label just before it, which is how
+    // programs halt
+    pub fn halts(&self, previous: Option<&Instruction>) -> bool {
+        match (self, previous) {
+            (Instruction::Goto(label), Some(Instruction::Label(previous))) => {
+                label == previous
+            }
+            _ => false,
+        }
+    }
+
+    // The instruction as it's written in a .vm file, without the extra
+    // spaces and comments a line can have. The optimizer's instructions
+    // have no text form.
+    pub fn to_vm(&self) -> Option<String> {
+        use Instruction::*;
+
+        Some(match self {
+            Push(segment, index) => {
+                format!("push {} {}", segment.name()?, index)
+            }
+            Pop(segment, index) => format!("pop {} {}", segment.name()?, index),
+            Label(label) => format!("label {}", label),
+            Goto(label) => format!("goto {}", label),
+            IfGoto(label) => format!("if-goto {}", label),
+            Function(name, locals) => format!("function {} {}", name, locals),
+            Call(name, arguments) => format!("call {} {}", name, arguments),
+            Add => "add".into(),
+            Subtract => "sub".into(),
+            Negate => "neg".into(),
+            Equal => "eq".into(),
+            GreaterThan => "gt".into(),
+            LessThan => "lt".into(),
+            And => "and".into(),
+            Or => "or".into(),
+            Not => "not".into(),
+            Multiply => "mul".into(),
+            Divide => "div".into(),
+            Modulo => "mod".into(),
+            ShiftLeft => "shl".into(),
+            ShiftRight => "shr".into(),
+            Xor => "xor".into(),
+            Return => "return".into(),
+            Move(..) | JumpIf(..) | TailCall(..) | Discard(_) => return None,
+        })
+    }
+
+    // What an extended instruction leaves for x and y, taken as signed.
+    // Division truncates toward zero, and dividing by zero gives 0 and x as
+    // the remainder. Shifting by a negative count or one over 15 shifts
+    // every bit out, and `shr` copies the sign bit in.
+    pub fn extended_result(&self, x: u16, y: u16) -> Option<u16> {
+        use Instruction::*;
+
+        let (x, y) = (x as i16, y as i16);
+        let count = y as u16;
+        Some(match self {
+            Multiply => x.wrapping_mul(y),
+            Divide if y == 0 => 0,
+            Divide => x.wrapping_div(y),
+            Modulo if y == 0 => x,
+            Modulo => x.wrapping_rem(y),
+            ShiftLeft if count > 15 => 0,
+            ShiftLeft => x << count,
+            ShiftRight => x >> count.min(15),
+            Xor => x ^ y,
+            _ => return None,
+        } as u16)
+    }
+}
+
+// The condition of a fused comparison and `if-goto`. `NotTrue` tests a
+// single value the way `not` then `if-goto` does, jumping unless it's -1;
+// the others compare the top two like `eq`, `gt` and `lt` do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    NotTrue,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Segment {
     Argument,
     Local,
@@ -29,4 +160,24 @@ pub enum Segment {
     That,
     Pointer,
     Temp,
+    // The values on the stack, counting down from the top, which is 1. Used
+    // by inlined functions for their arguments and locals.
+    Stack,
+}
+
+impl Segment {
+    // the segment's name in .vm files, which the stack doesn't have
+    pub fn name(&self) -> Option<&'static str> {
+        Some(match self {
+            Segment::Argument => "argument",
+            Segment::Local => "local",
+            Segment::Static => "static",
+            Segment::Constant => "constant",
+            Segment::This => "this",
+            Segment::That => "that",
+            Segment::Pointer => "pointer",
+            Segment::Temp => "temp",
+            Segment::Stack => return None,
+        })
+    }
 }
@@ -0,0 +1,248 @@
+use crate::translator::instruction::Instruction::*;
+use crate::translator::instruction::*;
+use crate::translator::SourceLine;
+
+// Rewrites a file's instructions into fewer, cheaper ones: arithmetic on
+// constants is done up front, a push straight into a pop becomes a move, a
+// comparison and the `if-goto` that tests it become one jump, a call right
+// before a `return` becomes a tail call that reuses the caller's frame, and
+// code after a `goto` or `return` that no label leads to is dropped.
+// Comment lines go too. Each rewrite only looks at the instructions just
+// before it, and a label stops it, so jumps can't land in the middle of
+// one. A fused line keeps the first line's number and the texts of all of
+// them.
+pub fn optimize(lines: Vec<SourceLine>) -> Vec<SourceLine> {
+    let mut output: Vec<SourceLine> = vec![];
+    let mut unreachable = false;
+
+    for line in lines {
+        let Some(instruction) = &line.instruction else {
+            continue;
+        };
+        if unreachable && !matches!(instruction, Label(_) | Function(..)) {
+            continue;
+        }
+
+        output.push(line);
+        while reduce(&mut output) {}
+        unreachable = matches!(
+            output.last().and_then(|line| line.instruction.as_ref()),
+            Some(Goto(_) | Return | TailCall(..))
+        );
+    }
+    output
+}
+
+// replaces the last few lines with something shorter, if any rule applies
+fn reduce(lines: &mut Vec<SourceLine>) -> bool {
+    for length in [3, 2] {
+        if lines.len() < length {
+            continue;
+        }
+        let start = lines.len() - length;
+        let window: Vec<&Instruction> = lines[start..]
+            .iter()
+            .filter_map(|line| line.instruction.as_ref())
+            .collect();
+
+        if let Some(replacement) = rewrite(&window) {
+            let fused = lines.split_off(start);
+            let text = fused
+                .iter()
+                .map(|line| line.text.as_str())
+                .collect::<Vec<&str>>()
+                .join(" / ");
+            lines.extend(replacement.into_iter().map(|instruction| {
+                SourceLine {
+                    number: fused[0].number,
+                    text: text.clone(),
+                    instruction: Some(instruction),
+                }
+            }));
+            return true;
+        }
+    }
+    false
+}
+
+fn rewrite(window: &[&Instruction]) -> Option<Vec<Instruction>> {
+    use Segment::Constant;
+
+    let replacement = match window {
+        [Push(Constant, x), Push(Constant, y), operation] => {
+            vec![Push(Constant, fold_binary(operation, *x, *y)?)]
+        }
+        [comparison, Not, IfGoto(label)] => {
+            vec![JumpIf(negate(condition(comparison)?), label.clone())]
+        }
+        [Push(Constant, value), IfGoto(label)] => match value {
+            0 => vec![],
+            _ => vec![Goto(label.clone())],
+        },
+        [Call(name, arguments), Return] => {
+            vec![TailCall(name.clone(), *arguments)]
+        }
+        [Push(from, from_index), Pop(to, to_index)]
+            if *from != Segment::Stack && *to != Segment::Stack =>
+        {
+            if from == to && from_index == to_index {
+                vec![]
+            } else {
+                vec![Move(from.clone(), *from_index, to.clone(), *to_index)]
+            }
+        }
+        [Push(Constant, value), operation] => {
+            vec![Push(Constant, fold_unary(operation, *value)?)]
+        }
+        [Not, IfGoto(label)] => vec![JumpIf(Condition::NotTrue, label.clone())],
+        [comparison, IfGoto(label)] => {
+            vec![JumpIf(condition(comparison)?, label.clone())]
+        }
+        _ => return None,
+    };
+    Some(replacement)
+}
+
+// the 16-bit result, with comparisons on signed values like the Hack code
+fn fold_binary(operation: &Instruction, x: u16, y: u16) -> Option<u16> {
+    let truth = |value: bool| if value { 0xFFFF } else { 0 };
+
+    Some(match operation {
+        Add => x.wrapping_add(y),
+        Subtract => x.wrapping_sub(y),
+        And => x & y,
+        Or => x | y,
+        Equal => truth(x == y),
+        GreaterThan => truth(x as i16 > y as i16),
+        LessThan => truth((x as i16) < y as i16),
+        _ => return operation.extended_result(x, y),
+    })
+}
+
+fn fold_unary(operation: &Instruction, value: u16) -> Option<u16> {
+    match operation {
+        Negate => Some(value.wrapping_neg()),
+        Not => Some(!value),
+        _ => None,
+    }
+}
+
+// comparisons leave 0 or -1, so an `if-goto` after one jumps exactly when
+// the comparison holds
+fn condition(comparison: &Instruction) -> Option<Condition> {
+    match comparison {
+        Equal => Some(Condition::Equal),
+        GreaterThan => Some(Condition::Greater),
+        LessThan => Some(Condition::Less),
+        _ => None,
+    }
+}
+
+fn negate(condition: Condition) -> Condition {
+    match condition {
+        Condition::Equal => Condition::NotEqual,
+        Condition::NotEqual => Condition::Equal,
+        Condition::Greater => Condition::LessOrEqual,
+        Condition::LessOrEqual => Condition::Greater,
+        Condition::Less => Condition::GreaterOrEqual,
+        Condition::GreaterOrEqual => Condition::Less,
+        Condition::NotTrue => panic!("can't negate {:?}", condition),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::translator::parse_file;
+
+    fn optimized(contents: &str) -> Vec<Instruction> {
+        optimize(parse_file("Main.vm", contents).unwrap())
+            .into_iter()
+            .filter_map(|line| line.instruction)
+            .collect()
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        assert_eq!(
+            vec![Push(Segment::Constant, 9)],
+            optimized(
+                "push constant 2\npush constant 3\npush constant 4\nadd\nadd"
+            )
+        );
+        assert_eq!(
+            vec![Push(Segment::Constant, 0xFFFF)],
+            optimized("push constant 1\nneg")
+        );
+        assert_eq!(
+            vec![Push(Segment::Constant, 0xFFFF)],
+            optimized("push constant 1\nneg\npush constant 0\nlt")
+        );
+        assert_eq!(
+            vec![Push(Segment::Constant, 5), Push(Segment::Local, 0), Add],
+            optimized("push constant 5\npush local 0\nadd")
+        );
+    }
+
+    #[test]
+    fn test_push_then_pop_becomes_a_move() {
+        assert_eq!(
+            vec![Move(Segment::Argument, 1, Segment::Local, 0)],
+            optimized("push argument 1\npop local 0")
+        );
+        assert!(optimized("push temp 0\npop temp 0").is_empty());
+    }
+
+    #[test]
+    fn test_comparisons_fuse_with_if_goto() {
+        let label = String::from("END");
+
+        assert_eq!(
+            vec![JumpIf(Condition::GreaterOrEqual, label.clone())],
+            optimized("lt\nnot\nif-goto END")
+        );
+        assert_eq!(
+            vec![JumpIf(Condition::Equal, label.clone())],
+            optimized("eq\nif-goto END")
+        );
+        assert_eq!(
+            vec![
+                Push(Segment::Local, 0),
+                JumpIf(Condition::NotTrue, label.clone())
+            ],
+            optimized("push local 0\nnot\nif-goto END")
+        );
+        assert_eq!(
+            vec![Goto(label)],
+            optimized("push constant 1\nneg\nif-goto END")
+        );
+        assert!(optimized("push constant 0\nif-goto END").is_empty());
+    }
+
+    #[test]
+    fn test_unreachable_code_is_dropped() {
+        let lines = optimize(
+            parse_file(
+                "Main.vm",
+                "function Main.f 0\ngoto END\npush constant 1\n\
+                 // comment\nlabel END\npush constant 0\nreturn\n\
+                 push constant 2\nfunction Main.g 0",
+            )
+            .unwrap(),
+        );
+        let numbers: Vec<usize> =
+            lines.iter().map(|line| line.number).collect();
+
+        assert_eq!(vec![1, 2, 5, 6, 7, 9], numbers);
+    }
+
+    #[test]
+    fn test_fused_lines_keep_their_source() {
+        let lines = optimize(
+            parse_file("Main.vm", "label L\npush local 0\npop that 1").unwrap(),
+        );
+
+        assert_eq!(2, lines[1].number);
+        assert_eq!("push local 0 / pop that 1", lines[1].text);
+    }
+}
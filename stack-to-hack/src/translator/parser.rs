@@ -1,6 +1,86 @@
 use crate::translator::instruction::*;
+use std::fmt;
 
-pub fn parse<S>(line: S) -> Instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    UnknownCommand,
+    UnknownSegment,
+    InvalidNumber,
+}
+
+// The file and line are left empty by `parse`, which only sees the line
+// itself, and filled in by `parse_file`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub file: String,
+    pub line: usize,
+    pub token: String,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self.kind {
+            ErrorKind::UnknownCommand => "unknown instruction",
+            ErrorKind::UnknownSegment => "unknown segment",
+            ErrorKind::InvalidNumber => "invalid number",
+        };
+        write!(
+            f,
+            "{}:{}: {}: {}",
+            self.file, self.line, message, self.token
+        )
+    }
+}
+
+// a line of a .vm file and the instruction on it, if it has one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    pub number: usize,
+    pub text: String,
+    pub instruction: Option<Instruction>,
+}
+
+// Parses every line of a file, returning all the errors in it if any line
+// is invalid
+pub fn parse_file(
+    file: &str,
+    contents: &str,
+) -> Result<Vec<SourceLine>, Vec<ParseError>> {
+    let mut lines = vec![];
+    let mut errors = vec![];
+
+    for (index, text) in contents.lines().map(|line| line.trim()).enumerate() {
+        let instruction = if text.is_empty() || text.starts_with("//") {
+            None
+        } else {
+            match parse(text) {
+                Ok(instruction) => Some(instruction),
+                Err(error) => {
+                    errors.push(ParseError {
+                        file: file.into(),
+                        line: index + 1,
+                        ..error
+                    });
+                    continue;
+                }
+            }
+        };
+        lines.push(SourceLine {
+            number: index + 1,
+            text: text.into(),
+            instruction,
+        });
+    }
+
+    if errors.is_empty() {
+        Ok(lines)
+    } else {
+        Err(errors)
+    }
+}
+
+pub fn parse<S>(line: S) -> Result<Instruction, ParseError>
 where
     S: Into<String>,
 {
@@ -12,7 +92,7 @@ where
         None => line.as_str(),
     };
 
-    match line.split_whitespace().collect::<Vec<&str>>()[..] {
+    let instruction = match line.split_whitespace().collect::<Vec<&str>>()[..] {
         ["add"] => Add,
         ["sub"] => Subtract,
         ["neg"] => Negate,
@@ -22,39 +102,119 @@ where
         ["and"] => And,
         ["or"] => Or,
         ["not"] => Not,
+        ["mul"] => Multiply,
+        ["div"] => Divide,
+        ["mod"] => Modulo,
+        ["shl"] => ShiftLeft,
+        ["shr"] => ShiftRight,
+        ["xor"] => Xor,
         ["push", segment, index_or_value] => {
-            Push(parse_segment(segment), parse_number(index_or_value))
+            Push(parse_segment(segment)?, parse_number(index_or_value)?)
         }
         ["pop", segment, index_or_value] => {
-            Pop(parse_segment(segment), parse_number(index_or_value))
+            Pop(parse_segment(segment)?, parse_number(index_or_value)?)
         }
         ["label", name] => Label(name.into()),
         ["goto", name] => Goto(name.into()),
         ["if-goto", name] => IfGoto(name.into()),
-        ["function", name, arity] => Function(name.into(), parse_number(arity)),
-        ["call", name, arity] => Call(name.into(), parse_number(arity)),
+        ["function", name, arity] => {
+            Function(name.into(), parse_number(arity)?)
+        }
+        ["call", name, arity] => Call(name.into(), parse_number(arity)?),
         ["return"] => Return,
-        _ => panic!("unknown instruction: {}", line),
-    }
+        _ => return Err(error(ErrorKind::UnknownCommand, line.trim())),
+    };
+    Ok(instruction)
 }
 
-fn parse_segment(segment: &str) -> Segment {
+pub fn parse_segment(segment: &str) -> Result<Segment, ParseError> {
     match segment {
-        "argument" => Segment::Argument,
-        "local" => Segment::Local,
-        "static" => Segment::Static,
-        "constant" => Segment::Constant,
-        "this" => Segment::This,
-        "that" => Segment::That,
-        "pointer" => Segment::Pointer,
-        "temp" => Segment::Temp,
-        _ => panic!("unknown segment: {}", segment),
-    }
-}
-
-fn parse_number(string: &str) -> u16 {
-    match string.parse::<u16>() {
-        Ok(value) => value,
-        Err(_) => panic!("invalid number: {}", string),
+        "argument" => Ok(Segment::Argument),
+        "local" => Ok(Segment::Local),
+        "static" => Ok(Segment::Static),
+        "constant" => Ok(Segment::Constant),
+        "this" => Ok(Segment::This),
+        "that" => Ok(Segment::That),
+        "pointer" => Ok(Segment::Pointer),
+        "temp" => Ok(Segment::Temp),
+        _ => Err(error(ErrorKind::UnknownSegment, segment)),
+    }
+}
+
+fn parse_number(string: &str) -> Result<u16, ParseError> {
+    string
+        .parse::<u16>()
+        .map_err(|_| error(ErrorKind::InvalidNumber, string))
+}
+
+fn error(kind: ErrorKind, token: &str) -> ParseError {
+    ParseError {
+        file: String::new(),
+        line: 0,
+        token: token.into(),
+        kind,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            Err(error(ErrorKind::UnknownCommand, "push local")),
+            parse("push local  // missing index")
+        );
+        assert_eq!(
+            Err(error(ErrorKind::UnknownSegment, "heap")),
+            parse("pop heap 2")
+        );
+        assert_eq!(
+            Err(error(ErrorKind::InvalidNumber, "-1")),
+            parse("push constant -1")
+        );
+    }
+
+    #[test]
+    fn test_parse_file_reports_every_error() {
+        let errors = parse_file(
+            "Main.vm",
+            "// Main\npush constant 1\nfrob\n\npush constant 70000\n",
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            vec![
+                "Main.vm:3: unknown instruction: frob",
+                "Main.vm:5: invalid number: 70000"
+            ],
+            errors
+                .iter()
+                .map(|error| error.to_string())
+                .collect::<Vec<String>>()
+        );
+    }
+
+    #[test]
+    fn test_parse_file() {
+        let lines =
+            parse_file("Main.vm", "// Main\n  push constant 1\n").unwrap();
+
+        assert_eq!(
+            vec![
+                SourceLine {
+                    number: 1,
+                    text: "// Main".into(),
+                    instruction: None,
+                },
+                SourceLine {
+                    number: 2,
+                    text: "push constant 1".into(),
+                    instruction: Some(Instruction::Push(Segment::Constant, 1)),
+                },
+            ],
+            lines
+        );
     }
 }
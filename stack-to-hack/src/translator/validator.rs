@@ -0,0 +1,290 @@
+use crate::translator::instruction::*;
+use crate::translator::Source;
+use std::collections::HashSet;
+use std::fmt;
+
+// statics are assembler variables, allocated from 16 up to the stack at 256
+const STATIC_WORDS: usize = 240;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+// Checks the things the parser can't see on a single line: segment indexes,
+// jumps to labels and calls to functions, and how many statics the whole
+// program uses.
+pub fn validate(sources: &[Source]) -> Vec<ValidationError> {
+    validate_with_builtins(sources, &[])
+}
+
+// the same, for a program that can also call the given functions, which
+// aren't defined in .vm files
+pub fn validate_with_builtins(
+    sources: &[Source],
+    builtins: &[&str],
+) -> Vec<ValidationError> {
+    let mut errors = vec![];
+    let functions: HashSet<&str> = instructions(sources)
+        .filter_map(|(_, _, instruction)| match instruction {
+            Instruction::Function(name, _) => Some(name.as_str()),
+            _ => None,
+        })
+        .chain(builtins.iter().copied())
+        .collect();
+    let mut statics = HashSet::new();
+
+    for source in sources {
+        for (function, lines) in function_bodies(source) {
+            let labels: HashSet<&str> = lines
+                .iter()
+                .filter_map(|(_, instruction)| match instruction {
+                    Instruction::Label(label) => Some(label.as_str()),
+                    _ => None,
+                })
+                .collect();
+
+            for &(line, instruction) in &lines {
+                let mut error = |message: String| {
+                    errors.push(ValidationError {
+                        file: source.file.clone(),
+                        line,
+                        message,
+                    })
+                };
+
+                match instruction {
+                    Instruction::Pop(Segment::Constant, _) => {
+                        error("can't pop to the constant segment".into())
+                    }
+                    Instruction::Push(segment, index)
+                    | Instruction::Pop(segment, index) => {
+                        if let Some(message) = check_segment(segment, *index) {
+                            error(message);
+                        }
+                    }
+                    Instruction::Goto(label) | Instruction::IfGoto(label)
+                        if !labels.contains(label.as_str()) =>
+                    {
+                        error(match function {
+                            Some(function) => {
+                                format!("no label {} in {}", label, function)
+                            }
+                            None => format!("no label {}", label),
+                        })
+                    }
+                    Instruction::Call(name, _)
+                        if !functions.contains(name.as_str()) =>
+                    {
+                        error(format!("no function {} is defined", name))
+                    }
+                    _ => {}
+                }
+
+                if let Instruction::Push(Segment::Static, index)
+                | Instruction::Pop(Segment::Static, index) = instruction
+                {
+                    if statics.insert((source.name.as_str(), *index))
+                        && statics.len() == STATIC_WORDS + 1
+                    {
+                        error(format!(
+                            "more than {} static variables in the program",
+                            STATIC_WORDS
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    errors
+}
+
+// Reports every extended instruction, for programs that are to run
+// without them
+pub fn check_extended(sources: &[Source]) -> Vec<ValidationError> {
+    sources
+        .iter()
+        .flat_map(|source| {
+            source
+                .lines
+                .iter()
+                .filter(|line| {
+                    line.instruction
+                        .as_ref()
+                        .is_some_and(Instruction::is_extended)
+                })
+                .map(|line| ValidationError {
+                    file: source.file.clone(),
+                    line: line.number,
+                    message: format!(
+                        "{} is only in the extended instruction set",
+                        line.text.split_whitespace().next().unwrap_or("")
+                    ),
+                })
+        })
+        .collect()
+}
+
+pub fn defines_function(sources: &[Source], name: &str) -> bool {
+    instructions(sources).any(|(_, _, instruction)| {
+        matches!(instruction, Instruction::Function(function, _) if function == name)
+    })
+}
+
+fn check_segment(segment: &Segment, index: u16) -> Option<String> {
+    let limit = match segment {
+        Segment::Constant => 32767,
+        Segment::Pointer => 1,
+        Segment::Temp => 7,
+        _ => return None,
+    };
+
+    if index > limit {
+        Some(format!(
+            "{} index {} is out of range 0-{}",
+            format!("{:?}", segment).to_lowercase(),
+            index,
+            limit
+        ))
+    } else {
+        None
+    }
+}
+
+fn instructions(
+    sources: &[Source],
+) -> impl Iterator<Item = (&Source, usize, &Instruction)> {
+    sources.iter().flat_map(|source| {
+        source.lines.iter().filter_map(move |line| {
+            Some((source, line.number, line.instruction.as_ref()?))
+        })
+    })
+}
+
+// a function's name and its instructions with their line numbers
+pub type Body<'a> = (Option<&'a str>, Vec<(usize, &'a Instruction)>);
+
+// Splits a file at each `function`, as labels are local to functions. Code
+// before the first function gets a body of its own.
+pub fn function_bodies(source: &Source) -> Vec<Body<'_>> {
+    let mut bodies = vec![(None, vec![])];
+
+    for (_, line, instruction) in instructions(std::slice::from_ref(source)) {
+        if let Instruction::Function(name, _) = instruction {
+            bodies.push((Some(name.as_str()), vec![]));
+        }
+        bodies.last_mut().unwrap().1.push((line, instruction));
+    }
+    bodies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::translator::parse_file;
+
+    fn source(name: &str, contents: &str) -> Source {
+        Source {
+            file: format!("{}.vm", name),
+            name: name.into(),
+            lines: parse_file(name, contents).unwrap(),
+        }
+    }
+
+    fn messages(sources: &[Source]) -> Vec<String> {
+        validate(sources)
+            .iter()
+            .map(|error| error.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_segment_ranges() {
+        assert_eq!(
+            vec![
+                "Main.vm:1: can't pop to the constant segment",
+                "Main.vm:2: pointer index 2 is out of range 0-1",
+                "Main.vm:3: temp index 9 is out of range 0-7",
+                "Main.vm:4: constant index 40000 is out of range 0-32767",
+            ],
+            messages(&[source(
+                "Main",
+                "pop constant 3\npush pointer 2\npop temp 9\n\
+                 push constant 40000\npush temp 7\npop pointer 1",
+            )])
+        );
+    }
+
+    #[test]
+    fn test_labels_are_local_to_functions() {
+        let main = source(
+            "Main",
+            "function Main.main 0\nlabel LOOP\ngoto LOOP\n\
+             function Main.other 0\nif-goto LOOP\ngoto END\nlabel END",
+        );
+
+        assert_eq!(
+            vec!["Main.vm:5: no label LOOP in Main.other"],
+            messages(&[main])
+        );
+    }
+
+    #[test]
+    fn test_call_targets_across_files() {
+        let main = source(
+            "Main",
+            "function Main.main 0\ncall Math.double 1\ncall Math.half 1",
+        );
+        let math = source("Math", "function Math.double 0\nreturn");
+
+        assert_eq!(
+            vec!["Main.vm:3: no function Math.half is defined"],
+            messages(&[main, math])
+        );
+    }
+
+    #[test]
+    fn test_static_usage_across_files() {
+        let statics = |name: &str, count: u16| {
+            let contents: Vec<String> = (0..count)
+                .map(|index| format!("push static {}", index))
+                .collect();
+            source(name, &contents.join("\n"))
+        };
+
+        assert!(validate(&[statics("A", 200), statics("B", 40)]).is_empty());
+        assert_eq!(
+            vec!["B.vm:41: more than 240 static variables in the program"],
+            messages(&[statics("A", 200), statics("B", 41)])
+        );
+    }
+
+    #[test]
+    fn test_extended_instructions() {
+        let sources = [source(
+            "Main",
+            "push constant 6\npush constant 7\nmul  // 42\nadd\nxor",
+        )];
+
+        assert!(validate(&sources).is_empty());
+        assert_eq!(
+            vec![
+                "Main.vm:3: mul is only in the extended instruction set",
+                "Main.vm:5: xor is only in the extended instruction set",
+            ],
+            check_extended(&sources)
+                .iter()
+                .map(|error| error.to_string())
+                .collect::<Vec<String>>()
+        );
+    }
+}
@@ -0,0 +1,839 @@
+use crate::translator::instruction::Instruction::*;
+use crate::translator::instruction::*;
+use crate::translator::{register_address, Bootstrap, Source};
+use std::collections::HashMap;
+
+// The RAM is the module's memory, a page of 32K 16-bit words, and the stack
+// helpers work on it like the C backend's. Values are sign-extended when
+// loaded and cut to 16 bits when stored, so i32 arithmetic wraps like the
+// Hack CPU's.
+const HELPERS: &str = r#"  (memory (export "ram") 1)
+  (global $pc (mut i32) (i32.const 0))
+  (global $halted (mut i32) (i32.const 0))
+
+  (func $load (param $address i32) (result i32)
+    local.get $address
+    i32.const 32767
+    i32.and
+    i32.const 1
+    i32.shl
+    i32.load16_s)
+
+  (func $store (param $address i32) (param $value i32)
+    local.get $address
+    i32.const 32767
+    i32.and
+    i32.const 1
+    i32.shl
+    local.get $value
+    i32.store16)
+
+  (func $push (param $value i32)
+    i32.const 0
+    call $load
+    local.get $value
+    call $store
+    i32.const 0
+    i32.const 0
+    call $load
+    i32.const 1
+    i32.add
+    call $store)
+
+  (func $pop (result i32)
+    i32.const 0
+    i32.const 0
+    call $load
+    i32.const 1
+    i32.sub
+    call $store
+    i32.const 0
+    call $load
+    call $load)
+
+  (func $pop_to (param $address i32)
+    local.get $address
+    call $pop
+    call $store)
+
+  (func $neg
+    i32.const 0
+    call $pop
+    i32.sub
+    call $push)
+
+  (func $not
+    call $pop
+    i32.const -1
+    i32.xor
+    call $push)
+
+  (func $div (local $x i32) (local $y i32)
+    call $pop
+    local.set $y
+    call $pop
+    local.set $x
+    local.get $y
+    if (result i32)
+      local.get $x
+      local.get $y
+      i32.div_s
+    else
+      i32.const 0
+    end
+    call $push)
+
+  (func $mod (local $x i32) (local $y i32)
+    call $pop
+    local.set $y
+    call $pop
+    local.set $x
+    local.get $y
+    if (result i32)
+      local.get $x
+      local.get $y
+      i32.rem_s
+    else
+      local.get $x
+    end
+    call $push)
+
+  (func $shl (local $x i32) (local $y i32)
+    call $pop
+    i32.const 65535
+    i32.and
+    local.set $y
+    call $pop
+    local.set $x
+    local.get $y
+    i32.const 15
+    i32.gt_u
+    if (result i32)
+      i32.const 0
+    else
+      local.get $x
+      local.get $y
+      i32.shl
+    end
+    call $push)
+
+  (func $shr (local $y i32)
+    call $pop
+    i32.const 65535
+    i32.and
+    local.set $y
+    call $pop
+    local.get $y
+    i32.const 15
+    local.get $y
+    i32.const 15
+    i32.lt_u
+    select
+    i32.shr_s
+    call $push)
+
+  (func $call (param $return i32) (param $arguments i32)
+    local.get $return
+    call $push
+    i32.const 1
+    call $load
+    call $push
+    i32.const 2
+    call $load
+    call $push
+    i32.const 3
+    call $load
+    call $push
+    i32.const 4
+    call $load
+    call $push
+    i32.const 2
+    i32.const 0
+    call $load
+    i32.const 5
+    i32.sub
+    local.get $arguments
+    i32.sub
+    call $store
+    i32.const 1
+    i32.const 0
+    call $load
+    call $store)
+
+  ;; copies the caller's frame and the arguments down to its own arguments,
+  ;; so the callee returns straight to the caller's caller
+  (func $tail_call (param $arguments i32)
+    (local $frame i32) (local $size i32) (local $from i32) (local $to i32)
+    (local $i i32)
+    i32.const 1
+    call $load
+    local.set $frame
+    i32.const 5
+    local.set $i
+    loop $push_frame
+      local.get $frame
+      local.get $i
+      i32.sub
+      call $load
+      call $push
+      local.get $i
+      i32.const 1
+      i32.sub
+      local.tee $i
+      br_if $push_frame
+    end
+    local.get $arguments
+    i32.const 5
+    i32.add
+    local.set $size
+    i32.const 0
+    call $load
+    local.get $size
+    i32.sub
+    local.set $from
+    i32.const 2
+    call $load
+    local.set $to
+    block $copied
+      loop $copy
+        local.get $i
+        local.get $size
+        i32.ge_u
+        br_if $copied
+        local.get $to
+        local.get $i
+        i32.add
+        local.get $from
+        local.get $i
+        i32.add
+        call $load
+        call $store
+        local.get $i
+        i32.const 1
+        i32.add
+        local.set $i
+        br $copy
+      end
+    end
+    i32.const 0
+    local.get $to
+    local.get $size
+    i32.add
+    call $store
+    i32.const 1
+    local.get $to
+    local.get $size
+    i32.add
+    call $store)
+
+  (func $return (result i32) (local $frame i32) (local $return i32)
+    i32.const 1
+    call $load
+    local.set $frame
+    local.get $frame
+    i32.const 5
+    i32.sub
+    call $load
+    i32.const 65535
+    i32.and
+    local.set $return
+    i32.const 2
+    call $load
+    call $pop
+    call $store
+    i32.const 0
+    i32.const 2
+    call $load
+    i32.const 1
+    i32.add
+    call $store
+    i32.const 4
+    local.get $frame
+    i32.const 1
+    i32.sub
+    call $load
+    call $store
+    i32.const 3
+    local.get $frame
+    i32.const 2
+    i32.sub
+    call $load
+    call $store
+    i32.const 2
+    local.get $frame
+    i32.const 3
+    i32.sub
+    call $load
+    call $store
+    i32.const 1
+    local.get $frame
+    i32.const 4
+    i32.sub
+    call $load
+    call $store
+    local.get $return)
+
+  (func (export "halted") (result i32)
+    global.get $halted)
+
+  ;; the byte offset of the screen in the memory, 512 pixels to a row of 32
+  ;; words, with the lowest bit on the left
+  (func (export "screen") (result i32)
+    i32.const 32768)
+
+  (func (export "set_key") (param $key i32)
+    i32.const 24576
+    local.get $key
+    call $store)
+
+  (func (export "peek") (param $address i32) (result i32)
+    local.get $address
+    call $load)
+
+  (func (export "poke") (param $address i32) (param $value i32)
+    local.get $address
+    local.get $value
+    call $store)
+"#;
+
+// the binary operations, which pop y and x and push the result
+const BINARY: &[(&str, &str)] = &[
+    ("add", "i32.add"),
+    ("sub", "i32.sub"),
+    ("and", "i32.and"),
+    ("or", "i32.or"),
+    ("xor", "i32.xor"),
+    ("mul", "i32.mul"),
+    // comparisons give 1 or 0, and true is -1
+    ("eq", "i32.eq\n    i32.const -1\n    i32.mul"),
+    ("gt", "i32.gt_s\n    i32.const -1\n    i32.mul"),
+    ("lt", "i32.lt_s\n    i32.const -1\n    i32.mul"),
+];
+
+// Translates the whole program to a WebAssembly module in the text format.
+// It runs like the C backend's code, but with a case for each function,
+// label and return site in a `br_table` over nested blocks, as WebAssembly
+// has no gotos. The host calls `run` with a number of steps, the function
+// entries and labels passed, and gets back how many are left; it can read
+// the screen from the memory and press keys with `set_key` in between.
+pub fn to_wat(sources: &[Source], bootstrap: &Bootstrap) -> String {
+    let mut program = Program::new(sources);
+
+    for (register, value) in &bootstrap.registers {
+        program.emit(&format!(";; {} = {}", register, value));
+        program.emit(&format!("i32.const {}", register_address(register)));
+        program.emit(&format!("i32.const {}", *value as i16));
+        program.emit("call $store");
+    }
+    if let Some(entry) = &bootstrap.entry {
+        program.emit(&format!(";; call {}", entry));
+        program.emit("i32.const 1");
+        program.emit("i32.const 0");
+        program.emit("call $call");
+        program.jump(program.function(entry));
+    }
+    program.cases.push(vec![]);
+
+    for source in sources {
+        let mut previous: Option<&Instruction> = None;
+        for line in &source.lines {
+            let Some(instruction) = &line.instruction else {
+                continue;
+            };
+            let text = line.text.split("//").next().unwrap_or("").trim();
+            program.translate(instruction, previous, text, &source.name);
+            previous = Some(instruction);
+        }
+    }
+
+    let mut output = vec!["(module".to_owned(), HELPERS.to_owned()];
+    for (name, operation) in BINARY {
+        output.push(format!(
+            "  (func ${} (local $y i32)\n    call $pop\n    local.set $y\n    \
+             call $pop\n    local.get $y\n    {}\n    call $push)\n",
+            name, operation
+        ));
+    }
+
+    let cases = program.cases.len();
+    output.append(&mut vec![
+        "  ;; runs until the program halts or has taken `steps` steps, and \
+         returns the\n  ;; steps left. Calling it again carries on."
+            .to_owned(),
+        "  (func (export \"run\") (param $steps i32) (result i32) \
+         (local $y i32)"
+            .to_owned(),
+        "    global.get $halted".to_owned(),
+        "    if".to_owned(),
+        "      local.get $steps".to_owned(),
+        "      return".to_owned(),
+        "    end".to_owned(),
+        "    loop $dispatch".to_owned(),
+        "    block $halt".to_owned(),
+    ]);
+    for case in (0..cases).rev() {
+        output.push(format!("    block $c{}", case));
+    }
+    output.push("    global.get $pc".to_owned());
+    output.push(format!(
+        "    br_table {} $halt",
+        (0..cases)
+            .map(|case| format!("$c{}", case))
+            .collect::<Vec<String>>()
+            .join(" ")
+    ));
+    for (case, code) in program.cases.iter().enumerate() {
+        output.push(format!("    end ;; case {}", case));
+        output.extend(code.iter().map(|line| format!("    {}", line)));
+    }
+    output.append(
+        &mut [
+            "    end ;; halt",
+            "    i32.const 1",
+            "    global.set $halted",
+            "    local.get $steps",
+            "    return",
+            "    end",
+            "    unreachable)",
+            ")",
+            "",
+        ]
+        .map(String::from)
+        .to_vec(),
+    );
+    output.join("\n")
+}
+
+// The code of each case: 0 is the bootstrap and 1 the start of the code
+// after it, then every function, label and return site in the order they
+// come in, as each case's code follows the block of the same number
+struct Program {
+    cases: Vec<Vec<String>>,
+    functions: HashMap<String, usize>,
+    labels: HashMap<String, usize>,
+    statics: HashMap<(String, u16), u16>,
+    current_function: String,
+}
+
+impl Program {
+    fn new(sources: &[Source]) -> Self {
+        let mut program = Program {
+            cases: vec![vec![]],
+            functions: HashMap::new(),
+            labels: HashMap::new(),
+            statics: HashMap::new(),
+            current_function: String::new(),
+        };
+        let mut next = 2;
+        for source in sources {
+            for line in &source.lines {
+                match &line.instruction {
+                    Some(Function(name, _)) => {
+                        program.current_function = name.clone();
+                        program.functions.insert(name.clone(), next);
+                    }
+                    Some(Label(label)) => {
+                        let label = program.label_name(label);
+                        program.labels.insert(label, next);
+                    }
+                    Some(Call(..)) => {}
+                    _ => continue,
+                }
+                next += 1;
+            }
+        }
+        program.current_function.clear();
+        program
+    }
+
+    fn emit(&mut self, code: &str) {
+        self.cases.last_mut().unwrap().push(code.to_owned());
+    }
+
+    fn jump(&mut self, case: usize) {
+        self.jump_indented(case, "");
+    }
+
+    fn jump_indented(&mut self, case: usize, indent: &str) {
+        self.emit(&format!("{}i32.const {}", indent, case));
+        self.emit(&format!("{}global.set $pc", indent));
+        self.emit(&format!("{}br $dispatch", indent));
+    }
+
+    // each function entry and label is a step, where running can stop and
+    // carry on later
+    fn step(&mut self) {
+        let case = self.cases.len() - 1;
+        for code in [
+            "local.get $steps",
+            "i32.eqz",
+            "if",
+            &format!("  i32.const {}", case),
+            "  global.set $pc",
+            "  i32.const 0",
+            "  return",
+            "end",
+            "local.get $steps",
+            "i32.const 1",
+            "i32.sub",
+            "local.set $steps",
+        ] {
+            self.emit(code);
+        }
+    }
+
+    fn function(&self, name: &str) -> usize {
+        *self
+            .functions
+            .get(name)
+            .unwrap_or_else(|| panic!("no function {}", name))
+    }
+
+    // labels are local to the function they're in, as in the Hack code
+    fn label_name(&self, label: &str) -> String {
+        format!("{}${}", self.current_function, label)
+    }
+
+    fn label(&self, label: &str) -> usize {
+        *self
+            .labels
+            .get(&self.label_name(label))
+            .unwrap_or_else(|| panic!("no label {}", label))
+    }
+
+    // puts the address of a segment entry on the stack
+    fn address(&mut self, segment: &Segment, index: u16, name: &str) {
+        let base = match segment {
+            Segment::Local => 1,
+            Segment::Argument => 2,
+            Segment::This => 3,
+            Segment::That => 4,
+            Segment::Stack => 0,
+            Segment::Pointer => {
+                return self.emit(&format!("i32.const {}", index + 3))
+            }
+            Segment::Temp => {
+                return self.emit(&format!("i32.const {}", index + 5))
+            }
+            Segment::Static => {
+                let count = self.statics.len() as u16;
+                let address = *self
+                    .statics
+                    .entry((name.to_owned(), index))
+                    .or_insert(16 + count);
+                return self.emit(&format!("i32.const {}", address));
+            }
+            Segment::Constant => {
+                panic!("the constant segment has no addresses")
+            }
+        };
+        self.emit(&format!("i32.const {}", base));
+        self.emit("call $load");
+        self.emit(&format!("i32.const {}", index));
+        self.emit(if *segment == Segment::Stack {
+            "i32.sub"
+        } else {
+            "i32.add"
+        });
+    }
+
+    fn value(&mut self, segment: &Segment, index: u16, name: &str) {
+        match segment {
+            Segment::Constant => {
+                self.emit(&format!("i32.const {}", index as i16))
+            }
+            _ => {
+                self.address(segment, index, name);
+                self.emit("call $load");
+            }
+        }
+    }
+
+    fn translate(
+        &mut self,
+        instruction: &Instruction,
+        previous: Option<&Instruction>,
+        text: &str,
+        name: &str,
+    ) {
+        if let Function(function, _) = instruction {
+            self.current_function = function.clone();
+        }
+        if matches!(instruction, Function(..) | Label(_)) {
+            self.cases.push(vec![]);
+        }
+        self.emit(&format!(";; {}", text));
+
+        let operation = match instruction {
+            Add => "add",
+            Subtract => "sub",
+            Negate => "neg",
+            Equal => "eq",
+            GreaterThan => "gt",
+            LessThan => "lt",
+            And => "and",
+            Or => "or",
+            Not => "not",
+            Multiply => "mul",
+            Divide => "div",
+            Modulo => "mod",
+            ShiftLeft => "shl",
+            ShiftRight => "shr",
+            Xor => "xor",
+            _ => "",
+        };
+        if !operation.is_empty() {
+            return self.emit(&format!("call ${}", operation));
+        }
+
+        match instruction {
+            Push(segment, index) => {
+                self.value(segment, *index, name);
+                self.emit("call $push");
+            }
+            Pop(segment, index) => {
+                self.address(segment, *index, name);
+                self.emit("call $pop_to");
+            }
+            Move(from, from_index, to, to_index) => {
+                self.address(to, *to_index, name);
+                self.value(from, *from_index, name);
+                self.emit("call $store");
+            }
+            Discard(count) => {
+                self.emit("i32.const 0");
+                self.emit("i32.const 0");
+                self.emit("call $load");
+                self.emit(&format!("i32.const {}", count));
+                self.emit("i32.sub");
+                self.emit("call $store");
+            }
+            Label(_) => self.step(),
+            _ if instruction.halts(previous) => self.emit("br $halt"),
+            Goto(label) => {
+                let label = self.label(label);
+                self.jump(label);
+            }
+            IfGoto(label) => {
+                let label = self.label(label);
+                self.emit("call $pop");
+                self.emit("if");
+                self.jump_indented(label, "  ");
+                self.emit("end");
+            }
+            JumpIf(condition, label) => {
+                let label = self.label(label);
+                self.emit("call $pop");
+                let test = match condition {
+                    Condition::NotTrue => {
+                        self.emit("i32.const -1");
+                        "i32.ne"
+                    }
+                    Condition::Equal => "i32.eq",
+                    Condition::NotEqual => "i32.ne",
+                    Condition::Greater => "i32.gt_s",
+                    Condition::GreaterOrEqual => "i32.ge_s",
+                    Condition::Less => "i32.lt_s",
+                    Condition::LessOrEqual => "i32.le_s",
+                };
+                if *condition != Condition::NotTrue {
+                    self.emit("local.set $y");
+                    self.emit("call $pop");
+                    self.emit("local.get $y");
+                }
+                self.emit(test);
+                self.emit("if");
+                self.jump_indented(label, "  ");
+                self.emit("end");
+            }
+            Function(_, locals) => {
+                self.step();
+                for _ in 0..*locals {
+                    self.emit("i32.const 0");
+                    self.emit("call $push");
+                }
+            }
+            Call(function, arguments) => {
+                let function = self.function(function);
+                self.emit(&format!("i32.const {}", self.cases.len()));
+                self.emit(&format!("i32.const {}", arguments));
+                self.emit("call $call");
+                self.jump(function);
+                self.cases.push(vec![]);
+            }
+            TailCall(function, arguments) => {
+                let function = self.function(function);
+                self.emit(&format!("i32.const {}", arguments));
+                self.emit("call $tail_call");
+                self.jump(function);
+            }
+            Return => {
+                self.emit("call $return");
+                self.emit("global.set $pc");
+                self.emit("br $dispatch");
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::VirtualMachine;
+    use crate::sources::{parse_sources, source_paths};
+    use crate::translator::wat_interpreter::Instance;
+    use crate::translator::{optimize, parse_file};
+    use std::path::Path;
+
+    fn source(name: &str, contents: &str) -> Source {
+        Source {
+            file: format!("{}.vm", name),
+            name: name.into(),
+            lines: parse_file(name, contents).unwrap(),
+        }
+    }
+
+    fn bootstrap() -> Bootstrap {
+        Bootstrap {
+            registers: vec![("SP".into(), 256)],
+            entry: Some("Sys.init".into()),
+        }
+    }
+
+    fn peek(instance: &mut Instance, address: u16) -> u16 {
+        instance.call("peek", &[address as i32]).unwrap() as u16
+    }
+
+    // runs the module until it halts, checking that it does
+    fn run(sources: &[Source], steps: i32) -> Instance {
+        let mut instance = Instance::new(&to_wat(sources, &bootstrap()));
+        instance.call("run", &[steps]);
+        assert_eq!(Some(1), instance.call("halted", &[]));
+        instance
+    }
+
+    #[test]
+    fn test_matches_the_emulator() {
+        for program in ["FibonacciElement", "StaticsTest", "NestedCall"] {
+            let directory = Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("../projects/08/FunctionCalls")
+                .join(program);
+            let sources =
+                parse_sources(&source_paths(&directory).unwrap()).unwrap();
+            let mut machine = VirtualMachine::new(&sources).unwrap();
+            machine.bootstrap().unwrap();
+            machine.run(1_000_000).unwrap();
+            assert!(machine.halted());
+
+            let mut instance = run(&sources, 1_000_000);
+            // above them, the frames hold return addresses, which differ
+            for address in (0..256).chain([261]) {
+                assert_eq!(
+                    machine.peek(address),
+                    peek(&mut instance, address),
+                    "{} RAM[{}]",
+                    program,
+                    address
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_optimized_and_extended_instructions() {
+        // counts down from 300 with tail calls, multiplying as it goes
+        let mut sources = [source(
+            "Sys",
+            "function Sys.init 0\npush constant 1\npush constant 300\n\
+             call Sys.count 2\npop temp 0\n\
+             push constant 7\nneg\npush constant 2\ndiv\npop temp 1\n\
+             push constant 7\nneg\npush constant 2\nmod\npop temp 2\n\
+             push constant 7\nneg\npush constant 1\nshr\npop temp 3\n\
+             push constant 1\npush constant 16\nshl\npop temp 4\n\
+             push constant 5\npush constant 3\nxor\npop temp 5\n\
+             label HALT\ngoto HALT\n\
+             function Sys.count 0\npush argument 1\nif-goto MORE\n\
+             push argument 0\nreturn\nlabel MORE\n\
+             push argument 0\npush constant 3\nmul\n\
+             push argument 1\npush constant 1\nsub\n\
+             call Sys.count 2\nreturn",
+        )];
+        sources[0].lines = optimize(sources[0].lines.clone());
+        assert!(to_wat(&sources, &bootstrap()).contains("call $tail_call"));
+
+        let mut instance = run(&sources, 10_000);
+        let results: Vec<i16> = (5..11)
+            .map(|address| peek(&mut instance, address) as i16)
+            .collect();
+        // 3 to the 300th, cut to 16 bits
+        let power = (0..300).fold(1i16, |power, _| power.wrapping_mul(3));
+        assert_eq!(vec![power, -3, -1, -4, 0, 6], results);
+        assert!(peek(&mut instance, 0) < 270);
+    }
+
+    #[test]
+    fn test_stops_and_carries_on() {
+        let sources = [source(
+            "Sys",
+            "function Sys.init 0\nlabel LOOP\npush temp 0\npush constant 1\n\
+             add\npop temp 0\npush temp 0\npush constant 100\nlt\n\
+             if-goto LOOP\nlabel HALT\ngoto HALT",
+        )];
+        let mut instance = Instance::new(&to_wat(&sources, &bootstrap()));
+
+        // a step runs a case: Sys.init, then 9 times round the loop
+        assert_eq!(Some(0), instance.call("run", &[10]));
+        assert_eq!(Some(0), instance.call("halted", &[]));
+        assert_eq!(9, peek(&mut instance, 5));
+        // 91 more times round and the halt
+        assert_eq!(Some(908), instance.call("run", &[1000]));
+        assert_eq!(Some(1), instance.call("halted", &[]));
+        assert_eq!(100, peek(&mut instance, 5));
+    }
+
+    #[test]
+    fn test_screen_and_keyboard() {
+        // copies the key pressed to the first word of the screen
+        let sources = [source(
+            "Sys",
+            "function Sys.init 0\nlabel WAIT\npush constant 24576\n\
+             pop pointer 1\npush that 0\nnot\nnot\nif-goto KEY\ngoto WAIT\n\
+             label KEY\npush that 0\npush constant 16384\npop pointer 1\n\
+             pop that 0\nlabel HALT\ngoto HALT",
+        )];
+        let mut instance = Instance::new(&to_wat(&sources, &bootstrap()));
+
+        instance.call("run", &[100]);
+        assert_eq!(Some(0), instance.call("halted", &[]));
+        instance.call("set_key", &[65]);
+        instance.call("run", &[100]);
+        assert_eq!(Some(1), instance.call("halted", &[]));
+        let screen = instance.call("screen", &[]).unwrap() as usize;
+        assert_eq!([65, 0], instance.memory()[screen..screen + 2]);
+    }
+
+    #[test]
+    fn test_course_os_draws_like_the_emulator() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+        let mut paths = source_paths(&root.join("tools/OS")).unwrap();
+        paths.push(root.join("projects/11/Seven/Main.vm"));
+        let sources = parse_sources(&paths).unwrap();
+        let mut machine = VirtualMachine::new(&sources).unwrap();
+        machine.bootstrap().unwrap();
+        // the course's Sys.halt loops instead of stopping the machine
+        while !machine.location().unwrap().contains("Sys.vm:2") {
+            machine.run(10_000).unwrap();
+        }
+
+        let mut instance = Instance::new(&to_wat(&sources, &bootstrap()));
+        instance.call("run", &[200_000]);
+        let screen = instance.call("screen", &[]).unwrap() as usize;
+        let words: Vec<u16> = instance.memory()[screen..screen + 16384]
+            .chunks(2)
+            .map(|word| u16::from_le_bytes([word[0], word[1]]))
+            .collect();
+        assert_eq!(machine.screen(), words);
+        assert!(words.iter().any(|&word| word != 0));
+    }
+}
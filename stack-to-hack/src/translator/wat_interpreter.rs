@@ -0,0 +1,565 @@
+// A small interpreter for the WebAssembly text the WAT backend writes, to
+// test its modules without a toolchain. It knows the module fields and the
+// instructions the backend uses, in the flat form it writes them in, and
+// panics on anything else.
+use std::collections::HashMap;
+
+#[derive(Debug)]
+enum Expression {
+    Atom(String),
+    List(Vec<Expression>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Numeric {
+    Add,
+    Sub,
+    Mul,
+    DivS,
+    RemS,
+    And,
+    Or,
+    Xor,
+    Shl,
+    ShrS,
+    Eq,
+    Ne,
+    LtS,
+    GtS,
+    LeS,
+    GeS,
+    LtU,
+    GtU,
+    GeU,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Op {
+    Const(i32),
+    LocalGet(usize),
+    LocalSet(usize),
+    LocalTee(usize),
+    GlobalGet(usize),
+    GlobalSet(usize),
+    Load,
+    Store,
+    Numeric(Numeric),
+    Eqz,
+    Select,
+    Call(usize),
+    // blocks know the index of their `end`, and `if` of its `else` too
+    Block {
+        end: usize,
+        arity: usize,
+    },
+    Loop,
+    If {
+        otherwise: Option<usize>,
+        end: usize,
+        arity: usize,
+    },
+    Else {
+        end: usize,
+    },
+    End,
+    Br(usize),
+    BrIf(usize),
+    BrTable(Vec<usize>, usize),
+    Return,
+    Unreachable,
+}
+
+struct Function {
+    parameters: usize,
+    results: usize,
+    locals: usize,
+    code: Vec<Op>,
+}
+
+// where a branch to a block goes, and what it leaves on the stack
+struct Label {
+    height: usize,
+    arity: usize,
+    target: usize,
+    is_loop: bool,
+}
+
+pub struct Instance {
+    memory: Vec<u8>,
+    globals: Vec<i32>,
+    functions: Vec<Function>,
+    exports: HashMap<String, usize>,
+}
+
+impl Instance {
+    pub fn new(wat: &str) -> Self {
+        let mut tokens = tokenize(wat).into_iter();
+        let Expression::List(module) = parse(&mut tokens) else {
+            panic!("expected a module");
+        };
+        let fields: Vec<&Vec<Expression>> = module[1..]
+            .iter()
+            .map(|field| match field {
+                Expression::List(field) => field,
+                _ => panic!("unexpected {:?}", field),
+            })
+            .collect();
+
+        let mut instance = Instance {
+            memory: vec![],
+            globals: vec![],
+            functions: vec![],
+            exports: HashMap::new(),
+        };
+        let mut global_names = HashMap::new();
+        let mut function_names = HashMap::new();
+        let mut count = 0;
+        for field in &fields {
+            match (atom(&field[0]), field.get(1).map(name)) {
+                ("global", Some(Some(name))) => {
+                    let Expression::List(value) = &field[3] else {
+                        panic!("expected the value of {}", name);
+                    };
+                    global_names.insert(name, global_names.len());
+                    instance.globals.push(number(atom(&value[1])));
+                }
+                ("func", name) => {
+                    if let Some(Some(name)) = name {
+                        function_names.insert(name, count);
+                    }
+                    count += 1;
+                }
+                ("memory", _) => {
+                    let pages = number(atom(field.last().unwrap()));
+                    instance.memory = vec![0; pages as usize * 65536];
+                }
+                _ => panic!("unexpected field {:?}", field),
+            }
+            // only functions are looked up by their export
+            for item in field.iter().filter(|_| atom(&field[0]) == "func") {
+                if let Expression::List(export) = item {
+                    if atom(&export[0]) == "export" {
+                        let name = atom(&export[1]).trim_matches('"');
+                        instance.exports.insert(name.to_owned(), count - 1);
+                    }
+                }
+            }
+        }
+        for field in fields.iter().filter(|field| atom(&field[0]) == "func") {
+            instance.functions.push(compile(
+                field,
+                &function_names,
+                &global_names,
+            ));
+        }
+        instance
+    }
+
+    pub fn call(&mut self, name: &str, arguments: &[i32]) -> Option<i32> {
+        let function = *self
+            .exports
+            .get(name)
+            .unwrap_or_else(|| panic!("no export {}", name));
+        self.invoke(function, arguments.to_vec())
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    fn invoke(&mut self, function: usize, mut locals: Vec<i32>) -> Option<i32> {
+        let code = std::mem::take(&mut self.functions[function].code);
+        let result = self.execute(function, &code, &mut locals);
+        self.functions[function].code = code;
+        result
+    }
+
+    fn execute(
+        &mut self,
+        function: usize,
+        code: &[Op],
+        locals: &mut Vec<i32>,
+    ) -> Option<i32> {
+        let Function {
+            parameters,
+            results,
+            locals: count,
+            ..
+        } = self.functions[function];
+        assert_eq!(parameters, locals.len());
+        locals.resize(parameters + count, 0);
+        let mut stack: Vec<i32> = vec![];
+        let mut labels: Vec<Label> = vec![];
+        let mut pc = 0;
+
+        while pc < code.len() {
+            let mut next = pc + 1;
+            match &code[pc] {
+                Op::Const(value) => stack.push(*value),
+                Op::LocalGet(index) => stack.push(locals[*index]),
+                Op::LocalSet(index) => locals[*index] = stack.pop().unwrap(),
+                Op::LocalTee(index) => locals[*index] = *stack.last().unwrap(),
+                Op::GlobalGet(index) => stack.push(self.globals[*index]),
+                Op::GlobalSet(index) => {
+                    self.globals[*index] = stack.pop().unwrap()
+                }
+                Op::Load => {
+                    let address = stack.pop().unwrap() as usize;
+                    let bytes =
+                        [self.memory[address], self.memory[address + 1]];
+                    stack.push(i16::from_le_bytes(bytes) as i32);
+                }
+                Op::Store => {
+                    let value = stack.pop().unwrap() as i16;
+                    let address = stack.pop().unwrap() as usize;
+                    self.memory[address..address + 2]
+                        .copy_from_slice(&value.to_le_bytes());
+                }
+                Op::Numeric(operation) => {
+                    let y = stack.pop().unwrap();
+                    let x = stack.pop().unwrap();
+                    stack.push(numeric(*operation, x, y));
+                }
+                Op::Eqz => {
+                    let x = stack.pop().unwrap();
+                    stack.push((x == 0) as i32);
+                }
+                Op::Select => {
+                    let condition = stack.pop().unwrap();
+                    let second = stack.pop().unwrap();
+                    let first = stack.pop().unwrap();
+                    stack.push(if condition != 0 { first } else { second });
+                }
+                Op::Call(callee) => {
+                    let parameters = self.functions[*callee].parameters;
+                    let arguments = stack.split_off(stack.len() - parameters);
+                    if let Some(result) = self.invoke(*callee, arguments) {
+                        stack.push(result);
+                    }
+                }
+                Op::Block { end, arity } => labels.push(Label {
+                    height: stack.len(),
+                    arity: *arity,
+                    target: end + 1,
+                    is_loop: false,
+                }),
+                Op::Loop => labels.push(Label {
+                    height: stack.len(),
+                    arity: 0,
+                    target: pc + 1,
+                    is_loop: true,
+                }),
+                Op::If {
+                    otherwise,
+                    end,
+                    arity,
+                } => {
+                    let condition = stack.pop().unwrap();
+                    labels.push(Label {
+                        height: stack.len(),
+                        arity: *arity,
+                        target: end + 1,
+                        is_loop: false,
+                    });
+                    if condition == 0 {
+                        match otherwise {
+                            Some(otherwise) => next = otherwise + 1,
+                            None => {
+                                labels.pop();
+                                next = end + 1;
+                            }
+                        }
+                    }
+                }
+                Op::Else { end } => next = *end,
+                Op::End => {
+                    labels.pop();
+                }
+                Op::Br(depth) => next = branch(&mut stack, &mut labels, *depth),
+                Op::BrIf(depth) => {
+                    if stack.pop().unwrap() != 0 {
+                        next = branch(&mut stack, &mut labels, *depth);
+                    }
+                }
+                Op::BrTable(depths, default) => {
+                    let index = stack.pop().unwrap() as u32 as usize;
+                    let depth = *depths.get(index).unwrap_or(default);
+                    next = branch(&mut stack, &mut labels, depth);
+                }
+                Op::Return => break,
+                Op::Unreachable => panic!("unreachable"),
+            }
+            pc = next;
+        }
+
+        match results {
+            0 => None,
+            _ => stack.pop(),
+        }
+    }
+}
+
+fn branch(
+    stack: &mut Vec<i32>,
+    labels: &mut Vec<Label>,
+    depth: usize,
+) -> usize {
+    let index = labels.len() - 1 - depth;
+    let label = &labels[index];
+    let results = stack.split_off(stack.len() - label.arity);
+    stack.truncate(label.height);
+    stack.extend(results);
+    let target = label.target;
+    // a loop's label stays for the next time round
+    labels.truncate(if label.is_loop { index + 1 } else { index });
+    target
+}
+
+fn numeric(operation: Numeric, x: i32, y: i32) -> i32 {
+    use Numeric::*;
+
+    match operation {
+        Add => x.wrapping_add(y),
+        Sub => x.wrapping_sub(y),
+        Mul => x.wrapping_mul(y),
+        DivS => x.checked_div(y).expect("integer divide by zero"),
+        RemS => x.checked_rem(y).expect("integer divide by zero"),
+        And => x & y,
+        Or => x | y,
+        Xor => x ^ y,
+        Shl => x.wrapping_shl(y as u32),
+        ShrS => x.wrapping_shr(y as u32),
+        Eq => (x == y) as i32,
+        Ne => (x != y) as i32,
+        LtS => (x < y) as i32,
+        GtS => (x > y) as i32,
+        LeS => (x <= y) as i32,
+        GeS => (x >= y) as i32,
+        LtU => ((x as u32) < y as u32) as i32,
+        GtU => (x as u32 > y as u32) as i32,
+        GeU => (x as u32 >= y as u32) as i32,
+    }
+}
+
+fn tokenize(wat: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    for line in wat.lines() {
+        let line = line.split(";;").next().unwrap();
+        for word in line
+            .replace('(', " ( ")
+            .replace(')', " ) ")
+            .split_whitespace()
+        {
+            tokens.push(word.to_owned());
+        }
+    }
+    tokens
+}
+
+fn parse(tokens: &mut impl Iterator<Item = String>) -> Expression {
+    let token = tokens.next().expect("unexpected end");
+    if token != "(" {
+        return Expression::Atom(token);
+    }
+    parse_list(tokens)
+}
+
+// the rest of a list, after its `(`
+fn parse_list(tokens: &mut impl Iterator<Item = String>) -> Expression {
+    let mut list = vec![];
+    loop {
+        match tokens.next().expect("unclosed list") {
+            token if token == ")" => return Expression::List(list),
+            token if token == "(" => list.push(parse_list(tokens)),
+            token => list.push(Expression::Atom(token)),
+        }
+    }
+}
+
+fn atom(expression: &Expression) -> &str {
+    match expression {
+        Expression::Atom(atom) => atom,
+        Expression::List(_) => panic!("expected an atom, not {:?}", expression),
+    }
+}
+
+// the `$name` an expression is, if it's one
+fn name(expression: &Expression) -> Option<String> {
+    match expression {
+        Expression::Atom(atom) if atom.starts_with('$') => Some(atom.clone()),
+        _ => None,
+    }
+}
+
+// the atom after an instruction
+fn immediate<'a>(items: &mut impl Iterator<Item = &'a Expression>) -> String {
+    atom(items.next().expect("expected an immediate")).to_owned()
+}
+
+fn number(text: &str) -> i32 {
+    text.parse()
+        .unwrap_or_else(|_| panic!("expected a number, not {}", text))
+}
+
+fn compile(
+    field: &[Expression],
+    functions: &HashMap<String, usize>,
+    globals: &HashMap<String, usize>,
+) -> Function {
+    let mut locals = HashMap::new();
+    let mut function = Function {
+        parameters: 0,
+        results: 0,
+        locals: 0,
+        code: vec![],
+    };
+    let mut body = vec![];
+    for item in &field[1..] {
+        match item {
+            Expression::List(list) => match atom(&list[0]) {
+                kind @ ("param" | "local") => {
+                    // a name goes with a single type
+                    let count = match name(&list[1]) {
+                        Some(name) => {
+                            let index = function.parameters + function.locals;
+                            locals.insert(name, index);
+                            1
+                        }
+                        None => list.len() - 1,
+                    };
+                    if kind == "param" {
+                        function.parameters += count;
+                    } else {
+                        function.locals += count;
+                    }
+                }
+                "result" => function.results += 1,
+                "export" => {}
+                _ => body.push(item),
+            },
+            Expression::Atom(atom)
+                if atom.starts_with('$') && body.is_empty() => {}
+            _ => body.push(item),
+        }
+    }
+
+    // the blocks still open, with their names and where they start
+    let mut blocks: Vec<(Option<String>, usize)> = vec![];
+    let mut items = body.into_iter().peekable();
+    let depth = |blocks: &Vec<(Option<String>, usize)>, label: &str| {
+        blocks
+            .iter()
+            .rev()
+            .position(|(name, _)| name.as_deref() == Some(label))
+            .unwrap_or_else(|| panic!("no block {}", label))
+    };
+    while let Some(item) = items.next() {
+        let instruction = atom(item);
+        let op = match instruction {
+            "i32.const" => Op::Const(number(&immediate(&mut items))),
+            "local.get" => Op::LocalGet(locals[&immediate(&mut items)]),
+            "local.set" => Op::LocalSet(locals[&immediate(&mut items)]),
+            "local.tee" => Op::LocalTee(locals[&immediate(&mut items)]),
+            "global.get" => Op::GlobalGet(globals[&immediate(&mut items)]),
+            "global.set" => Op::GlobalSet(globals[&immediate(&mut items)]),
+            "call" => Op::Call(functions[&immediate(&mut items)]),
+            "i32.load16_s" => Op::Load,
+            "i32.store16" => Op::Store,
+            "i32.eqz" => Op::Eqz,
+            "select" => Op::Select,
+            "return" => Op::Return,
+            "unreachable" => Op::Unreachable,
+            "block" | "loop" | "if" => {
+                let name = items.peek().and_then(|item| name(item));
+                if name.is_some() {
+                    items.next();
+                }
+                let arity = match items.peek() {
+                    Some(Expression::List(list))
+                        if atom(&list[0]) == "result" =>
+                    {
+                        items.next();
+                        1
+                    }
+                    _ => 0,
+                };
+                blocks.push((name, function.code.len()));
+                match instruction {
+                    "block" => Op::Block { end: 0, arity },
+                    "loop" => Op::Loop,
+                    _ => Op::If {
+                        otherwise: None,
+                        end: 0,
+                        arity,
+                    },
+                }
+            }
+            "else" => {
+                let start = blocks.last().unwrap().1;
+                let here = function.code.len();
+                if let Op::If { otherwise, .. } = &mut function.code[start] {
+                    *otherwise = Some(here);
+                }
+                Op::Else { end: 0 }
+            }
+            "end" => {
+                let (_, start) = blocks.pop().expect("unmatched end");
+                let end = function.code.len();
+                let mut otherwise = None;
+                match &mut function.code[start] {
+                    Op::Block { end: block_end, .. } => *block_end = end,
+                    Op::If {
+                        end: if_end,
+                        otherwise: if_otherwise,
+                        ..
+                    } => {
+                        *if_end = end;
+                        otherwise = *if_otherwise;
+                    }
+                    _ => {}
+                }
+                if let Some(otherwise) = otherwise {
+                    function.code[otherwise] = Op::Else { end };
+                }
+                Op::End
+            }
+            "br" => Op::Br(depth(&blocks, &immediate(&mut items))),
+            "br_if" => Op::BrIf(depth(&blocks, &immediate(&mut items))),
+            "br_table" => {
+                let mut depths = vec![];
+                while let Some(label) = items.peek().and_then(|item| name(item))
+                {
+                    items.next();
+                    depths.push(depth(&blocks, &label));
+                }
+                let default = depths.pop().expect("br_table needs a default");
+                Op::BrTable(depths, default)
+            }
+            _ => Op::Numeric(match instruction {
+                "i32.add" => Numeric::Add,
+                "i32.sub" => Numeric::Sub,
+                "i32.mul" => Numeric::Mul,
+                "i32.div_s" => Numeric::DivS,
+                "i32.rem_s" => Numeric::RemS,
+                "i32.and" => Numeric::And,
+                "i32.or" => Numeric::Or,
+                "i32.xor" => Numeric::Xor,
+                "i32.shl" => Numeric::Shl,
+                "i32.shr_s" => Numeric::ShrS,
+                "i32.eq" => Numeric::Eq,
+                "i32.ne" => Numeric::Ne,
+                "i32.lt_s" => Numeric::LtS,
+                "i32.gt_s" => Numeric::GtS,
+                "i32.le_s" => Numeric::LeS,
+                "i32.ge_s" => Numeric::GeS,
+                "i32.lt_u" => Numeric::LtU,
+                "i32.gt_u" => Numeric::GtU,
+                "i32.ge_u" => Numeric::GeU,
+                _ => panic!("unknown instruction {}", instruction),
+            }),
+        };
+        function.code.push(op);
+    }
+    assert!(blocks.is_empty(), "unclosed block");
+    function
+}